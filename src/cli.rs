@@ -0,0 +1,154 @@
+use std::error::Error;
+
+use rpassword::{prompt_password, read_password};
+
+use crate::{
+    config::{ConfigManager, Configuration, SecretConfiguration},
+    data::crypto::PrivKey,
+    error::ConfigError,
+};
+
+/// Amount of times the password of the secrets file can be typed
+const PASSWORD_ATTEMPTS: usize = 5;
+
+const SECRETS_USAGE: &str = "Usage: cacophoney secrets <command>
+
+Commands:
+    passwd                   Change the password of the secrets file
+    show-pubkey              Print the node key and its fingerprint
+    rotate-key               Replace the node key by a new key, signed by the current key
+    set-admin-pass           Change the admin password. An empty password disables administration
+    export <path>            Write an encrypted backup of the secrets file, to move the node to another machine
+    import <path> [--force]  Replace the secrets file by a backup";
+
+/// Reads the secrets file, asking for its password until it is unlocked.
+/// The password of the configuration is tried first if it is set.
+/// If `create` is set and there is no secrets file, a secrets file is created with the password.
+/// Returns the secrets and the password that unlocked them
+pub async fn unlock_secrets(
+    config: &Configuration,
+    mgr: &ConfigManager,
+    create: bool,
+) -> Result<(SecretConfiguration, String), Box<dyn Error>> {
+    let mut pass = match &config.secret_config.password {
+        Some(v) => v.clone(),
+        None => {
+            tracing::info!("Please type the password for the secrets file.");
+            read_password()?
+        }
+    };
+    let mut attempt = 1;
+
+    loop {
+        match mgr.get_secrets(&pass).await {
+            Ok(v) => return Ok((v, pass)),
+            Err(ConfigError::IoError(e)) if create && e.kind() == std::io::ErrorKind::NotFound => {
+                tracing::info!("Creating secrets file in {}", config.secret_config.location);
+                return Ok((mgr.create_secrets(&pass).await?, pass));
+            }
+            Err(ConfigError::PasswordError(_)) if attempt < PASSWORD_ATTEMPTS => {
+                attempt += 1;
+                tracing::info!("Please type the password for the secrets file. {}/{}", attempt, PASSWORD_ATTEMPTS);
+                pass = read_password()?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Asks for a new password twice, until both match
+fn read_new_password(name: &str) -> Result<String, Box<dyn Error>> {
+    loop {
+        let pass = prompt_password(format!("New {}: ", name))?;
+
+        if prompt_password(format!("Repeat the new {}: ", name))? == pass {
+            return Ok(pass);
+        }
+        println!("The passwords do not match.");
+    }
+}
+
+/// Runs a `secrets` subcommand
+pub async fn secrets(config: &Configuration, mgr: &ConfigManager, args: &[String]) -> Result<(), Box<dyn Error>> {
+    match args.first().map(String::as_str) {
+        Some("rotate-key") => {
+            let (_, pass) = unlock_secrets(config, mgr, false).await?;
+            let rotation = mgr.rotate_node_key(&pass).await?;
+
+            println!("Previous node key: {}", rotation.old.to_hex());
+            println!("New node key: {}", rotation.new.to_hex());
+            println!("Fingerprint: {}", rotation.new.fingerprint());
+        }
+        Some("passwd") => {
+            let (secrets, _) = unlock_secrets(config, mgr, false).await?;
+            let pass = read_new_password("password of the secrets file")?;
+            mgr.write_secrets(&secrets, &pass).await?;
+
+            println!("Changed the password of the secrets file.");
+            if config.secret_config.password.is_some() {
+                println!("The configuration file still contains the previous password, change it as well.");
+            }
+        }
+        Some("show-pubkey") => {
+            let (secrets, _) = unlock_secrets(config, mgr, false).await?;
+            let key = PrivKey::new(secrets.private_key.unwrap_or_default())?.public_key();
+
+            println!("Node key: {}", key.to_hex());
+            println!("Fingerprint: {}", key.fingerprint());
+            if let Some(rotation) = secrets.node_rotation.filter(|r| r.new == key) {
+                println!("Rotated from: {}", rotation.old.to_hex());
+            }
+        }
+        Some("set-admin-pass") => {
+            let (mut secrets, pass) = unlock_secrets(config, mgr, false).await?;
+            let admin_pass = read_new_password("admin password")?;
+
+            // Clients answer the admin challenge with the blake3 hash of the password
+            secrets.admin_pass = match admin_pass.is_empty() {
+                true => None,
+                false => Some(*blake3::hash(admin_pass.as_bytes()).as_bytes()),
+            };
+            mgr.write_secrets(&secrets, &pass).await?;
+
+            match secrets.admin_pass {
+                Some(_) => println!("Changed the admin password."),
+                None => println!("Disabled administration."),
+            }
+        }
+        Some("export") => {
+            let path = args.get(1).ok_or(SECRETS_USAGE)?;
+            let (_, pass) = unlock_secrets(config, mgr, false).await?;
+            let backup_pass = read_new_password("password of the backup")?;
+
+            let backup = mgr.export_secrets(&pass, &backup_pass).await?;
+            tokio::fs::write(path, backup).await?;
+
+            println!("Wrote the backup to {}.", path);
+        }
+        Some("import") => {
+            // --force can come before the path
+            let path = args.iter().skip(1).find(|a| !a.starts_with("--")).ok_or(SECRETS_USAGE)?;
+            let force = args.iter().any(|a| a == "--force");
+
+            let secrets_path = format!("{}/secret", config.secret_config.location);
+            if !force && tokio::fs::metadata(&secrets_path).await.is_ok() {
+                return Err(format!("{} exists and would be replaced, use --force to replace it", secrets_path).into());
+            }
+
+            let backup = tokio::fs::read(path).await?;
+            let backup_pass = prompt_password("Password of the backup: ")?;
+            let pass = match &config.secret_config.password {
+                Some(v) => v.clone(),
+                None => read_new_password("password of the secrets file")?,
+            };
+
+            let secrets = mgr.import_secrets(&backup, &backup_pass, &pass).await?;
+            let key = PrivKey::new(secrets.private_key.unwrap_or_default())?.public_key();
+
+            println!("Imported node key {} (fingerprint {}).", key.to_hex(), key.fingerprint());
+        }
+        _ => println!("{}", SECRETS_USAGE),
+    }
+
+    Ok(())
+}
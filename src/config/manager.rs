@@ -1,5 +1,5 @@
-use futures::Future;
-use rand::{rngs::OsRng, RngCore};
+use chrono::Utc;
+use rand::rngs::OsRng;
 use rustls::{Certificate, PrivateKey};
 use std::{collections::HashSet, error::Error, io::Cursor, sync::Arc};
 use tokio::{
@@ -7,7 +7,12 @@ use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
 };
 
-use crate::{error::{ConfigError}, helpers::hash_s, config};
+use crate::{
+    config,
+    data::{crypto::PrivKey, KeyRotation},
+    error::ConfigError,
+    helpers::{encrypt, legacy_secrets_key, random_nonce, read_encrypted, KdfParams, SecretsFile},
+};
 
 use super::{Configuration, SecretConfiguration};
 
@@ -51,41 +56,25 @@ impl ConfigManager {
         Ok((arc.clone(), Self::new(arc)))
     }
 
-    pub async fn get_nonce(&self) -> Result<[u8; 12], tokio::io::Error> {
+    /// Reads the nonce of secrets files written before the nonce was stored in the secrets file
+    async fn get_legacy_nonce(&self) -> Result<[u8; 12], tokio::io::Error> {
         let path = format!("{}/nonce", self.config.secret_config.location);
 
-        let nonce = File::open(&path).await;
-
-        let mut f = match nonce {
-            Ok(f) => f,
-            Err(_) => {
-                tracing::info!("Creating nonce...");
-                return Self::write_nonce(&path).await;
-            }
-        };
-
         let mut n = [0u8; 12];
-        f.read_exact(&mut n).await?;
+        File::open(&path).await?.read_exact(&mut n).await?;
 
         Ok(n)
     }
-    async fn write_nonce(path: &str) -> Result<[u8; 12], tokio::io::Error> {
-        let mut f = File::create(&path).await?;
-
-        // Generate nonce
-        let mut n = [0u8; 12];
-        OsRng::fill_bytes(&mut OsRng, &mut n);
-
-        f.write_all(&n).await?;
-
-        return Ok(n);
-    }
 
     pub async fn get_or_create_certs(
         &self,
     ) -> Result<(Vec<Certificate>, PrivateKey), Box<dyn Error>> {
-        if let Ok(mut pubfile) = File::open(&self.config.main_config.cert_path).await &&
-            let Ok(mut privfile) = File::open(&self.config.main_config.private_key_path).await {
+        let files = (
+            File::open(&self.config.main_config.cert_path).await,
+            File::open(&self.config.main_config.private_key_path).await,
+        );
+
+        if let (Ok(mut pubfile), Ok(mut privfile)) = files {
             // Public key reading
             let mut buf = String::new();
             pubfile.read_to_string(&mut buf).await?;
@@ -129,85 +118,239 @@ impl ConfigManager {
             Ok((vec![rustls::Certificate(cert.serialize_der()?)], key))
         }
     }
-    /// Reads from the secrets file using the hashed password
+    /// Reads from the secrets file using the key derived from the password.
+    /// Secrets files written before the current version are written again with the current version
     pub async fn get_secrets(&self, pass : &str) -> Result<SecretConfiguration, ConfigError> {
         let path = format!("{}/secret", self.config.secret_config.location);
 
         // Reading from the secrets file
         let mut f = File::open(&path).await?;
-        let mut cipher = Vec::new();
-        f.read_to_end(&mut cipher).await?;
-
-        // Decrypting the cyphertext
-        let nonce = self.get_nonce().await?;
-        let h = hash_s(pass);
-        let mut r = crate::helpers::read_encrypted::<SecretConfiguration>(&h, &nonce, &cipher).await?;
-
-        if self.config.secret_config.restart_key {
-            // Set the key to a random key
-            r.private_key = Some(libsecp256k1::SecretKey::random(&mut OsRng).serialize());
-        }
+        let mut file = Vec::new();
+        f.read_to_end(&mut file).await?;
+
+        // Decrypting the cyphertext. Older versions read the nonce from the nonce file
+        let (mut r, outdated) = match SecretsFile::parse(&file)? {
+            Some(SecretsFile { params, nonce: Some(nonce), ciphertext }) => {
+                let key = params.derive(pass)?;
+                (read_encrypted::<SecretConfiguration>(&key, &nonce, ciphertext).await?, false)
+            }
+            Some(SecretsFile { params, nonce: None, ciphertext }) => {
+                let key = params.derive(pass)?;
+                let nonce = self.get_legacy_nonce().await?;
+                (read_encrypted::<SecretConfiguration>(&key, &nonce, ciphertext).await?, true)
+            }
+            None => {
+                let key = legacy_secrets_key(pass);
+                let nonce = self.get_legacy_nonce().await?;
+                (read_encrypted::<SecretConfiguration>(&key, &nonce, &file).await?, true)
+            }
+        };
 
+        // The node key is generated once and kept, so other nodes and clients can pin it
         if r.private_key.is_none() {
             r.private_key = Some(libsecp256k1::SecretKey::random(&mut OsRng).serialize());
             self.write_secrets(&r, pass).await?;
+        } else if outdated {
+            tracing::info!("Migrating the secrets file to the current version...");
+            self.write_secrets(&r, pass).await?;
+        }
+
+        if outdated {
+            // The nonce is now stored in the secrets file
+            let _ = tokio::fs::remove_file(format!("{}/nonce", self.config.secret_config.location)).await;
         }
 
         Ok(r)
     }
-    /// Serializes the provided [`SecretConfiguration`] and writes it to the secrets file
-    pub async fn write_secrets(&self, config : &SecretConfiguration, pass : &str) -> Result<(), tokio::io::Error> {
+    /// Serializes the provided [`SecretConfiguration`] and writes it to the secrets file,
+    /// with a key derived from the password with a new random salt, and a new random nonce
+    pub async fn write_secrets(&self, config : &SecretConfiguration, pass : &str) -> Result<(), ConfigError> {
         let path = format!("{}/secret", self.config.secret_config.location);
+        let temp = format!("{}.tmp", path);
 
-        // Get the nonce and hash of the password
-        let nonce = self.get_nonce().await?;
-        let hash = hash_s(pass);
-
-        // Encrypting the configuration
-        let s = crate::helpers::encrypt(&hash, &nonce, config).await;
+        let s = seal_secrets(config, pass).await?;
 
-        let mut f = File::create(&path).await?;
+        // Replaced at once, so an interrupted write does not lose the secrets
+        let mut f = File::create(&temp).await?;
         f.write_all(&s).await?;
+        f.sync_all().await?;
+        tokio::fs::rename(&temp, &path).await?;
 
         Ok(())
     }
     /// Creates a secrets file, overwriting existing ones
-    pub async fn create_secrets(&self, pass : &str) -> Result<SecretConfiguration, tokio::io::Error> {
-        let mut secrets = SecretConfiguration::default();
-        
-        if !self.config.secret_config.restart_key {
-            // Set the key to a random key
-            secrets.private_key = Some(libsecp256k1::SecretKey::random(&mut OsRng).serialize());
-        }
+    pub async fn create_secrets(&self, pass : &str) -> Result<SecretConfiguration, ConfigError> {
+        tokio::fs::create_dir_all(&self.config.secret_config.location).await?;
+
+        let secrets = SecretConfiguration {
+            private_key: Some(libsecp256k1::SecretKey::random(&mut OsRng).serialize()),
+            ..Default::default()
+        };
         self.write_secrets(&secrets, pass).await?;
 
-        if self.config.secret_config.restart_key {
-            secrets.private_key = Some(libsecp256k1::SecretKey::random(&mut OsRng).serialize());
-        }
+        Ok(secrets)
+    }
+    /// Returns a portable backup of the secrets file, encrypted with `backup_pass`
+    pub async fn export_secrets(&self, pass : &str, backup_pass : &str) -> Result<Vec<u8>, ConfigError> {
+        let secrets = self.get_secrets(pass).await?;
+
+        seal_secrets(&secrets, backup_pass).await
+    }
+    /// Replaces the secrets file by a backup made with [`ConfigManager::export_secrets`].
+    /// The secrets file is encrypted with `pass` instead of `backup_pass`
+    pub async fn import_secrets(&self, backup : &[u8], backup_pass : &str, pass : &str) -> Result<SecretConfiguration, ConfigError> {
+        let secrets = match SecretsFile::parse(backup)? {
+            Some(SecretsFile { params, nonce: Some(nonce), ciphertext }) => {
+                let key = params.derive(backup_pass)?;
+                read_encrypted::<SecretConfiguration>(&key, &nonce, ciphertext).await?
+            }
+            _ => return Err(ConfigError::InvalidBackup),
+        };
+
+        tokio::fs::create_dir_all(&self.config.secret_config.location).await?;
+        self.write_secrets(&secrets, pass).await?;
 
         Ok(secrets)
     }
+    /// Replaces the node key of the secrets file by a random key, signed by the previous key.
+    /// Returns the rotation from the previous key to the new key
+    pub async fn rotate_node_key(&self, pass : &str) -> Result<KeyRotation, Box<dyn Error>> {
+        let mut secrets = self.get_secrets(pass).await?;
+
+        // get_secrets always sets a key
+        let old = PrivKey::new(secrets.private_key.unwrap_or_default())?;
+        let new = PrivKey::random();
+
+        let rotation = KeyRotation::new(&old, new.public_key(), Utc::now());
+        secrets.private_key = Some(new.to_bytes());
+        secrets.node_rotation = Some(rotation);
+        self.write_secrets(&secrets, pass).await?;
+
+        Ok(rotation)
+    }
+
+}
+
+/// Encrypts secrets in the format of the secrets file,
+/// with a key derived from the password with a new random salt, and a new random nonce
+async fn seal_secrets(config : &SecretConfiguration, pass : &str) -> Result<Vec<u8>, ConfigError> {
+    let params = KdfParams::random();
+    let nonce = random_nonce();
+    let key = params.derive(pass)?;
+
+    let ciphertext = encrypt(&key, &nonce, config).await;
 
+    Ok(SecretsFile::encode(&params, &nonce, &ciphertext))
 }
 
 async fn default_domains() -> HashSet<String> {
-    let mut ret = HashSet::from_iter(vec!["localhost".to_string()].into_iter());
+    let mut ret = HashSet::from_iter(vec!["localhost".to_string()]);
 
     let v4 = public_ip::addr_v4().await;
     let v6 = public_ip::addr_v6().await;
 
-    match v4 {
-        Some(ip) => {
-            ret.insert(ip.to_string());
-        }
-        _ => {}
+    if let Some(ip) = v4 {
+        ret.insert(ip.to_string());
     }
-    match v6 {
-        Some(ip) => {
-            ret.insert(ip.to_string());
-        }
-        _ => {}
+    if let Some(ip) = v6 {
+        ret.insert(ip.to_string());
     }
 
     ret
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A manager of a secrets file in a new temporary folder
+    fn manager() -> ConfigManager {
+        let mut config = Configuration::default();
+        let location = std::env::temp_dir().join(format!("cacophoney-secrets-{:016x}", rand::random::<u64>()));
+        std::fs::create_dir_all(&location).unwrap();
+        config.secret_config.location = location.to_string_lossy().into_owned();
+
+        ConfigManager::new(Arc::new(config))
+    }
+
+    async fn read_file(mgr: &ConfigManager, name: &str) -> std::io::Result<Vec<u8>> {
+        tokio::fs::read(format!("{}/{}", mgr.config().secret_config.location, name)).await
+    }
+
+    #[tokio::test]
+    async fn legacy_secrets_are_migrated() {
+        let mgr = manager();
+        let location = mgr.config().secret_config.location.clone();
+        let secrets = SecretConfiguration {
+            private_key: Some(PrivKey::random().to_bytes()),
+            ..Default::default()
+        };
+
+        // Encrypted with the unsalted hash of the password, with the nonce in its own file
+        let nonce = random_nonce();
+        let legacy = encrypt(&legacy_secrets_key("password"), &nonce, &secrets).await;
+        tokio::fs::write(format!("{}/secret", location), &legacy).await.unwrap();
+        tokio::fs::write(format!("{}/nonce", location), nonce).await.unwrap();
+
+        assert!(matches!(mgr.get_secrets("wrong").await, Err(ConfigError::PasswordError(_))));
+        assert_eq!(read_file(&mgr, "secret").await.unwrap(), legacy);
+
+        let read = mgr.get_secrets("password").await.unwrap();
+        assert_eq!(read.private_key, secrets.private_key);
+
+        let file = read_file(&mgr, "secret").await.unwrap();
+        assert!(SecretsFile::parse(&file).unwrap().unwrap().nonce.is_some());
+        assert!(read_file(&mgr, "nonce").await.is_err());
+        assert_eq!(mgr.get_secrets("password").await.unwrap().private_key, secrets.private_key);
+
+        tokio::fs::remove_dir_all(location).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn every_write_uses_a_new_nonce() {
+        let mgr = manager();
+        let secrets = mgr.create_secrets("password").await.unwrap();
+
+        let first = read_file(&mgr, "secret").await.unwrap();
+        mgr.write_secrets(&secrets, "password").await.unwrap();
+        let second = read_file(&mgr, "secret").await.unwrap();
+
+        let (first, second) = (SecretsFile::parse(&first).unwrap().unwrap(), SecretsFile::parse(&second).unwrap().unwrap());
+        assert_ne!(first.nonce.unwrap(), second.nonce.unwrap());
+        assert_ne!(first.ciphertext, second.ciphertext);
+        assert!(read_file(&mgr, "nonce").await.is_err());
+        assert_eq!(mgr.get_secrets("password").await.unwrap().private_key, secrets.private_key);
+
+        tokio::fs::remove_dir_all(&mgr.config().secret_config.location).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn version_1_files_read_the_nonce_file() {
+        let mgr = manager();
+        let location = mgr.config().secret_config.location.clone();
+        let secrets = SecretConfiguration {
+            private_key: Some(PrivKey::random().to_bytes()),
+            ..Default::default()
+        };
+
+        // Version 1 has the Argon2id header, but no nonce
+        let params = KdfParams::random();
+        let nonce = random_nonce();
+        let ciphertext = encrypt(&params.derive("password").unwrap(), &nonce, &secrets).await;
+        let mut file = SecretsFile::encode(&params, &nonce, &[]);
+        file[4] = 1;
+        file.truncate(file.len() - nonce.len());
+        file.extend(&ciphertext);
+        tokio::fs::write(format!("{}/secret", location), &file).await.unwrap();
+        tokio::fs::write(format!("{}/nonce", location), nonce).await.unwrap();
+
+        assert_eq!(mgr.get_secrets("password").await.unwrap().private_key, secrets.private_key);
+
+        let file = read_file(&mgr, "secret").await.unwrap();
+        let migrated = SecretsFile::parse(&file).unwrap().unwrap();
+        assert!(migrated.nonce.is_some_and(|n| n != nonce));
+        assert!(read_file(&mgr, "nonce").await.is_err());
+
+        tokio::fs::remove_dir_all(location).await.unwrap();
+    }
+}
@@ -3,8 +3,13 @@ use std::collections::HashSet;
 pub use self::manager::*;
 use serde::{Deserialize, Serialize};
 
+use crate::data::{crypto::PubKey, KeyRotation};
+
 mod manager;
 
+/// Path of the configuration file of the node
+pub const CONFIG_PATH: &str = "./Config.toml";
+
 #[derive(Clone, Default, Serialize, Deserialize)]
 pub struct Configuration {
     #[serde(default)]
@@ -16,36 +21,55 @@ pub struct Configuration {
     pub main_config: MainConfiguration,
     #[serde(default)]
     pub secret_config: SecretFileConfiguration,
+    #[serde(default)]
+    pub storage: StorageConfiguration,
+    #[serde(default)]
+    pub identify: IdentifyConfiguration,
+    #[serde(default)]
+    pub peers: PeersConfiguration,
 }
-#[derive(Clone, Default, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct MainConfiguration {
     /// The services provided by the server
     #[serde(default = "default_features")]
     pub features: HashSet<String>,
     /// The protocol version number, e.g 1.0.0
+    #[serde(default = "default_version")]
     pub version: String,
     /// File path of the certificate
-    #[serde(default)]
+    #[serde(default = "default_pubkey")]
     pub cert_path: String,
     /// Private key path of the certificate
-    #[serde(default)]
+    #[serde(default = "default_privkey")]
     pub private_key_path: String,
     /// Domain names present on potential self signed certificates
     #[serde(default)]
     pub domains: Option<HashSet<String>>,
 }
 
+impl Default for MainConfiguration {
+    fn default() -> Self {
+        MainConfiguration {
+            features: default_features(),
+            version: default_version(),
+            cert_path: default_pubkey(),
+            private_key_path: default_privkey(),
+            domains: None,
+        }
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct SecretFileConfiguration {
-    /// The path to the encrypted secrets file
+    /// The path to the folder containing the secrets file
     #[serde(default = "default_secret_location")]
     pub location: String,
     /// The password to unlock the secrets file. If null, you will be prompted to type the password when the node turns on.
     #[serde(default)]
     pub password: Option<String>,
-    /// Reset the private key every time the node is turned on. `private_key` must be null in the secrets file for this option to have any effect.
-    /// If turned off and `private_key` is null, the file will be edited with a random private key.
-    #[serde(default = "default_restart_key")]
+    /// Use a new random node key every time the node is turned on, instead of the key stored in the secrets file.
+    /// Other nodes and clients that pinned the node key reject the node after every restart
+    #[serde(default)]
     pub restart_key: bool,
 }
 
@@ -54,11 +78,127 @@ impl Default for SecretFileConfiguration {
         SecretFileConfiguration {
             location: default_secret_location(),
             password: None,
-            restart_key: default_restart_key(),
+            restart_key: false,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct StorageConfiguration {
+    /// The path to the folder containing the database. Only used if the "storage" feature is enabled
+    #[serde(default = "default_storage_location")]
+    pub location: String,
+    /// Hours a message is kept for a recipient that is not connected. Expired messages are deleted without being delivered
+    #[serde(default = "default_mailbox_retention")]
+    pub mailbox_retention_hours: u64,
+    /// Maximum amount of messages queued for a single recipient. 0 disables queueing
+    #[serde(default = "default_mailbox_max_messages")]
+    pub mailbox_max_messages: usize,
+    /// What happens to a message queued for a recipient whose mailbox is full
+    #[serde(default)]
+    pub mailbox_overflow: MailboxOverflow,
+}
+
+impl Default for StorageConfiguration {
+    fn default() -> Self {
+        StorageConfiguration {
+            location: default_storage_location(),
+            mailbox_retention_hours: default_mailbox_retention(),
+            mailbox_max_messages: default_mailbox_max_messages(),
+            mailbox_overflow: MailboxOverflow::default(),
+        }
+    }
+}
+
+/// How a full mailbox makes room for a new message
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MailboxOverflow {
+    /// The oldest queued messages are deleted without being delivered
+    #[default]
+    DropOldest,
+    /// The new message is not queued, and its sender is told the mailbox is full
+    Reject,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct IdentifyConfiguration {
+    /// Maximum difference in seconds between the node's time and the timestamp of an identify
+    #[serde(default = "default_clock_skew")]
+    pub clock_skew_secs: u64,
+    /// Maximum amount of recent identifies remembered to reject replays
+    #[serde(default = "default_replay_cache_size")]
+    pub replay_cache_size: usize,
+    /// Failed answers to the admin challenge after which an IP address cannot authenticate as administrator
+    #[serde(default = "default_admin_max_failures")]
+    pub admin_max_failures: u32,
+    /// Seconds an IP address cannot authenticate as administrator after too many failures
+    #[serde(default = "default_admin_lockout")]
+    pub admin_lockout_secs: u64,
+}
+
+impl Default for IdentifyConfiguration {
+    fn default() -> Self {
+        IdentifyConfiguration {
+            clock_skew_secs: default_clock_skew(),
+            replay_cache_size: default_replay_cache_size(),
+            admin_max_failures: default_admin_max_failures(),
+            admin_lockout_secs: default_admin_lockout(),
         }
     }
 }
 
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PeersConfiguration {
+    /// Nodes linked when the node starts, and kept linked. Only used if the "federation" feature is enabled
+    #[serde(default)]
+    pub bootstrap: Vec<BootstrapPeer>,
+    /// Address other nodes can link to this node with, as `host:port`. The node is not shared with other nodes if [`None`]
+    #[serde(default)]
+    pub public_address: Option<String>,
+    /// The node stops linking to other nodes once it has this many links
+    #[serde(default = "default_max_peers")]
+    pub max_peers: usize,
+    /// Seconds before linking a node again. Doubled after every failure, up to `reconnect_max_secs`
+    #[serde(default = "default_reconnect_min")]
+    pub reconnect_min_secs: u64,
+    #[serde(default = "default_reconnect_max")]
+    pub reconnect_max_secs: u64,
+    /// Seconds between two exchanges of known nodes with the linked nodes
+    #[serde(default = "default_gossip_interval")]
+    pub gossip_interval_secs: u64,
+    /// Hours a node learned from other nodes is kept without being seen
+    #[serde(default = "default_forget_after")]
+    pub forget_after_hours: u64,
+    /// Hours a record of the node hosting a public key is kept in the DHT. Clients publish their record again before it expires
+    #[serde(default = "default_home_record_ttl")]
+    pub home_record_ttl_hours: u64,
+}
+
+impl Default for PeersConfiguration {
+    fn default() -> Self {
+        PeersConfiguration {
+            bootstrap: Vec::new(),
+            public_address: None,
+            max_peers: default_max_peers(),
+            reconnect_min_secs: default_reconnect_min(),
+            reconnect_max_secs: default_reconnect_max(),
+            gossip_interval_secs: default_gossip_interval(),
+            forget_after_hours: default_forget_after(),
+            home_record_ttl_hours: default_home_record_ttl(),
+        }
+    }
+}
+
+/// A node linked when the node starts
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BootstrapPeer {
+    /// The address of the node, as `host:port`
+    pub address: String,
+    /// The hex encoded key of the node. The link is rejected if the node has another key
+    pub key: PubKey,
+}
+
 #[derive(Clone, Serialize, Deserialize, Default)]
 pub struct SecretConfiguration {
     #[serde(default)]
@@ -66,9 +206,13 @@ pub struct SecretConfiguration {
     /// The password to unlock the certificate
     #[serde(default)]
     pub cert_password: Option<String>,
-    /// A password for client administrative privileges. If [`None`], no client can administrate the server.
+    /// The blake3 hash of the password for client administrative privileges. If [`None`], no client can administrate the server.
     #[serde(default)]
     pub admin_pass: Option<[u8; 32]>,
+    /// The rotation from the previous node key to `private_key`, signed by the previous key.
+    /// Sent to clients and nodes that pinned the previous key
+    #[serde(default)]
+    pub node_rotation: Option<KeyRotation>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -104,18 +248,55 @@ fn default_features() -> HashSet<String> {
             "proxy/json5".to_string(),
             "proxy/json".to_string(), // Recommended
             "storage".to_string(),
-        ]
-        .into_iter(),
+        ],
     )
 }
 fn default_version() -> String {
     "0.1.0".to_string()
 }
 fn default_secret_location() -> String {
-    "./secrets/secret".to_string()
+    "./secrets".to_string()
 }
-fn default_restart_key() -> bool {
-    true
+fn default_storage_location() -> String {
+    "./data".to_string()
+}
+fn default_mailbox_retention() -> u64 {
+    // One week
+    168
+}
+fn default_mailbox_max_messages() -> usize {
+    1000
+}
+fn default_clock_skew() -> u64 {
+    300
+}
+fn default_replay_cache_size() -> usize {
+    10000
+}
+fn default_admin_max_failures() -> u32 {
+    5
+}
+fn default_admin_lockout() -> u64 {
+    900
+}
+fn default_max_peers() -> usize {
+    16
+}
+fn default_reconnect_min() -> u64 {
+    5
+}
+fn default_reconnect_max() -> u64 {
+    300
+}
+fn default_gossip_interval() -> u64 {
+    300
+}
+fn default_forget_after() -> u64 {
+    // One week
+    168
+}
+fn default_home_record_ttl() -> u64 {
+    24
 }
 
 fn default_pubkey() -> String {
@@ -146,17 +327,59 @@ features = [
     "proxy/json",
     
     "storage",
+
+    # Link with other nodes, see the [peers] section
+    # "federation",
 ]
 
 [secret_config]
-# The path to the encrypted secrets file
-location = "./secrets/secret"
-# Reset the private key every time the node is turned on. `private_key` must be null in the secrets file for this option to have any effect.
-# If turned off and `private_key` is null, the file will be edited with a random private key.
-restart_key = true
+# The path to the folder containing the secrets file
+location = "./secrets"
+# Use a new random node key every time the node is turned on, instead of the key stored in the secrets file.
+# Other nodes and clients that pinned the node key reject the node after every restart.
+restart_key = false
 # The password to unlock the secrets file. If null, you will be prompted to type the password when the node turns on.
 # password = ""
 
+[storage]
+# The path to the folder containing the database. Only used if the "storage" feature is enabled
+location = "./data"
+# Hours a message is kept for a recipient that is not connected
+mailbox_retention_hours = 168
+# Maximum amount of messages queued for a single recipient. 0 disables queueing
+mailbox_max_messages = 1000
+# What happens when the mailbox of a recipient is full: "drop_oldest" deletes the oldest messages, "reject" refuses the new message
+mailbox_overflow = "drop_oldest"
+
+[identify]
+# Maximum difference in seconds between the node's time and the timestamp of an identify
+clock_skew_secs = 300
+# Maximum amount of recent identifies remembered to reject replays
+replay_cache_size = 10000
+# Failed answers to the admin challenge after which an IP address cannot authenticate as administrator
+admin_max_failures = 5
+# Seconds an IP address cannot authenticate as administrator after too many failures
+admin_lockout_secs = 900
+
+[peers]
+# Nodes linked when the node starts, and kept linked. Only used if the "federation" feature is enabled
+# bootstrap = [
+#     { address = "node.example.com:56665", key = "<hex encoded node key>" },
+# ]
+# Address other nodes can link to this node with. If not set, the node is not shared with other nodes
+# public_address = "node.example.com:56665"
+# The node stops linking to other nodes once it has this many links
+max_peers = 16
+# Seconds before linking a node again, doubled after every failure up to reconnect_max_secs
+reconnect_min_secs = 5
+reconnect_max_secs = 300
+# Seconds between two exchanges of known nodes with the linked nodes
+gossip_interval_secs = 300
+# Hours a node learned from other nodes is kept without being seen
+forget_after_hours = 168
+# Hours a record of the node hosting a public key is kept in the DHT
+home_record_ttl_hours = 24
+
 [quic]
 address = "::/0"
 port = 56665
@@ -0,0 +1,96 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::{crypto::PubKey, StreamIdentify};
+
+/// A challenge sent by the node when a stream is identified as [`StreamIdentify::Administration`]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AdminChallenge {
+    /// Random bytes that have to be hashed with the admin password
+    pub nonce: [u8; 32],
+}
+
+/// The response to an [`AdminChallenge`]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AdminAuth {
+    /// The blake3 hash of the nonce, keyed with the blake3 hash of the admin password
+    pub response: [u8; 32],
+}
+
+impl AdminAuth {
+    /// Creates the response to a challenge from the admin password
+    pub fn new(password: &str, challenge: &AdminChallenge) -> Self {
+        let key = blake3::hash(password.as_bytes());
+
+        Self {
+            response: *blake3::keyed_hash(key.as_bytes(), &challenge.nonce).as_bytes(),
+        }
+    }
+}
+
+/// A command sent on an authenticated administration stream
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AdminCommand {
+    /// List every connection to the node
+    ListConnections,
+    /// Close every connection identified as a public key
+    Kick { key: PubKey },
+    /// Close every connection identified as a public key, and reject its identifies until it is unbanned
+    Ban { key: PubKey },
+    Unban { key: PubKey },
+    ListBans,
+    /// Read the configuration file again. Addresses, ports and features only change after a restart
+    ReloadConfig,
+    Stats,
+}
+
+/// The node's response to an [`AdminCommand`]
+#[derive(Clone, Serialize, Deserialize)]
+pub enum AdminResponse {
+    Connections(Vec<ConnectionInfo>),
+    /// Amount of connections that were closed
+    Kicked(usize),
+    Bans(Vec<PubKey>),
+    Stats(Box<NodeStats>),
+    /// The command succeeded and has nothing to return
+    Done,
+}
+
+/// How a client is connected to the node
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Transport {
+    Quic,
+    WebSocket,
+}
+
+/// A connection to the node, as seen by administrators
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    /// Address of the peer
+    pub address: String,
+    pub transport: Transport,
+    pub connected_at: DateTime<Utc>,
+    /// The type of the stream, if the client identified it
+    pub stream_type: Option<StreamIdentify>,
+    /// Public keys the connection is identified as
+    pub identities: Vec<PubKey>,
+}
+
+/// Statistics of a running node
+#[derive(Clone, Serialize, Deserialize)]
+pub struct NodeStats {
+    /// The protocol version of the node
+    pub version: String,
+    /// The key identifying the node to other nodes and clients
+    pub node_key: PubKey,
+    /// The fingerprint of the node key, see [`PubKey::fingerprint`]
+    pub fingerprint: String,
+    pub started_at: DateTime<Utc>,
+    pub connections: usize,
+    /// Amount of distinct public keys identified by at least one connection
+    pub online_keys: usize,
+    pub bans: usize,
+    /// Amount of nodes linked with the node
+    pub peers: usize,
+}
@@ -1,12 +1,15 @@
-use std::{error::Error, ops::Deref};
+use std::{
+    error::Error,
+    fmt::Debug,
+    hash::{Hash, Hasher},
+    ops::Deref,
+};
 
+use aes_gcm::aead::OsRng;
 use byteorder::{LittleEndian, WriteBytesExt};
 use chrono::{DateTime, Utc};
 use libsecp256k1::{verify, Message, PublicKey, SecretKey, Signature};
 use serde::{de::Visitor, Deserialize, Serialize};
-use serde_with::serde_as;
-
-use super::Identity;
 
 #[derive(Clone, Copy)]
 pub struct PubKey {
@@ -19,12 +22,28 @@ impl PubKey {
     pub fn new(key: [u8; 33]) -> Self {
         Self { key, verif: None }
     }
-    fn verify(&mut self, msg: &[u8], sig: &[u8; 64]) -> Result<bool, Box<dyn Error>> {
+    /// Verifies the signature of the blake3 hash of a message
+    pub fn verify(&mut self, msg: &[u8], sig: &[u8; 64]) -> Result<bool, Box<dyn Error>> {
         let hash = blake3::hash(msg);
 
         self.verify_hash(hash.as_bytes(), sig)
     }
 
+    /// Returns the hex encoding of the key, as written in the configuration
+    pub fn to_hex(&self) -> String {
+        self.key.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+    /// Returns a short hash of the key for humans to compare, as groups of 4 hex characters
+    pub fn fingerprint(&self) -> String {
+        let hash = blake3::hash(&self.key);
+        let groups: Vec<String> = hash.as_bytes()[..10]
+            .chunks(2)
+            .map(|c| format!("{:02x}{:02x}", c[0], c[1]))
+            .collect();
+
+        groups.join(":")
+    }
+
     fn verify_hash(&mut self, hash: &[u8; 32], sig: &[u8; 64]) -> Result<bool, Box<dyn Error>> {
         let key = match &self.verif {
             Some(v) => v,
@@ -43,6 +62,29 @@ impl PubKey {
     }
 }
 
+impl PartialEq for PubKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+impl Eq for PubKey {}
+
+impl Hash for PubKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key.hash(state)
+    }
+}
+
+impl Debug for PubKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PubKey(")?;
+        for b in self.key {
+            write!(f, "{:02x}", b)?;
+        }
+        write!(f, ")")
+    }
+}
+
 impl Default for PubKey {
     fn default() -> Self {
         Self::new([0u8; 33])
@@ -105,6 +147,40 @@ impl<'de> Visitor<'de> for PubKeyVisitor {
         // Cannot fail
         Ok(PubKey::new(v.try_into().unwrap()))
     }
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: serde::de::SeqAccess<'de>,
+    {
+        // Formats without a byte type (such as JSON) serialize bytes as a sequence
+        let mut key = [0u8; 33];
+
+        for (i, b) in key.iter_mut().enumerate() {
+            *b = seq
+                .next_element()?
+                .ok_or_else(|| serde::de::Error::invalid_length(i, &self))?;
+        }
+        if seq.next_element::<u8>()?.is_some() {
+            return Err(serde::de::Error::custom("pub key length must be 33 bytes"));
+        }
+
+        Ok(PubKey::new(key))
+    }
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        // Text formats written by hand (such as the configuration) use the hex encoding of the key
+        if v.len() != 66 || !v.is_ascii() {
+            return Err(E::custom("hex pub key length must be 66 characters"));
+        }
+
+        let mut key = [0u8; 33];
+        for (i, b) in key.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&v[i * 2..i * 2 + 2], 16).map_err(E::custom)?;
+        }
+
+        Ok(PubKey::new(key))
+    }
     fn visit_unit<E>(self) -> Result<Self::Value, E>
     where
         E: serde::de::Error,
@@ -129,6 +205,16 @@ impl PrivKey {
             key: SecretKey::parse(&key)?,
         })
     }
+    /// Generates a random private key
+    pub fn random() -> Self {
+        PrivKey {
+            key: SecretKey::random(&mut OsRng),
+        }
+    }
+    /// Returns the bytes of the key, as stored in the secrets file
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.key.serialize()
+    }
     pub fn sign_hash(&self, msg: &[u8; 32]) -> [u8; 64] {
         let msg = libsecp256k1::Message::parse(msg);
         libsecp256k1::sign(&msg, &self.key).0.serialize()
@@ -136,6 +222,18 @@ impl PrivKey {
     pub fn sign(&self, msg: &[u8]) -> [u8; 64] {
         self.sign_hash(blake3::hash(msg).as_bytes())
     }
+    /// Returns the public key of the private key
+    pub fn public_key(&self) -> PubKey {
+        PubKey::new(PublicKey::from_secret_key(&self.key).serialize_compressed())
+    }
+    /// Computes the ECDH shared point with a public key, compressed. Both keys of a pair compute the same point.
+    /// The point is not uniformly random and has to go through a key derivation function before being used as a key
+    pub fn shared_point(&self, other: &PubKey) -> Result<[u8; 33], libsecp256k1::Error> {
+        let mut point = PublicKey::parse_compressed(&other.key)?;
+        point.tweak_mul_assign(&self.key)?;
+
+        Ok(point.serialize_compressed())
+    }
 }
 
 impl Serialize for PrivKey {
@@ -187,10 +285,30 @@ impl<'de> Deserialize<'de> for PrivKey {
     }
 }
 
-/// A type for a signed message
+/// A type for a signed message. Written as the first byte of every signed message,
+/// so a signature made for one type is never valid for another
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SigmsgType {
-    /// A dummy message. Used for identifying public keys to clients and servers
-    Dummy = 0,
+    /// A challenge of a node. Used for identifying public keys to clients and servers
+    Identify = 0,
+    /// A signed prekey of a public key. Used for starting ratchet sessions
+    PrekeyPublish = 1,
+    /// A message sent from one public key to another
+    MessageSend = 2,
+    /// A change of the profile of a public key
+    ProfileUpdate = 3,
+    /// An operation on a group, such as an invite or a kick
+    GroupOperation = 4,
+    /// A message between two nodes, signed with the key of the sending node
+    NodeToNode = 5,
+    /// The node hosting a public key, published in the DHT
+    HomeRecord = 6,
+    /// A master key allowing a device key to act as its user
+    Delegation = 7,
+    /// A master key cutting off a device key
+    Revocation = 8,
+    /// A public key replaced by a new key
+    KeyRotation = 9,
 }
 
 /// A message that can be serialized, hashed, then signed.
@@ -201,21 +319,122 @@ pub struct SignedMsg {
     hash: blake3::Hash,
 }
 
-impl SignedMsg {
-    pub fn from_identity(sig_msg: &[u8; 32], timestamp: &DateTime<Utc>) -> Self {
+/// Writes the contents of a [`SignedMsg`] before they are hashed
+struct SignedMsgBuilder {
+    contents: Vec<u8>,
+}
+
+impl SignedMsgBuilder {
+    fn new(ty: SigmsgType) -> Self {
         let mut contents = Vec::new();
 
         // Write header byte
-        let _ = contents.write_u8(SigmsgType::Dummy as u8);
-        // Write sig_msg
-        contents.extend(sig_msg);
-        // Write timestamp as millis. Always little endian.
-        let _ = contents.write_i64::<LittleEndian>(timestamp.timestamp_millis());
-
-        Self {
-            hash: blake3::hash(&contents),
+        let _ = contents.write_u8(ty as u8);
+
+        Self { contents }
+    }
+    /// Writes bytes of a fixed length
+    fn fixed(mut self, bytes: &[u8]) -> Self {
+        self.contents.extend(bytes);
+        self
+    }
+    /// Writes bytes prefixed by their length, so two fields cannot be shifted into each other
+    fn bytes(mut self, bytes: &[u8]) -> Self {
+        let _ = self.contents.write_u32::<LittleEndian>(bytes.len() as u32);
+        self.contents.extend(bytes);
+        self
+    }
+    /// Writes the timestamp as millis and hashes the contents
+    fn finish(mut self, timestamp: &DateTime<Utc>) -> SignedMsg {
+        // Always little endian.
+        let _ = self.contents.write_i64::<LittleEndian>(timestamp.timestamp_millis());
+
+        SignedMsg {
+            hash: blake3::hash(&self.contents),
         }
     }
+}
+
+impl SignedMsg {
+    /// A challenge signed to identify
+    pub fn from_identity(sig_msg: &[u8; 32], timestamp: &DateTime<Utc>) -> Self {
+        SignedMsgBuilder::new(SigmsgType::Identify)
+            .fixed(sig_msg)
+            .finish(timestamp)
+    }
+    /// A prekey published by its owner
+    pub fn from_prekey(prekey: &PubKey, timestamp: &DateTime<Utc>) -> Self {
+        SignedMsgBuilder::new(SigmsgType::PrekeyPublish)
+            .fixed(&prekey.key)
+            .finish(timestamp)
+    }
+    /// The contents of a message sent from one public key to another
+    pub fn from_message(from: &PubKey, to: &PubKey, content: &[u8], timestamp: &DateTime<Utc>) -> Self {
+        SignedMsgBuilder::new(SigmsgType::MessageSend)
+            .fixed(&from.key)
+            .fixed(&to.key)
+            .bytes(content)
+            .finish(timestamp)
+    }
+    /// The serialized profile of a public key
+    pub fn from_profile(key: &PubKey, profile: &[u8], timestamp: &DateTime<Utc>) -> Self {
+        SignedMsgBuilder::new(SigmsgType::ProfileUpdate)
+            .fixed(&key.key)
+            .bytes(profile)
+            .finish(timestamp)
+    }
+    /// A serialized operation on a group
+    pub fn from_group_op(group: &[u8; 32], operation: &[u8], timestamp: &DateTime<Utc>) -> Self {
+        SignedMsgBuilder::new(SigmsgType::GroupOperation)
+            .fixed(group)
+            .bytes(operation)
+            .finish(timestamp)
+    }
+    /// A serialized message sent by a node to another node
+    pub fn from_node(from: &PubKey, to: &PubKey, payload: &[u8], timestamp: &DateTime<Utc>) -> Self {
+        SignedMsgBuilder::new(SigmsgType::NodeToNode)
+            .fixed(&from.key)
+            .fixed(&to.key)
+            .bytes(payload)
+            .finish(timestamp)
+    }
+    /// The node hosting a public key
+    pub fn from_home(key: &PubKey, node: &PubKey, timestamp: &DateTime<Utc>) -> Self {
+        SignedMsgBuilder::new(SigmsgType::HomeRecord)
+            .fixed(&key.key)
+            .fixed(&node.key)
+            .finish(timestamp)
+    }
+    /// A delegation of a master key to a device key
+    pub fn from_delegation(
+        master: &PubKey,
+        device: &PubKey,
+        expires: Option<&DateTime<Utc>>,
+        timestamp: &DateTime<Utc>,
+    ) -> Self {
+        // Empty if the delegation never expires
+        let expires = expires.map(|t| t.timestamp_millis().to_le_bytes().to_vec()).unwrap_or_default();
+
+        SignedMsgBuilder::new(SigmsgType::Delegation)
+            .fixed(&master.key)
+            .fixed(&device.key)
+            .bytes(&expires)
+            .finish(timestamp)
+    }
+    /// A revocation of a device key by a master key
+    pub fn from_revocation(master: &PubKey, device: &PubKey, timestamp: &DateTime<Utc>) -> Self {
+        SignedMsgBuilder::new(SigmsgType::Revocation)
+            .fixed(&master.key)
+            .fixed(&device.key)
+            .finish(timestamp)
+    }
+    /// A public key replacing an old key. Signed by the old key, and by the master key countersigning the rotation
+    pub fn from_rotation(old: &PubKey, new: &PubKey, timestamp: &DateTime<Utc>) -> Self {
+        SignedMsgBuilder::new(SigmsgType::KeyRotation)
+            .fixed(&old.key)
+            .fixed(&new.key)
+            .finish(timestamp)
+    }
     /// Returns the hash of the converted message
     pub fn hash(&self) -> &[u8; 32] {
         self.hash.as_bytes()
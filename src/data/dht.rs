@@ -0,0 +1,92 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+
+use super::{
+    crypto::{PrivKey, PubKey, SignedMsg},
+    PeerInfo,
+};
+
+/// Position of a public key or of a node in the DHT: the blake3 hash of the public key
+pub type DhtId = [u8; 32];
+
+/// Returns the position of a public key in the DHT
+pub fn dht_id(key: &PubKey) -> DhtId {
+    *blake3::hash(&key.key).as_bytes()
+}
+
+/// A public key stating which node it is connected to
+#[serde_as]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HomeRecord {
+    pub key: PubKey,
+    /// Key of the node hosting the public key
+    pub node: PubKey,
+    pub timestamp: DateTime<Utc>,
+    #[serde_as(as = "[_; 64]")]
+    pub signature: [u8; 64],
+}
+
+impl HomeRecord {
+    /// Creates a record of the public key of `key`, signed by it
+    pub fn new(key: &PrivKey, node: PubKey, timestamp: DateTime<Utc>) -> Self {
+        let public_key = key.public_key();
+
+        Self {
+            key: public_key,
+            node,
+            timestamp,
+            signature: SignedMsg::from_home(&public_key, &node, &timestamp).sign(key),
+        }
+    }
+    /// Returns true if the public key of the record signed it
+    pub fn verify(&self) -> bool {
+        let mut key = self.key;
+
+        matches!(
+            SignedMsg::from_home(&self.key, &self.node, &self.timestamp).verify(&mut key, &self.signature),
+            Ok(true)
+        )
+    }
+}
+
+/// A [`HomeRecord`] as stored in the DHT, with the address of the node hosting the public key
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HomeEntry {
+    pub record: HomeRecord,
+    /// Address other nodes link to the node with, as `host:port`.
+    /// Not signed, the key of the node is checked when linking
+    pub address: String,
+}
+
+/// A node looking for a [`HomeEntry`], or for the nodes closest to a position
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DhtFind {
+    /// ID of the request, copied to the response
+    pub id: u64,
+    pub target: DhtId,
+}
+
+/// A node asking another node to store a [`HomeEntry`]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DhtStore {
+    /// ID of the request, copied to the response
+    pub id: u64,
+    pub entry: HomeEntry,
+}
+
+/// The response to a [`DhtFind`] or a [`DhtStore`]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DhtResponse {
+    pub id: u64,
+    /// The entry that was looked for, if the node stores it
+    pub entry: Option<HomeEntry>,
+    /// The nodes known by the node that are the closest to the position
+    pub closer: Vec<PeerInfo>,
+}
+
+/// A client looking for the node hosting a public key
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HomeLookup {
+    pub public_key: PubKey,
+}
@@ -0,0 +1,134 @@
+use aes_gcm::{
+    aead::{Aead, OsRng, Payload},
+    Aes256Gcm, KeyInit,
+};
+use generic_array::GenericArray;
+use rand::RngCore;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_with::{serde_as, Bytes};
+
+use crate::error::EnvelopeError;
+
+use super::crypto::{PrivKey, PubKey};
+
+/// Context of the key derivation. Changing it makes every existing envelope unreadable
+const ENVELOPE_KDF_CONTEXT: &str = "cacophoney 2022-10 sealed envelope v1";
+
+/// Contents encrypted by a sender for a recipient. The node relaying it only sees the ciphertext.
+///
+/// The key is derived from the ECDH shared point of the sender and the recipient, so both of them can open the envelope.
+/// Both public keys are authenticated, an envelope cannot be relayed as coming from or going to another public key.
+#[serde_as]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SealedEnvelope {
+    /// Random nonce of the encryption
+    #[serde_as(as = "[_; 12]")]
+    pub nonce: [u8; 12],
+    /// The CBOR serialized contents, encrypted with AES-256-GCM
+    #[serde_as(as = "Bytes")]
+    pub ciphertext: Vec<u8>,
+}
+
+impl SealedEnvelope {
+    /// Serializes and encrypts contents sent by the owner of `sender` to `recipient`
+    pub fn seal<T: Serialize>(sender: &PrivKey, recipient: &PubKey, contents: &T) -> Result<Self, EnvelopeError> {
+        let from = sender.public_key();
+        let cipher = Self::cipher(sender, recipient)?;
+
+        let mut nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut nonce);
+
+        let msg = serde_cbor::to_vec(contents)?;
+        let aad = Self::associated_data(&from, recipient);
+
+        let ciphertext = cipher.encrypt(
+            GenericArray::from_slice(&nonce),
+            Payload {
+                msg: &msg,
+                aad: &aad,
+            },
+        )?;
+
+        Ok(Self { nonce, ciphertext })
+    }
+    /// Decrypts and deserializes an envelope sent from `from` to `to`.
+    /// `key` must be the private key of either of them, and `other` the public key of the other one.
+    pub fn open<T: DeserializeOwned>(
+        &self,
+        key: &PrivKey,
+        other: &PubKey,
+        from: &PubKey,
+        to: &PubKey,
+    ) -> Result<T, EnvelopeError> {
+        let cipher = Self::cipher(key, other)?;
+        let aad = Self::associated_data(from, to);
+
+        let msg = cipher.decrypt(
+            GenericArray::from_slice(&self.nonce),
+            Payload {
+                msg: &self.ciphertext,
+                aad: &aad,
+            },
+        )?;
+
+        Ok(serde_cbor::from_slice(&msg)?)
+    }
+    /// Decrypts an envelope received by the owner of `recipient` from `sender`
+    pub fn open_from<T: DeserializeOwned>(&self, recipient: &PrivKey, sender: &PubKey) -> Result<T, EnvelopeError> {
+        self.open(recipient, sender, sender, &recipient.public_key())
+    }
+
+    fn cipher(key: &PrivKey, other: &PubKey) -> Result<Aes256Gcm, EnvelopeError> {
+        let point = key.shared_point(other)?;
+        let key = blake3::derive_key(ENVELOPE_KDF_CONTEXT, &point);
+
+        Ok(Aes256Gcm::new(GenericArray::from_slice(&key)))
+    }
+    /// The direction of the envelope. Always the sender's key followed by the recipient's key
+    fn associated_data(from: &PubKey, to: &PubKey) -> [u8; 66] {
+        let mut aad = [0u8; 66];
+        aad[..33].copy_from_slice(&from.key);
+        aad[33..].copy_from_slice(&to.key);
+
+        aad
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn both_sides_open_the_envelope() {
+        let sender = PrivKey::random();
+        let recipient = PrivKey::random();
+        let (from, to) = (sender.public_key(), recipient.public_key());
+
+        let envelope = SealedEnvelope::seal(&sender, &to, &"hello").unwrap();
+        assert_eq!(envelope.open_from::<String>(&recipient, &from).unwrap(), "hello");
+        assert_eq!(envelope.open::<String>(&sender, &to, &from, &to).unwrap(), "hello");
+    }
+
+    #[test]
+    fn tampered_envelopes_are_rejected() {
+        let sender = PrivKey::random();
+        let recipient = PrivKey::random();
+        let other = PrivKey::random();
+        let (from, to) = (sender.public_key(), recipient.public_key());
+        let envelope = SealedEnvelope::seal(&sender, &to, &"hello").unwrap();
+
+        let mut tampered = envelope.clone();
+        tampered.ciphertext[0] ^= 1;
+        assert!(matches!(tampered.open_from::<String>(&recipient, &from), Err(EnvelopeError::DecryptError(_))));
+
+        let mut tampered = envelope.clone();
+        tampered.nonce[0] ^= 1;
+        assert!(matches!(tampered.open_from::<String>(&recipient, &from), Err(EnvelopeError::DecryptError(_))));
+
+        // Relayed as coming from another public key, or opened by another public key
+        assert!(envelope.open_from::<String>(&recipient, &other.public_key()).is_err());
+        assert!(envelope.open_from::<String>(&other, &from).is_err());
+        // Relayed back to the sender, as if the recipient sent it
+        assert!(envelope.open::<String>(&sender, &to, &to, &from).is_err());
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, Bytes};
+
+use super::crypto::PubKey;
+
+/// Unique ID of a group, generated by the node creating it
+pub type GroupId = [u8; 32];
+
+/// A group chat between public keys
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Group {
+    pub id: GroupId,
+    /// Display name of the group
+    pub name: String,
+    /// The public key that created the group, or received it from the previous owner
+    pub owner: PubKey,
+    /// Members that can invite and kick other members. Never contains the owner
+    pub admins: HashSet<PubKey>,
+    /// Every member of the group, including the owner and the admins
+    pub members: HashSet<PubKey>,
+}
+
+/// The role of a member of a [`Group`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum GroupRole {
+    Member = 0,
+    Admin = 1,
+    Owner = 2,
+}
+
+impl Group {
+    /// Creates a group owned by `owner`. The owner is always a member
+    pub fn new(id: GroupId, name: String, owner: PubKey, members: impl IntoIterator<Item = PubKey>) -> Self {
+        let mut members: HashSet<PubKey> = members.into_iter().collect();
+        members.insert(owner);
+
+        Self {
+            id,
+            name,
+            owner,
+            admins: HashSet::new(),
+            members,
+        }
+    }
+    /// Returns the role of a public key, or [`None`] if it is not a member
+    pub fn role(&self, key: &PubKey) -> Option<GroupRole> {
+        if self.owner == *key {
+            Some(GroupRole::Owner)
+        } else if self.admins.contains(key) {
+            Some(GroupRole::Admin)
+        } else if self.members.contains(key) {
+            Some(GroupRole::Member)
+        } else {
+            None
+        }
+    }
+    /// Returns true if `actor` can invite other public keys
+    pub fn can_invite(&self, actor: &PubKey) -> bool {
+        matches!(self.role(actor), Some(GroupRole::Admin | GroupRole::Owner))
+    }
+    /// Returns true if `actor` can kick `target`. Only roles lower than the role of the actor can be kicked
+    pub fn can_kick(&self, actor: &PubKey, target: &PubKey) -> bool {
+        match (self.role(actor), self.role(target)) {
+            (Some(actor), Some(target)) => actor >= GroupRole::Admin && actor > target,
+            _ => false,
+        }
+    }
+    /// Removes a member. If the owner is removed, the ownership goes to an admin, or to a member if there are no admins.
+    /// Returns false if the group has no members left
+    pub fn remove_member(&mut self, key: &PubKey) -> bool {
+        self.members.remove(key);
+        self.admins.remove(key);
+
+        if self.owner == *key {
+            // Lowest key first, so every node picks the same new owner
+            let next = match self.admins.iter().min_by_key(|k| k.key) {
+                Some(v) => Some(*v),
+                None => self.members.iter().min_by_key(|k| k.key).copied(),
+            };
+
+            match next {
+                Some(v) => {
+                    self.admins.remove(&v);
+                    self.owner = v;
+                }
+                None => return false,
+            }
+        }
+
+        true
+    }
+}
+
+/// A client creating a group
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GroupCreate {
+    /// The identity of the client that owns the group
+    pub owner: PubKey,
+    pub name: String,
+    /// Members added along with the owner
+    pub members: Vec<PubKey>,
+}
+
+/// An admin or the owner adding a public key to a group
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GroupInvite {
+    pub group: GroupId,
+    /// The identity of the client inviting
+    pub from: PubKey,
+    /// The public key added to the group
+    pub member: PubKey,
+}
+
+/// A member leaving a group
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GroupLeave {
+    pub group: GroupId,
+    /// The identity of the client leaving
+    pub member: PubKey,
+}
+
+/// An admin or the owner removing a member with a lower role from a group
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GroupKick {
+    pub group: GroupId,
+    /// The identity of the client kicking
+    pub from: PubKey,
+    /// The member removed from the group
+    pub member: PubKey,
+}
+
+/// The owner changing the role of a member to [`GroupRole::Admin`] or [`GroupRole::Member`]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GroupSetRole {
+    pub group: GroupId,
+    /// The identity of the owner
+    pub from: PubKey,
+    pub member: PubKey,
+    pub role: GroupRole,
+}
+
+/// A message sent to every member of a group
+#[serde_as]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GroupMessage {
+    pub group: GroupId,
+    /// Public key of the sender
+    pub from: PubKey,
+    /// The contents, encrypted by the members. The node does not read them
+    #[serde_as(as = "Bytes")]
+    pub content: Vec<u8>,
+}
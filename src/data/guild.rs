@@ -0,0 +1,313 @@
+use std::collections::HashSet;
+
+use bitflags::bitflags;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, Bytes};
+
+use super::crypto::PubKey;
+
+/// Unique ID of a guild, generated by the node creating it
+pub type GuildId = [u8; 32];
+/// ID of a channel, unique in its guild
+pub type ChannelId = u64;
+/// ID of a role, unique in its guild
+pub type RoleId = u64;
+
+/// The role every member of a guild has
+pub const EVERYONE_ROLE: RoleId = 0;
+
+bitflags! {
+    /// What a member can do in a guild or in a channel
+    pub struct Permissions: u64 {
+        /// Receive the messages of a channel
+        const VIEW_CHANNEL    = 1 << 0;
+        /// Send messages to a channel
+        const SEND_MESSAGES   = 1 << 1;
+        /// Add public keys to the guild
+        const INVITE_MEMBERS  = 1 << 2;
+        /// Remove members from the guild
+        const KICK_MEMBERS    = 1 << 3;
+        /// Create, change and delete channels
+        const MANAGE_CHANNELS = 1 << 4;
+        /// Create, change, delete and give roles
+        const MANAGE_ROLES    = 1 << 5;
+        /// Every permission, in every channel
+        const ADMINISTRATOR   = 1 << 6;
+    }
+}
+
+impl Default for Permissions {
+    /// The permissions of the everyone role of a new guild
+    fn default() -> Self {
+        Permissions::VIEW_CHANNEL | Permissions::SEND_MESSAGES
+    }
+}
+
+impl Serialize for Permissions {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_u64(self.bits())
+    }
+}
+impl<'de> Deserialize<'de> for Permissions {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        // Unknown bits are permissions added by newer nodes
+        Ok(Permissions::from_bits_truncate(u64::deserialize(deserializer)?))
+    }
+}
+
+/// A named set of permissions given to members
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Role {
+    pub id: RoleId,
+    pub name: String,
+    pub permissions: Permissions,
+}
+
+/// Permissions given or taken from a role in a single channel
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PermissionOverwrite {
+    pub role: RoleId,
+    pub allow: Permissions,
+    pub deny: Permissions,
+}
+
+/// A text channel of a guild
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Channel {
+    pub id: ChannelId,
+    pub name: String,
+    pub overwrites: Vec<PermissionOverwrite>,
+}
+
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GuildMember {
+    pub key: PubKey,
+    /// Roles of the member, other than the everyone role
+    pub roles: HashSet<RoleId>,
+}
+
+/// A community of public keys with channels and roles
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Guild {
+    pub id: GuildId,
+    pub name: String,
+    /// The owner has every permission and cannot be kicked
+    pub owner: PubKey,
+    /// Every role of the guild, including the everyone role
+    pub roles: Vec<Role>,
+    pub channels: Vec<Channel>,
+    /// Every member of the guild, including the owner
+    pub members: Vec<GuildMember>,
+}
+
+impl Guild {
+    /// Creates a guild owned by `owner`, with the everyone role and a single channel
+    pub fn new(id: GuildId, name: String, owner: PubKey) -> Self {
+        Self {
+            id,
+            name,
+            owner,
+            roles: vec![Role {
+                id: EVERYONE_ROLE,
+                name: "everyone".to_string(),
+                permissions: Permissions::default(),
+            }],
+            channels: vec![Channel {
+                id: 0,
+                name: "general".to_string(),
+                overwrites: Vec::new(),
+            }],
+            members: vec![GuildMember {
+                key: owner,
+                roles: HashSet::new(),
+            }],
+        }
+    }
+    pub fn member(&self, key: &PubKey) -> Option<&GuildMember> {
+        self.members.iter().find(|m| m.key == *key)
+    }
+    pub fn member_mut(&mut self, key: &PubKey) -> Option<&mut GuildMember> {
+        self.members.iter_mut().find(|m| m.key == *key)
+    }
+    pub fn is_member(&self, key: &PubKey) -> bool {
+        self.member(key).is_some()
+    }
+    pub fn role(&self, id: RoleId) -> Option<&Role> {
+        self.roles.iter().find(|r| r.id == id)
+    }
+    pub fn channel(&self, id: ChannelId) -> Option<&Channel> {
+        self.channels.iter().find(|c| c.id == id)
+    }
+    /// Returns an ID not used by any role
+    pub fn next_role_id(&self) -> RoleId {
+        self.roles.iter().map(|r| r.id).max().unwrap_or(EVERYONE_ROLE) + 1
+    }
+    /// Returns an ID not used by any channel
+    pub fn next_channel_id(&self) -> ChannelId {
+        self.channels.iter().map(|c| c.id + 1).max().unwrap_or(0)
+    }
+    /// Returns the permissions of a public key in the whole guild. Public keys that are not members have none
+    pub fn permissions(&self, key: &PubKey) -> Permissions {
+        if self.owner == *key {
+            return Permissions::all();
+        }
+        let member = match self.member(key) {
+            Some(v) => v,
+            None => return Permissions::empty(),
+        };
+
+        let mut permissions = Permissions::empty();
+        for role in &self.roles {
+            if role.id == EVERYONE_ROLE || member.roles.contains(&role.id) {
+                permissions |= role.permissions;
+            }
+        }
+
+        if permissions.contains(Permissions::ADMINISTRATOR) {
+            return Permissions::all();
+        }
+
+        permissions
+    }
+    /// Returns the permissions of a public key in a channel. The overwrites of the everyone role are applied first,
+    /// then the overwrites of the other roles of the member
+    pub fn channel_permissions(&self, key: &PubKey, channel: ChannelId) -> Permissions {
+        let mut permissions = self.permissions(key);
+
+        if permissions.contains(Permissions::ADMINISTRATOR) {
+            return permissions;
+        }
+        let (member, channel) = match (self.member(key), self.channel(channel)) {
+            (Some(m), Some(c)) => (m, c),
+            _ => return Permissions::empty(),
+        };
+
+        if let Some(o) = channel.overwrites.iter().find(|o| o.role == EVERYONE_ROLE) {
+            permissions.remove(o.deny);
+            permissions.insert(o.allow);
+        }
+
+        let mut allow = Permissions::empty();
+        let mut deny = Permissions::empty();
+        for o in &channel.overwrites {
+            if o.role != EVERYONE_ROLE && member.roles.contains(&o.role) {
+                allow |= o.allow;
+                deny |= o.deny;
+            }
+        }
+        permissions.remove(deny);
+        permissions.insert(allow);
+
+        permissions
+    }
+    /// Removes a member, returning false if the public key was not a member
+    pub fn remove_member(&mut self, key: &PubKey) -> bool {
+        let len = self.members.len();
+        self.members.retain(|m| m.key != *key);
+
+        self.members.len() != len
+    }
+}
+
+/// A client creating a guild
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GuildCreate {
+    /// The identity of the client that owns the guild
+    pub owner: PubKey,
+    pub name: String,
+}
+
+/// A member with [`Permissions::INVITE_MEMBERS`] adding a public key to a guild
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GuildInvite {
+    pub guild: GuildId,
+    /// The identity of the client inviting
+    pub from: PubKey,
+    pub member: PubKey,
+}
+
+/// A member leaving a guild
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GuildLeave {
+    pub guild: GuildId,
+    /// The identity of the client leaving
+    pub member: PubKey,
+}
+
+/// A member with [`Permissions::KICK_MEMBERS`] removing a member from a guild.
+/// The kicking member must have every permission of the kicked member
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GuildKick {
+    pub guild: GuildId,
+    /// The identity of the client kicking
+    pub from: PubKey,
+    pub member: PubKey,
+}
+
+/// A member with [`Permissions::MANAGE_CHANNELS`] creating or replacing a channel.
+/// The overwrites can only give or take permissions the member has in the guild
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GuildSetChannel {
+    pub guild: GuildId,
+    pub from: PubKey,
+    /// The channel to replace, or [`None`] to create a channel
+    pub id: Option<ChannelId>,
+    pub name: String,
+    pub overwrites: Vec<PermissionOverwrite>,
+}
+
+/// A member with [`Permissions::MANAGE_CHANNELS`] deleting a channel
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GuildDeleteChannel {
+    pub guild: GuildId,
+    pub from: PubKey,
+    pub channel: ChannelId,
+}
+
+/// A member with [`Permissions::MANAGE_ROLES`] creating or replacing a role.
+/// Only permissions the member has can be given, and only roles with permissions the member has can be replaced
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GuildSetRole {
+    pub guild: GuildId,
+    pub from: PubKey,
+    /// The role to replace, or [`None`] to create a role
+    pub id: Option<RoleId>,
+    pub name: String,
+    pub permissions: Permissions,
+}
+
+/// A member with [`Permissions::MANAGE_ROLES`] deleting a role. The everyone role cannot be deleted
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GuildDeleteRole {
+    pub guild: GuildId,
+    pub from: PubKey,
+    pub role: RoleId,
+}
+
+/// A member with [`Permissions::MANAGE_ROLES`] replacing the roles of a member
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GuildMemberRoles {
+    pub guild: GuildId,
+    pub from: PubKey,
+    pub member: PubKey,
+    pub roles: HashSet<RoleId>,
+}
+
+/// A message sent to a channel of a guild. Delivered to every member that can view the channel
+#[serde_as]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GuildMessage {
+    pub guild: GuildId,
+    pub channel: ChannelId,
+    /// Public key of the sender
+    pub from: PubKey,
+    /// The contents, encrypted by the members. The node does not read them
+    #[serde_as(as = "Bytes")]
+    pub content: Vec<u8>,
+}
@@ -2,10 +2,15 @@ use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
 
-use super::crypto::PubKey;
+use super::{
+    crypto::PubKey,
+    envelope::SealedEnvelope,
+    ratchet::{RatchetMessage, SessionInit},
+    user::{Delegation, KeyRotation},
+};
 
 /// Represents a header for a message
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum MessageHeader {
     /// A client identifies the stream type
     StreamIdentify = 0,
@@ -13,6 +18,127 @@ pub enum MessageHeader {
     Identify = 1,
     /// An error
     Error = 2,
+    /// The node sends a challenge that a client must sign to identify
+    IdentifyChallenge = 3,
+    /// The node accepted the public keys of an identify
+    IdentifyAccepted = 4,
+    /// A client requests to communicate with a public key
+    CommunicationRequest = 5,
+    /// The node opened a conversation requested by the client
+    CommunicationAccepted = 6,
+    /// A message relayed by the node between two public keys
+    DirectMessage = 7,
+    /// A message the node stored while the recipient was not connected
+    QueuedMessage = 8,
+    /// The client acknowledges the delivery of queued messages
+    QueuedAck = 9,
+    /// A client publishes the prekeys of a public key, replacing the previous ones
+    PublishPrekeys = 10,
+    /// A client requests the prekeys of a public key to start a session
+    PrekeyRequest = 11,
+    /// The node sends the prekeys requested by a client
+    PrekeyBundle = 12,
+    /// A client creates a group
+    GroupCreate = 13,
+    /// An admin adds a public key to a group
+    GroupInvite = 14,
+    /// A member leaves a group
+    GroupLeave = 15,
+    /// An admin removes a member from a group
+    GroupKick = 16,
+    /// The owner changes the role of a member
+    GroupSetRole = 17,
+    /// The node sends the current state of a group after it changed
+    GroupUpdate = 18,
+    /// A message relayed by the node to every member of a group
+    GroupMessage = 19,
+    /// A client creates a guild
+    GuildCreate = 20,
+    /// A member adds a public key to a guild
+    GuildInvite = 21,
+    /// A member leaves a guild
+    GuildLeave = 22,
+    /// A member removes another member from a guild
+    GuildKick = 23,
+    /// A member creates or changes a channel of a guild
+    GuildSetChannel = 24,
+    /// A member deletes a channel of a guild
+    GuildDeleteChannel = 25,
+    /// A member creates or changes a role of a guild
+    GuildSetRole = 26,
+    /// A member deletes a role of a guild
+    GuildDeleteRole = 27,
+    /// A member changes the roles of another member
+    GuildMemberRoles = 28,
+    /// The node sends the current state of a guild after it changed
+    GuildUpdate = 29,
+    /// A message relayed by the node to every member that can view a channel
+    GuildMessage = 30,
+    /// The node sends a challenge that must be hashed with the admin password
+    AdminChallenge = 31,
+    /// The client answers the admin challenge
+    AdminAuth = 32,
+    /// The node accepted the admin password
+    AdminAccepted = 33,
+    /// An authenticated client sends a command to the node
+    AdminCommand = 34,
+    /// The node sends the result of a command
+    AdminResponse = 35,
+    /// A node answers the identify challenge of another node with its node key
+    NodeIdentify = 36,
+    /// The node accepted the node key of another node and proves its own
+    NodeAccepted = 37,
+    /// A signed message between two identified nodes
+    PeerFrame = 38,
+    /// A node hosts new public keys
+    RouteAnnounce = 39,
+    /// A node no longer hosts public keys
+    RouteWithdraw = 40,
+    /// A node forwards a message to a public key hosted by another node
+    NodeForward = 41,
+    /// A node shares the nodes it knows
+    PeerList = 42,
+    /// A node looks for the node hosting a public key, or for the nodes closest to a position of the DHT
+    DhtFind = 43,
+    /// A node asks another node to store the node hosting a public key
+    DhtStore = 44,
+    /// A node answers a DHT request
+    DhtResponse = 45,
+    /// A client publishes the node hosting one of its public keys
+    PublishHome = 46,
+    /// A client looks for the node hosting a public key
+    HomeLookup = 47,
+    /// The node sends the node hosting a public key
+    HomeEntry = 48,
+    /// A user allows a device key to act as the user
+    Delegate = 49,
+    /// A user cuts off a device key
+    Revoke = 50,
+    /// A client requests the sub accounts of a user
+    SubAccountsRequest = 51,
+    /// The node sends the sub accounts of a user
+    SubAccountList = 52,
+    /// A client replaces a public key by a new key
+    RotateKey = 53,
+    /// The node sends the rotation of a public key, to its contacts or to a client requesting it
+    KeyRotated = 54,
+    /// A client requests the rotation of a public key
+    RotationRequest = 55,
+}
+impl MessageHeader {
+    /// Returns true if messages with the header can be forwarded to the node hosting the recipient.
+    /// Other messages are only sent by the node of the recipient, after its own checks
+    pub fn is_forwardable(&self) -> bool {
+        matches!(
+            self,
+            MessageHeader::CommunicationRequest
+                | MessageHeader::CommunicationAccepted
+                | MessageHeader::DirectMessage
+                | MessageHeader::GroupMessage
+                | MessageHeader::GuildMessage
+                | MessageHeader::KeyRotated
+        )
+    }
 }
 /// Represents a generic message received by/sent to a client
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -25,8 +151,18 @@ pub struct Message {
     #[serde(rename = "obj")]
     pub object: serde_cbor::Value,
 }
+
+impl Message {
+    /// Creates a message from a header and a serializable object
+    pub fn new<T: Serialize>(header: MessageHeader, object: &T) -> Result<Self, serde_cbor::Error> {
+        Ok(Self {
+            header,
+            object: serde_cbor::value::to_value(object)?,
+        })
+    }
+}
 /// A client identifying themself with a stream ID
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum StreamIdentify {
     /// A normal stream with normal events
     Normal = 0,
@@ -53,4 +189,152 @@ pub struct Identity {
     /// Digital signature of the timestamp
     #[serde_as(as = "[_; 64]")]
     pub signature: [u8; 64],
+    /// A delegation of a master key to the public key. The client is also identified as the master key
+    #[serde(default)]
+    pub delegation: Option<Delegation>,
+}
+
+/// A challenge sent by the node. The client signs it along with a timestamp to identify
+#[serde_as]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct IdentifyChallenge {
+    /// Random bytes that have to be present in the [`Identifier`]
+    #[serde_as(as = "[_; 32]")]
+    pub sig_msg: [u8; 32],
+    /// The key of the node, used by other nodes connecting to it
+    pub node_key: PubKey,
+    /// The rotation from the previous key of the node, if it was rotated.
+    /// Clients and nodes that pinned the previous key accept the new key if the previous key signed it
+    #[serde(default)]
+    pub node_rotation: Option<KeyRotation>,
+}
+
+/// The node's response to a successful identify
+#[derive(Clone, Serialize, Deserialize)]
+pub struct IdentifyAccepted {
+    /// Every public key the client is now identified as
+    pub keys: Vec<PubKey>,
+}
+
+/// A client requesting to communicate with a public key. The node sends the same object to the requested public key
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CommunicationRequest {
+    /// The identity of the client opening the conversation
+    pub from: PubKey,
+    /// The public key to communicate with
+    pub public_key: PubKey,
+}
+
+/// The node's response to a [`CommunicationRequest`]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CommunicationAccepted {
+    /// The public key the conversation was opened with
+    pub public_key: PubKey,
+    /// If the public key is currently connected to the node
+    pub online: bool,
+}
+
+/// A message sent from one public key to another, with the node as a middleman
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DirectMessage {
+    /// Public key of the sender
+    pub from: PubKey,
+    /// Public key of the recipient
+    pub to: PubKey,
+    /// The contents of the message, only readable by the sender and the recipient
+    pub content: DirectContent,
+}
+
+/// The encrypted contents of a [`DirectMessage`]
+#[derive(Clone, Serialize, Deserialize)]
+pub enum DirectContent {
+    /// Encrypted with the static keys of the sender and the recipient
+    Sealed(SealedEnvelope),
+    /// The first message of a ratchet session, started from the prekeys of the recipient
+    SessionInit(Box<SessionInit>),
+    /// A message of an established ratchet session
+    Ratchet(RatchetMessage),
+}
+
+/// A client requesting the prekeys of a public key
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PrekeyRequest {
+    /// The public key to start a session with
+    pub public_key: PubKey,
+}
+
+/// A [`DirectMessage`] stored by the node
+#[derive(Clone, Serialize, Deserialize)]
+pub struct StoredMessage {
+    /// Unique ID of the message. IDs increase with time
+    pub id: u64,
+    /// Time the node received the message
+    pub timestamp: DateTime<Utc>,
+    /// The message
+    pub message: DirectMessage,
+}
+
+/// A message the node stored while the recipient was not connected.
+/// The node keeps sending it every time the recipient identifies, until the recipient acknowledges it.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct QueuedMessage {
+    /// Unique ID of the queued message
+    pub id: u64,
+    /// Public key of the recipient
+    pub recipient: PubKey,
+    /// Time the node queued the message
+    pub timestamp: DateTime<Utc>,
+    /// The message that could not be delivered
+    pub message: Message,
+}
+
+/// Acknowledges the delivery of queued messages, letting the node delete them
+#[derive(Clone, Serialize, Deserialize)]
+pub struct QueuedAck {
+    /// Public key of the recipient of the messages
+    pub recipient: PubKey,
+    /// IDs of the delivered messages
+    pub ids: Vec<u64>,
+}
+
+/// Represents an error sent to a client
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorMsg {
+    /// The error code
+    pub code: ErrorCode,
+    /// Human readable description of the error
+    pub description: String,
+}
+
+impl ErrorMsg {
+    pub fn new(code: ErrorCode, description: impl Into<String>) -> Self {
+        Self {
+            code,
+            description: description.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// The object of the message could not be deserialized
+    Malformed = 0,
+    /// The signed challenge is not the one issued by the node
+    InvalidChallenge = 1,
+    /// The timestamp is too far from the node's time
+    StaleTimestamp = 2,
+    /// A signature could not be verified
+    InvalidSignature = 3,
+    /// The client is not identified with the required public key
+    Unauthorized = 4,
+    /// The recipient public key is not connected to the node
+    Unreachable = 5,
+    /// The requested data does not exist
+    NotFound = 6,
+    /// The data conflicts with existing data
+    Conflict = 7,
+    /// The node does not support the request
+    Unsupported = 8,
+    /// The storage of the node failed
+    StorageFailure = 9,
 }
@@ -1,6 +1,18 @@
+pub use self::admin::*;
+pub use self::dht::*;
+pub use self::group::*;
+pub use self::guild::*;
 pub use self::message::*;
+pub use self::peer::*;
 pub use self::user::*;
 
+mod admin;
 pub mod crypto;
+mod dht;
+pub mod envelope;
+mod group;
+mod guild;
+pub mod ratchet;
 mod message;
+mod peer;
 mod user;
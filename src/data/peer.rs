@@ -0,0 +1,93 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+
+use super::{crypto::PubKey, HomeRecord, Message};
+
+/// A node connecting to another node answers its [`IdentifyChallenge`](super::IdentifyChallenge) with its own key
+#[serde_as]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct NodeIdentify {
+    /// Public key of the connecting node
+    pub key: PubKey,
+    pub timestamp: DateTime<Utc>,
+    /// Signature of the challenge, from the connecting node to the challenging node
+    #[serde_as(as = "[_; 64]")]
+    pub signature: [u8; 64],
+    /// A challenge the challenging node has to sign in return
+    pub challenge: [u8; 32],
+    /// The link only carries requests of the connecting node, and closes once they are answered.
+    /// The challenging node does not route messages through it
+    #[serde(default)]
+    pub transient: bool,
+}
+
+/// A node accepting a [`NodeIdentify`] proves its own key
+#[serde_as]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct NodeAccepted {
+    /// Public key of the accepting node
+    pub key: PubKey,
+    pub timestamp: DateTime<Utc>,
+    /// Signature of the challenge of the [`NodeIdentify`], from the accepting node to the connecting node
+    #[serde_as(as = "[_; 64]")]
+    pub signature: [u8; 64],
+}
+
+/// A message between two identified nodes. Every frame of a node link is signed,
+/// so a relay between the nodes cannot add or change messages
+#[serde_as]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PeerFrame {
+    /// Increases with every frame sent on the link, so frames cannot be replayed
+    pub sequence: u64,
+    pub timestamp: DateTime<Utc>,
+    pub message: Message,
+    #[serde_as(as = "[_; 64]")]
+    pub signature: [u8; 64],
+}
+
+impl PeerFrame {
+    /// The bytes covered by the signature of the frame.
+    /// `link` is derived from both handshake challenges, so frames of a link are not accepted by another link
+    pub fn signed_payload(link: &[u8; 32], sequence: u64, message: &Message) -> Result<Vec<u8>, serde_cbor::Error> {
+        serde_cbor::to_vec(&(link, sequence, message))
+    }
+}
+
+/// Public keys that are now connected to the sending node.
+/// Every public key proves it with a record it signed naming the sending node, so a node cannot take the messages of
+/// public keys it does not host
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RouteAnnounce {
+    pub records: Vec<HomeRecord>,
+}
+
+/// Public keys that are no longer connected to the sending node
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RouteWithdraw {
+    pub keys: Vec<PubKey>,
+}
+
+/// A message for a public key connected to the receiving node. The receiving node does not forward it again
+#[derive(Clone, Serialize, Deserialize)]
+pub struct NodeForward {
+    pub recipient: PubKey,
+    pub message: Message,
+}
+
+/// A node accepting links from other nodes
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PeerInfo {
+    pub key: PubKey,
+    /// The address other nodes link to, as `host:port`
+    pub address: String,
+    /// The last time the node was linked with, by the node sending the information or by a node it learned it from
+    pub last_seen: DateTime<Utc>,
+}
+
+/// The nodes known by the sending node, including itself if it accepts links
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PeerList {
+    pub peers: Vec<PeerInfo>,
+}
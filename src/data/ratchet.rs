@@ -0,0 +1,499 @@
+use aes_gcm::{
+    aead::{Aead, Payload},
+    Aes256Gcm, KeyInit,
+};
+use chrono::{DateTime, Utc};
+use generic_array::GenericArray;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_with::{serde_as, Bytes};
+
+use crate::error::RatchetError;
+
+use super::crypto::{PrivKey, PubKey, SignedMsg};
+
+/// Context of the key agreement starting a session
+const AGREEMENT_KDF_CONTEXT: &str = "cacophoney 2022-10 prekey agreement v1";
+/// Context of the root chain of a session
+const ROOT_KDF_CONTEXT: &str = "cacophoney 2022-10 ratchet root chain v1";
+/// Context of the key and nonce of a single message
+const MESSAGE_KDF_CONTEXT: &str = "cacophoney 2022-10 ratchet message key v1";
+
+/// Maximum amount of messages a single message can skip, so a peer cannot make a session derive keys forever
+pub const MAX_SKIP: u32 = 1000;
+
+/// Prekeys published by a public key through the node, so sessions can be started while the public key is not connected
+#[serde_as]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PrekeyBundle {
+    /// The public key owning the prekeys
+    pub identity: PubKey,
+    /// Medium-term prekey, signed by the identity
+    pub signed_prekey: PubKey,
+    /// Time the signed prekey was signed
+    pub timestamp: DateTime<Utc>,
+    /// Signature of the signed prekey and the timestamp
+    #[serde_as(as = "[_; 64]")]
+    pub signature: [u8; 64],
+    /// Single-use prekeys. The node hands out at most one of them to every session initiator
+    pub one_time_prekeys: Vec<PubKey>,
+}
+
+impl PrekeyBundle {
+    /// Returns true if the signed prekey was signed by the identity
+    pub fn verify(&self) -> bool {
+        let mut identity = self.identity;
+        let msg = SignedMsg::from_prekey(&self.signed_prekey, &self.timestamp);
+
+        matches!(msg.verify(&mut identity, &self.signature), Ok(true))
+    }
+}
+
+/// The private keys of a published [`PrekeyBundle`]. Kept by the owner of the identity
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PrekeySecrets {
+    pub signed_prekey: PrivKey,
+    pub one_time_prekeys: Vec<PrivKey>,
+}
+
+impl PrekeySecrets {
+    /// Generates a signed prekey and `count` one-time prekeys, returning them along with the bundle to publish
+    pub fn generate(identity: &PrivKey, count: usize) -> (Self, PrekeyBundle) {
+        let secrets = Self {
+            signed_prekey: PrivKey::random(),
+            one_time_prekeys: (0..count).map(|_| PrivKey::random()).collect(),
+        };
+
+        let timestamp = Utc::now();
+        let signed_prekey = secrets.signed_prekey.public_key();
+        let signature = SignedMsg::from_prekey(&signed_prekey, &timestamp).sign(identity);
+
+        let bundle = PrekeyBundle {
+            identity: identity.public_key(),
+            signed_prekey,
+            timestamp,
+            signature,
+            one_time_prekeys: secrets.one_time_prekeys.iter().map(|k| k.public_key()).collect(),
+        };
+
+        (secrets, bundle)
+    }
+    /// Removes a one-time prekey, so it can never be used again
+    fn take_one_time(&mut self, key: &PubKey) -> Option<PrivKey> {
+        let pos = self.one_time_prekeys.iter().position(|k| k.public_key() == *key)?;
+
+        Some(self.one_time_prekeys.swap_remove(pos))
+    }
+}
+
+/// The header of a [`RatchetMessage`]. Authenticated, but not encrypted
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RatchetHeader {
+    /// The current ratchet public key of the sender
+    pub ratchet_key: PubKey,
+    /// Amount of messages sent with the previous ratchet key
+    pub previous: u32,
+    /// Number of the message with the current ratchet key
+    pub number: u32,
+}
+
+/// A message encrypted by a [`Session`]
+#[serde_as]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RatchetMessage {
+    pub header: RatchetHeader,
+    /// The CBOR serialized contents, encrypted with AES-256-GCM
+    #[serde_as(as = "Bytes")]
+    pub ciphertext: Vec<u8>,
+}
+
+/// The first message of a session, started from the [`PrekeyBundle`] of the recipient
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SessionInit {
+    /// Public key of the initiator
+    pub identity: PubKey,
+    /// Ephemeral public key of the initiator
+    pub ephemeral: PubKey,
+    /// The signed prekey of the recipient used by the initiator
+    pub signed_prekey: PubKey,
+    /// The one-time prekey of the recipient used by the initiator, if the node had one left
+    pub one_time_prekey: Option<PubKey>,
+    pub message: RatchetMessage,
+}
+
+/// A double ratchet session between two public keys.
+///
+/// Every message is encrypted with its own key. The keys of the session change every time the direction
+/// of the conversation changes, so leaking the keys of the session or the identities does not
+/// reveal previous messages.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Session {
+    /// Public key of the initiator of the session
+    initiator: PubKey,
+    /// Public key of the recipient of the session
+    responder: PubKey,
+    root_key: [u8; 32],
+    /// Our current ratchet key
+    ratchet_key: PrivKey,
+    /// The current ratchet public key of the other side
+    remote_ratchet_key: Option<PubKey>,
+    sending_chain: Option<[u8; 32]>,
+    receiving_chain: Option<[u8; 32]>,
+    sent: u32,
+    received: u32,
+    previous_sent: u32,
+    /// Keys of messages that were skipped, and may still arrive
+    skipped: Vec<(PubKey, u32, [u8; 32])>,
+}
+
+impl Session {
+    /// Starts a session with the owner of a prekey bundle, encrypting the first message of the session
+    pub fn initiate<T: Serialize>(
+        identity: &PrivKey,
+        bundle: &PrekeyBundle,
+        contents: &T,
+    ) -> Result<(Self, SessionInit), RatchetError> {
+        if !bundle.verify() {
+            return Err(RatchetError::InvalidSignature);
+        }
+
+        let ephemeral = PrivKey::random();
+        let one_time_prekey = bundle.one_time_prekeys.first().copied();
+
+        let mut agreement = Vec::with_capacity(33 * 4);
+        agreement.extend(identity.shared_point(&bundle.signed_prekey)?);
+        agreement.extend(ephemeral.shared_point(&bundle.identity)?);
+        agreement.extend(ephemeral.shared_point(&bundle.signed_prekey)?);
+        if let Some(key) = &one_time_prekey {
+            agreement.extend(ephemeral.shared_point(key)?);
+        }
+
+        let ratchet_key = PrivKey::random();
+        let (root_key, sending_chain) = kdf_root(
+            &blake3::derive_key(AGREEMENT_KDF_CONTEXT, &agreement),
+            &ratchet_key.shared_point(&bundle.signed_prekey)?,
+        );
+
+        let mut session = Self {
+            initiator: identity.public_key(),
+            responder: bundle.identity,
+            root_key,
+            ratchet_key,
+            remote_ratchet_key: Some(bundle.signed_prekey),
+            sending_chain: Some(sending_chain),
+            receiving_chain: None,
+            sent: 0,
+            received: 0,
+            previous_sent: 0,
+            skipped: Vec::new(),
+        };
+
+        let init = SessionInit {
+            identity: session.initiator,
+            ephemeral: ephemeral.public_key(),
+            signed_prekey: bundle.signed_prekey,
+            one_time_prekey,
+            message: session.encrypt(contents)?,
+        };
+
+        Ok((session, init))
+    }
+    /// Accepts a session started from our prekeys, decrypting the first message of the session.
+    /// The one-time prekey used by the initiator is removed from `secrets`
+    pub fn accept<T: DeserializeOwned>(
+        identity: &PrivKey,
+        secrets: &mut PrekeySecrets,
+        init: &SessionInit,
+    ) -> Result<(Self, T), RatchetError> {
+        if secrets.signed_prekey.public_key() != init.signed_prekey {
+            return Err(RatchetError::UnknownPrekey);
+        }
+
+        let one_time_prekey = match &init.one_time_prekey {
+            Some(key) => match secrets.one_time_prekeys.iter().find(|k| k.public_key() == *key) {
+                Some(v) => Some(*v),
+                None => return Err(RatchetError::UnknownPrekey),
+            },
+            None => None,
+        };
+
+        let mut agreement = Vec::with_capacity(33 * 4);
+        agreement.extend(secrets.signed_prekey.shared_point(&init.identity)?);
+        agreement.extend(identity.shared_point(&init.ephemeral)?);
+        agreement.extend(secrets.signed_prekey.shared_point(&init.ephemeral)?);
+        if let Some(key) = &one_time_prekey {
+            agreement.extend(key.shared_point(&init.ephemeral)?);
+        }
+
+        let mut session = Self {
+            initiator: init.identity,
+            responder: identity.public_key(),
+            root_key: blake3::derive_key(AGREEMENT_KDF_CONTEXT, &agreement),
+            ratchet_key: secrets.signed_prekey,
+            remote_ratchet_key: None,
+            sending_chain: None,
+            receiving_chain: None,
+            sent: 0,
+            received: 0,
+            previous_sent: 0,
+            skipped: Vec::new(),
+        };
+
+        let contents = session.decrypt(&init.message)?;
+
+        // Only consumed once the session is accepted, so a forged init cannot burn prekeys
+        if let Some(key) = &init.one_time_prekey {
+            secrets.take_one_time(key);
+        }
+
+        Ok((session, contents))
+    }
+    /// Serializes and encrypts the next message of the session
+    pub fn encrypt<T: Serialize>(&mut self, contents: &T) -> Result<RatchetMessage, RatchetError> {
+        // Cannot fail, accepting a session decrypts the first message, which sets the sending chain
+        let (chain, message_key) = kdf_chain(&self.sending_chain.unwrap());
+
+        let header = RatchetHeader {
+            ratchet_key: self.ratchet_key.public_key(),
+            previous: self.previous_sent,
+            number: self.sent,
+        };
+
+        let msg = serde_cbor::to_vec(contents)?;
+        let ciphertext = seal(&message_key, &self.associated_data(&header)?, &msg)?;
+
+        self.sending_chain = Some(chain);
+        self.sent += 1;
+
+        Ok(RatchetMessage { header, ciphertext })
+    }
+    /// Decrypts and deserializes a message of the session.
+    /// The session is left unchanged if the message cannot be decrypted
+    pub fn decrypt<T: DeserializeOwned>(&mut self, msg: &RatchetMessage) -> Result<T, RatchetError> {
+        let mut next = self.clone();
+        let plaintext = next.decrypt_raw(msg)?;
+        let contents = serde_cbor::from_slice(&plaintext)?;
+
+        *self = next;
+        Ok(contents)
+    }
+
+    fn decrypt_raw(&mut self, msg: &RatchetMessage) -> Result<Vec<u8>, RatchetError> {
+        let header = &msg.header;
+        let aad = self.associated_data(header)?;
+
+        // The message was skipped earlier
+        let skipped = self
+            .skipped
+            .iter()
+            .position(|(key, n, _)| *key == header.ratchet_key && *n == header.number);
+        if let Some(pos) = skipped {
+            let (_, _, message_key) = self.skipped.swap_remove(pos);
+            return open(&message_key, &aad, &msg.ciphertext);
+        }
+
+        if self.remote_ratchet_key != Some(header.ratchet_key) {
+            self.skip_until(header.previous)?;
+            self.ratchet(&header.ratchet_key)?;
+        }
+        self.skip_until(header.number)?;
+
+        // Cannot fail, the ratchet step sets the receiving chain
+        let (chain, message_key) = kdf_chain(&self.receiving_chain.unwrap());
+        self.receiving_chain = Some(chain);
+        self.received += 1;
+
+        open(&message_key, &aad, &msg.ciphertext)
+    }
+    /// Stores the keys of every message of the receiving chain up to `until`
+    fn skip_until(&mut self, until: u32) -> Result<(), RatchetError> {
+        if until > self.received + MAX_SKIP {
+            return Err(RatchetError::TooManySkipped(MAX_SKIP));
+        }
+
+        if let (Some(mut chain), Some(remote)) = (self.receiving_chain, self.remote_ratchet_key) {
+            while self.received < until {
+                let (next, message_key) = kdf_chain(&chain);
+                self.skipped.push((remote, self.received, message_key));
+
+                chain = next;
+                self.received += 1;
+            }
+            self.receiving_chain = Some(chain);
+        }
+
+        // Forget the oldest keys, a peer could otherwise make the session grow forever
+        if self.skipped.len() > MAX_SKIP as usize {
+            let excess = self.skipped.len() - MAX_SKIP as usize;
+            self.skipped.drain(..excess);
+        }
+
+        Ok(())
+    }
+    /// Advances the root chain with a new ratchet key of the other side
+    fn ratchet(&mut self, remote: &PubKey) -> Result<(), RatchetError> {
+        self.previous_sent = self.sent;
+        self.sent = 0;
+        self.received = 0;
+        self.remote_ratchet_key = Some(*remote);
+
+        let (root_key, receiving_chain) = kdf_root(&self.root_key, &self.ratchet_key.shared_point(remote)?);
+        self.root_key = root_key;
+        self.receiving_chain = Some(receiving_chain);
+
+        self.ratchet_key = PrivKey::random();
+
+        let (root_key, sending_chain) = kdf_root(&self.root_key, &self.ratchet_key.shared_point(remote)?);
+        self.root_key = root_key;
+        self.sending_chain = Some(sending_chain);
+
+        Ok(())
+    }
+    /// Authenticates both identities of the session along with the header of the message
+    fn associated_data(&self, header: &RatchetHeader) -> Result<Vec<u8>, RatchetError> {
+        let mut aad = Vec::with_capacity(33 * 2 + 64);
+        aad.extend(self.initiator.key);
+        aad.extend(self.responder.key);
+        aad.extend(serde_cbor::to_vec(header)?);
+
+        Ok(aad)
+    }
+}
+
+/// Derives the next root key and a new chain key
+fn kdf_root(root_key: &[u8; 32], shared_point: &[u8; 33]) -> ([u8; 32], [u8; 32]) {
+    let mut hasher = blake3::Hasher::new_derive_key(ROOT_KDF_CONTEXT);
+    hasher.update(root_key);
+    hasher.update(shared_point);
+
+    let mut out = [0u8; 64];
+    hasher.finalize_xof().fill(&mut out);
+
+    // Cannot fail, both halves are 32 bytes
+    (out[..32].try_into().unwrap(), out[32..].try_into().unwrap())
+}
+
+/// Derives the next chain key and the key of a message
+fn kdf_chain(chain_key: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let chain = blake3::keyed_hash(chain_key, &[1]);
+    let message = blake3::keyed_hash(chain_key, &[2]);
+
+    (*chain.as_bytes(), *message.as_bytes())
+}
+
+/// Derives the AES key and nonce of a message key. Every message key is only used once
+fn message_cipher(message_key: &[u8; 32]) -> (Aes256Gcm, [u8; 12]) {
+    let mut out = [0u8; 44];
+    blake3::Hasher::new_derive_key(MESSAGE_KDF_CONTEXT)
+        .update(message_key)
+        .finalize_xof()
+        .fill(&mut out);
+
+    let cipher = Aes256Gcm::new(GenericArray::from_slice(&out[..32]));
+    // Cannot fail, the slice is 12 bytes
+    (cipher, out[32..].try_into().unwrap())
+}
+
+fn seal(message_key: &[u8; 32], aad: &[u8], msg: &[u8]) -> Result<Vec<u8>, RatchetError> {
+    let (cipher, nonce) = message_cipher(message_key);
+
+    Ok(cipher.encrypt(GenericArray::from_slice(&nonce), Payload { msg, aad })?)
+}
+
+fn open(message_key: &[u8; 32], aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, RatchetError> {
+    let (cipher, nonce) = message_cipher(message_key);
+
+    Ok(cipher.decrypt(
+        GenericArray::from_slice(&nonce),
+        Payload {
+            msg: ciphertext,
+            aad,
+        },
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Starts a session from Alice to Bob, returning both sides of it
+    fn sessions() -> (Session, Session) {
+        let alice = PrivKey::random();
+        let bob = PrivKey::random();
+        let (mut secrets, bundle) = PrekeySecrets::generate(&bob, 1);
+
+        let (initiator, init) = Session::initiate(&alice, &bundle, &"hello").unwrap();
+        let (responder, contents) = Session::accept::<String>(&bob, &mut secrets, &init).unwrap();
+        assert_eq!(contents, "hello");
+
+        (initiator, responder)
+    }
+
+    #[test]
+    fn messages_round_trip_in_both_directions() {
+        let (mut alice, mut bob) = sessions();
+
+        for n in 0u32..3 {
+            let msg = bob.encrypt(&n).unwrap();
+            assert_eq!(alice.decrypt::<u32>(&msg).unwrap(), n);
+            let msg = alice.encrypt(&(n + 10)).unwrap();
+            assert_eq!(bob.decrypt::<u32>(&msg).unwrap(), n + 10);
+        }
+    }
+
+    #[test]
+    fn messages_can_arrive_out_of_order() {
+        let (mut alice, mut bob) = sessions();
+
+        let first = alice.encrypt(&1u32).unwrap();
+        let second = alice.encrypt(&2u32).unwrap();
+        assert_eq!(bob.decrypt::<u32>(&second).unwrap(), 2);
+        assert_eq!(bob.decrypt::<u32>(&first).unwrap(), 1);
+    }
+
+    #[test]
+    fn tampered_and_replayed_messages_are_rejected() {
+        let (mut alice, mut bob) = sessions();
+        let msg = alice.encrypt(&1u32).unwrap();
+
+        let mut tampered = msg.clone();
+        tampered.ciphertext[0] ^= 1;
+        assert!(matches!(bob.decrypt::<u32>(&tampered), Err(RatchetError::DecryptError(_))));
+        let mut tampered = msg.clone();
+        tampered.header.previous += 1;
+        assert!(bob.decrypt::<u32>(&tampered).is_err());
+
+        // The failed attempts left the session unchanged
+        assert_eq!(bob.decrypt::<u32>(&msg).unwrap(), 1);
+        assert!(bob.decrypt::<u32>(&msg).is_err());
+    }
+
+    #[test]
+    fn sessions_need_valid_prekeys() {
+        let alice = PrivKey::random();
+        let bob = PrivKey::random();
+        let (mut secrets, mut bundle) = PrekeySecrets::generate(&bob, 1);
+
+        let (_, init) = Session::initiate(&alice, &bundle, &"hello").unwrap();
+        Session::accept::<String>(&bob, &mut secrets, &init).unwrap();
+        // The one-time prekey was consumed by the first session
+        assert!(matches!(
+            Session::accept::<String>(&bob, &mut secrets, &init),
+            Err(RatchetError::UnknownPrekey)
+        ));
+
+        bundle.signed_prekey = PrivKey::random().public_key();
+        assert!(matches!(
+            Session::initiate(&alice, &bundle, &"hello"),
+            Err(RatchetError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn skipping_too_many_messages_is_rejected() {
+        let (mut alice, mut bob) = sessions();
+
+        let mut msg = alice.encrypt(&1u32).unwrap();
+        msg.header.number += MAX_SKIP + 1;
+        assert!(matches!(bob.decrypt::<u32>(&msg), Err(RatchetError::TooManySkipped(MAX_SKIP))));
+    }
+}
@@ -1,6 +1,8 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
 
-use super::crypto::PubKey;
+use super::crypto::{PrivKey, PubKey, SignedMsg};
 
 /// Represents a User who can send messages to other clients or could store other messages
 #[derive(Serialize, Deserialize, Clone)]
@@ -16,6 +18,9 @@ pub struct SubAccount {
     /// My public key
     pub pub_key: PubKey,
     pub publicity: Publicity,
+    /// The delegation of the user to the sub account, if the sub account is a device of the user
+    #[serde(default)]
+    pub delegation: Option<Delegation>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
@@ -23,3 +28,178 @@ pub enum Publicity {
     Public,
     Private,
 }
+
+/// A certificate from the master key of a user allowing a device key to act as the user.
+/// A client identifying with the device key and the delegation is also identified as the master key
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct Delegation {
+    /// Public key of the user
+    pub master: PubKey,
+    /// Public key of the device
+    pub device: PubKey,
+    pub timestamp: DateTime<Utc>,
+    /// The time the delegation stops being accepted. Never expires if [`None`]
+    pub expires: Option<DateTime<Utc>>,
+    #[serde_as(as = "[_; 64]")]
+    pub signature: [u8; 64],
+}
+
+impl Delegation {
+    /// Creates a delegation to `device`, signed by the master key
+    pub fn new(
+        master: &PrivKey,
+        device: PubKey,
+        timestamp: DateTime<Utc>,
+        expires: Option<DateTime<Utc>>,
+    ) -> Self {
+        let public_key = master.public_key();
+
+        Self {
+            master: public_key,
+            device,
+            timestamp,
+            expires,
+            signature: SignedMsg::from_delegation(&public_key, &device, expires.as_ref(), &timestamp).sign(master),
+        }
+    }
+    /// Returns true if the master key signed the delegation
+    pub fn verify(&self) -> bool {
+        let mut key = self.master;
+
+        matches!(
+            SignedMsg::from_delegation(&self.master, &self.device, self.expires.as_ref(), &self.timestamp)
+                .verify(&mut key, &self.signature),
+            Ok(true)
+        )
+    }
+    pub fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|t| t <= Utc::now())
+    }
+}
+
+/// A master key cutting off a device, for example after it was lost.
+/// Every delegation to the device made before the revocation is rejected
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct Revocation {
+    /// Public key of the user
+    pub master: PubKey,
+    /// Public key of the revoked device
+    pub device: PubKey,
+    pub timestamp: DateTime<Utc>,
+    #[serde_as(as = "[_; 64]")]
+    pub signature: [u8; 64],
+}
+
+impl Revocation {
+    /// Creates a revocation of `device`, signed by the master key
+    pub fn new(master: &PrivKey, device: PubKey, timestamp: DateTime<Utc>) -> Self {
+        let public_key = master.public_key();
+
+        Self {
+            master: public_key,
+            device,
+            timestamp,
+            signature: SignedMsg::from_revocation(&public_key, &device, &timestamp).sign(master),
+        }
+    }
+    /// Returns true if the master key signed the revocation
+    pub fn verify(&self) -> bool {
+        let mut key = self.master;
+
+        matches!(
+            SignedMsg::from_revocation(&self.master, &self.device, &self.timestamp).verify(&mut key, &self.signature),
+            Ok(true)
+        )
+    }
+}
+
+/// A public key replaced by a new key, for example after it was compromised.
+/// Signed by the old key, and optionally countersigned by the user the old key is a device of
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct KeyRotation {
+    pub old: PubKey,
+    pub new: PubKey,
+    pub timestamp: DateTime<Utc>,
+    /// Signature of the old key
+    #[serde_as(as = "[_; 64]")]
+    pub signature: [u8; 64],
+    #[serde(default)]
+    pub countersignature: Option<Countersignature>,
+}
+
+/// The approval of a [`KeyRotation`] by the user the old key is a device of.
+/// A countersigned rotation replaces a rotation that is not countersigned, if the node stores the delegation of the user
+/// to the old key
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct Countersignature {
+    /// The delegation of the user to the old key
+    pub delegation: Delegation,
+    /// Signature of the master key of the delegation
+    #[serde_as(as = "[_; 64]")]
+    pub signature: [u8; 64],
+}
+
+impl KeyRotation {
+    /// Creates a rotation from the old key to `new`, signed by the old key
+    pub fn new(old: &PrivKey, new: PubKey, timestamp: DateTime<Utc>) -> Self {
+        let public_key = old.public_key();
+
+        Self {
+            old: public_key,
+            new,
+            timestamp,
+            signature: SignedMsg::from_rotation(&public_key, &new, &timestamp).sign(old),
+            countersignature: None,
+        }
+    }
+    /// Adds the signature of the master key of `delegation`, the delegation of the user to the old key
+    pub fn countersign(&mut self, master: &PrivKey, delegation: Delegation) {
+        self.countersignature = Some(Countersignature {
+            delegation,
+            signature: SignedMsg::from_rotation(&self.old, &self.new, &self.timestamp).sign(master),
+        });
+    }
+    /// Returns true if the old key signed the rotation, and if the countersignature is valid if there is one
+    pub fn verify(&self) -> bool {
+        let msg = SignedMsg::from_rotation(&self.old, &self.new, &self.timestamp);
+        let mut key = self.old;
+
+        if !matches!(msg.verify(&mut key, &self.signature), Ok(true)) {
+            return false;
+        }
+
+        match &self.countersignature {
+            Some(counter) => {
+                let mut master = counter.delegation.master;
+
+                counter.delegation.device == self.old
+                    && counter.delegation.verify()
+                    && matches!(msg.verify(&mut master, &counter.signature), Ok(true))
+            }
+            None => true,
+        }
+    }
+}
+
+/// A client requesting the rotation of a public key
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RotationRequest {
+    pub key: PubKey,
+}
+
+/// A client requesting the sub accounts of a user
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SubAccountsRequest {
+    pub key: PubKey,
+}
+
+/// The sub accounts of a user. Only the public sub accounts are sent to other public keys
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SubAccountList {
+    pub key: PubKey,
+    pub accounts: Vec<SubAccount>,
+}
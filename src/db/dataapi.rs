@@ -1,17 +1,284 @@
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 
-use crate::data::{crypto::PubKey, SubAccount};
+use crate::{
+    config::MailboxOverflow,
+    data::{
+        crypto::PubKey, ratchet::PrekeyBundle, DirectMessage, Group, GroupId, Guild, GuildId,
+        KeyRotation, Message, PeerInfo, Publicity, QueuedMessage, Revocation, StoredMessage, SubAccount,
+        User,
+    },
+    error::DbError,
+};
 
+/// Storage used by a node. Shared by every connection of the node
 #[async_trait]
-pub trait DbApi {
-    // TODO add error type
-    async fn get_subaccounts(&mut self, key: &PubKey) -> Result<Vec<SubAccount>, ()>;
+pub trait DbApi: Send + Sync {
+    // Users
+
+    /// Creates a user. Fails with [`DbError::Conflict`] if a user with the same public key exists
+    async fn create_user(&self, user: &User) -> Result<(), DbError>;
+    /// Returns the user with the public key
+    async fn get_user(&self, key: &PubKey) -> Result<User, DbError>;
+    /// Replaces an existing user. Fails with [`DbError::NotFound`] if the user does not exist
+    async fn update_user(&self, user: &User) -> Result<(), DbError>;
+    /// Deletes the user with the public key
+    async fn delete_user(&self, key: &PubKey) -> Result<(), DbError>;
+
+    // Sub accounts
+
+    async fn get_subaccounts(&self, key: &PubKey) -> Result<Vec<SubAccount>, DbError>;
+    /// Adds a sub account to the public key of a user. Fails with [`DbError::Conflict`] if it was already added
+    async fn add_subaccount(&self, key: &PubKey, sub: &SubAccount) -> Result<(), DbError>;
+    /// Changes the publicity of a sub account of a user
+    async fn set_subaccount_publicity(
+        &self,
+        key: &PubKey,
+        sub: &PubKey,
+        publicity: Publicity,
+    ) -> Result<(), DbError>;
+    /// Removes a sub account from the public key of a user
+    async fn remove_subaccount(&self, key: &PubKey, sub: &PubKey) -> Result<(), DbError>;
+
+    // Messages
+
+    /// Stores a message, returning the ID of the stored message
+    async fn store_message(&self, msg: &DirectMessage) -> Result<u64, DbError>;
+    /// Returns up to `limit` messages of the conversation between two public keys, newest first.
+    /// If `before` is set, only messages with a lower ID are returned
+    async fn get_messages(
+        &self,
+        a: &PubKey,
+        b: &PubKey,
+        before: Option<u64>,
+        limit: usize,
+    ) -> Result<Vec<StoredMessage>, DbError>;
+    /// Deletes a message of the conversation between two public keys
+    async fn delete_message(&self, a: &PubKey, b: &PubKey, id: u64) -> Result<(), DbError>;
+    /// Records that two public keys have a conversation, even if none of their messages are stored
+    async fn add_contact(&self, a: &PubKey, b: &PubKey) -> Result<(), DbError>;
+    /// Returns every public key a public key has a conversation with
+    async fn get_contacts(&self, key: &PubKey) -> Result<Vec<PubKey>, DbError>;
+
+    // Mailbox
+
+    /// Queues a message for a recipient that is not connected, returning the ID of the queued message.
+    /// If `max` messages are already queued for the recipient, the oldest ones are deleted or the message fails with
+    /// [`DbError::MailboxFull`], depending on `overflow`. Every message fails if `max` is 0
+    async fn queue_message(
+        &self,
+        recipient: &PubKey,
+        msg: &Message,
+        max: usize,
+        overflow: MailboxOverflow,
+    ) -> Result<u64, DbError>;
+    /// Returns every message queued for a recipient, oldest first
+    async fn get_queued(&self, recipient: &PubKey) -> Result<Vec<QueuedMessage>, DbError>;
+    /// Deletes a queued message after the recipient acknowledged it
+    async fn ack_queued(&self, recipient: &PubKey, id: u64) -> Result<(), DbError>;
+    /// Deletes every queued message older than `before`, returning the amount of deleted messages
+    async fn expire_queued(&self, before: DateTime<Utc>) -> Result<usize, DbError>;
+
+    // Prekeys
+
+    /// Stores the prekeys of a public key, replacing the previous ones
+    async fn set_prekeys(&self, bundle: &PrekeyBundle) -> Result<(), DbError>;
+    /// Returns the prekeys of a public key with at most one one-time prekey, which is removed from the stored prekeys
+    async fn take_prekeys(&self, key: &PubKey) -> Result<PrekeyBundle, DbError>;
+
+    // Groups
+
+    /// Creates a group. Fails with [`DbError::Conflict`] if a group with the same ID exists
+    async fn create_group(&self, group: &Group) -> Result<(), DbError>;
+    /// Returns the group with the ID
+    async fn get_group(&self, id: &GroupId) -> Result<Group, DbError>;
+    /// Replaces an existing group if it is still `previous`. Fails with [`DbError::NotFound`] if the group does not exist,
+    /// and with [`DbError::Conflict`] if it was changed since `previous` was read
+    async fn update_group(&self, previous: &Group, group: &Group) -> Result<(), DbError>;
+    /// Deletes the group with the ID
+    async fn delete_group(&self, id: &GroupId) -> Result<(), DbError>;
+
+    // Guilds
+
+    /// Creates a guild. Fails with [`DbError::Conflict`] if a guild with the same ID exists
+    async fn create_guild(&self, guild: &Guild) -> Result<(), DbError>;
+    /// Returns the guild with the ID
+    async fn get_guild(&self, id: &GuildId) -> Result<Guild, DbError>;
+    /// Replaces an existing guild if it is still `previous`. Fails with [`DbError::NotFound`] if the guild does not exist,
+    /// and with [`DbError::Conflict`] if it was changed since `previous` was read
+    async fn update_guild(&self, previous: &Guild, guild: &Guild) -> Result<(), DbError>;
+    /// Deletes the guild with the ID
+    async fn delete_guild(&self, id: &GuildId) -> Result<(), DbError>;
+
+    // Bans
+
+    /// Bans a public key from the node
+    async fn add_ban(&self, key: &PubKey) -> Result<(), DbError>;
+    /// Removes the ban of a public key. Fails with [`DbError::NotFound`] if the public key is not banned
+    async fn remove_ban(&self, key: &PubKey) -> Result<(), DbError>;
+    /// Returns every banned public key
+    async fn get_bans(&self) -> Result<Vec<PubKey>, DbError>;
+
+    // Peers
+
+    /// Adds a node known to the node, replacing the previous information with the same key
+    async fn add_peer(&self, peer: &PeerInfo) -> Result<(), DbError>;
+    /// Removes a known node. Fails with [`DbError::NotFound`] if the node is not known
+    async fn remove_peer(&self, key: &PubKey) -> Result<(), DbError>;
+    /// Returns every known node
+    async fn get_peers(&self) -> Result<Vec<PeerInfo>, DbError>;
+
+    // Revocations
+
+    /// Stores the revocation of a device by a user, replacing an older revocation of the same device
+    async fn add_revocation(&self, revocation: &Revocation) -> Result<(), DbError>;
+    /// Returns every stored revocation
+    async fn get_revocations(&self) -> Result<Vec<Revocation>, DbError>;
+
+    // Rotations
+
+    /// Stores the rotation of a public key, replacing the previous rotation of the same public key
+    async fn add_rotation(&self, rotation: &KeyRotation) -> Result<(), DbError>;
+    /// Returns every stored rotation
+    async fn get_rotations(&self) -> Result<Vec<KeyRotation>, DbError>;
+
+    /// Deletes every piece of data stored for a public key: the user, its sub accounts, its conversations, its queued messages,
+    /// its prekeys, its group memberships, its guild memberships and the guilds it owns
+    async fn delete_data(&self, key: &PubKey) -> Result<(), DbError>;
 }
 pub struct EmptyDb {}
 
 #[async_trait]
 impl DbApi for EmptyDb {
-    async fn get_subaccounts(&mut self, _key: &PubKey) -> Result<Vec<SubAccount>, ()> {
-        Err(())
+    async fn create_user(&self, _user: &User) -> Result<(), DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn get_user(&self, _key: &PubKey) -> Result<User, DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn update_user(&self, _user: &User) -> Result<(), DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn delete_user(&self, _key: &PubKey) -> Result<(), DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn get_subaccounts(&self, _key: &PubKey) -> Result<Vec<SubAccount>, DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn add_subaccount(&self, _key: &PubKey, _sub: &SubAccount) -> Result<(), DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn set_subaccount_publicity(
+        &self,
+        _key: &PubKey,
+        _sub: &PubKey,
+        _publicity: Publicity,
+    ) -> Result<(), DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn remove_subaccount(&self, _key: &PubKey, _sub: &PubKey) -> Result<(), DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn store_message(&self, _msg: &DirectMessage) -> Result<u64, DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn get_messages(
+        &self,
+        _a: &PubKey,
+        _b: &PubKey,
+        _before: Option<u64>,
+        _limit: usize,
+    ) -> Result<Vec<StoredMessage>, DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn delete_message(&self, _a: &PubKey, _b: &PubKey, _id: u64) -> Result<(), DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn add_contact(&self, _a: &PubKey, _b: &PubKey) -> Result<(), DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn get_contacts(&self, _key: &PubKey) -> Result<Vec<PubKey>, DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn queue_message(
+        &self,
+        _recipient: &PubKey,
+        _msg: &Message,
+        _max: usize,
+        _overflow: MailboxOverflow,
+    ) -> Result<u64, DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn get_queued(&self, _recipient: &PubKey) -> Result<Vec<QueuedMessage>, DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn ack_queued(&self, _recipient: &PubKey, _id: u64) -> Result<(), DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn expire_queued(&self, _before: DateTime<Utc>) -> Result<usize, DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn set_prekeys(&self, _bundle: &PrekeyBundle) -> Result<(), DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn take_prekeys(&self, _key: &PubKey) -> Result<PrekeyBundle, DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn create_group(&self, _group: &Group) -> Result<(), DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn get_group(&self, _id: &GroupId) -> Result<Group, DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn update_group(&self, _previous: &Group, _group: &Group) -> Result<(), DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn delete_group(&self, _id: &GroupId) -> Result<(), DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn create_guild(&self, _guild: &Guild) -> Result<(), DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn get_guild(&self, _id: &GuildId) -> Result<Guild, DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn update_guild(&self, _previous: &Guild, _guild: &Guild) -> Result<(), DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn delete_guild(&self, _id: &GuildId) -> Result<(), DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn add_ban(&self, _key: &PubKey) -> Result<(), DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn remove_ban(&self, _key: &PubKey) -> Result<(), DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn get_bans(&self) -> Result<Vec<PubKey>, DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn add_peer(&self, _peer: &PeerInfo) -> Result<(), DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn remove_peer(&self, _key: &PubKey) -> Result<(), DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn get_peers(&self) -> Result<Vec<PeerInfo>, DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn add_revocation(&self, _revocation: &Revocation) -> Result<(), DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn get_revocations(&self) -> Result<Vec<Revocation>, DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn add_rotation(&self, _rotation: &KeyRotation) -> Result<(), DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn get_rotations(&self) -> Result<Vec<KeyRotation>, DbError> {
+        Err(DbError::Disabled)
+    }
+    async fn delete_data(&self, _key: &PubKey) -> Result<(), DbError> {
+        Err(DbError::Disabled)
     }
 }
@@ -1,3 +1,5 @@
 pub use self::dataapi::*;
+pub use self::storage::*;
 
 mod dataapi;
+mod storage;
@@ -0,0 +1,857 @@
+use async_trait::async_trait;
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Serialize};
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionError},
+    Transactional,
+};
+
+use crate::{
+    config::MailboxOverflow,
+    data::{
+        crypto::PubKey, ratchet::PrekeyBundle, DirectMessage, Group, GroupId, Guild, GuildId,
+        KeyRotation, Message, PeerInfo, Publicity, QueuedMessage, Revocation, StoredMessage, SubAccount,
+        User,
+    },
+    error::DbError,
+};
+
+use super::DbApi;
+
+/// Key of the schema version in the `meta` tree
+const SCHEMA_VERSION_KEY: &[u8] = b"schema_version";
+
+/// Migrates the database from the previous schema version
+type Migration = fn(&sled::Db) -> Result<(), DbError>;
+
+/// Every migration of the schema, in order. The schema version is the number of applied migrations
+const MIGRATIONS: &[Migration] = &[migrate_v1, migrate_v2, migrate_v3, migrate_v4, migrate_v5, migrate_v6, migrate_v7, migrate_v8, migrate_v9, migrate_v10, migrate_v11];
+
+/// The first version of the schema.
+///
+/// * `users`: public key -> [`User`]
+/// * `subaccounts`: user public key + sub account public key -> [`SubAccount`]
+/// * `messages`: conversation ID + big endian message ID -> [`StoredMessage`]
+fn migrate_v1(db: &sled::Db) -> Result<(), DbError> {
+    db.open_tree("users")?;
+    db.open_tree("subaccounts")?;
+    db.open_tree("messages")?;
+
+    Ok(())
+}
+
+/// Adds an index of the conversations of every public key, built from the stored messages.
+///
+/// * `conversations`: public key + conversation ID -> public key of the other participant
+fn migrate_v2(db: &sled::Db) -> Result<(), DbError> {
+    let messages = db.open_tree("messages")?;
+    let conversations = db.open_tree("conversations")?;
+
+    for entry in messages.iter() {
+        let stored = decode::<StoredMessage>(&entry?.1)?;
+        index_conversation(&conversations, &stored.message.from, &stored.message.to)?;
+    }
+
+    Ok(())
+}
+
+/// Adds the mailbox of messages queued for recipients that are not connected.
+///
+/// * `mailbox`: recipient public key + big endian queued message ID -> [`QueuedMessage`]
+fn migrate_v3(db: &sled::Db) -> Result<(), DbError> {
+    db.open_tree("mailbox")?;
+
+    Ok(())
+}
+
+/// Adds the prekeys published by public keys.
+///
+/// * `prekeys`: public key -> [`PrekeyBundle`]
+fn migrate_v4(db: &sled::Db) -> Result<(), DbError> {
+    db.open_tree("prekeys")?;
+
+    Ok(())
+}
+
+/// Adds group chats.
+///
+/// * `groups`: group ID -> [`Group`]
+fn migrate_v5(db: &sled::Db) -> Result<(), DbError> {
+    db.open_tree("groups")?;
+
+    Ok(())
+}
+
+/// Adds guilds.
+///
+/// * `guilds`: guild ID -> [`Guild`]
+fn migrate_v6(db: &sled::Db) -> Result<(), DbError> {
+    db.open_tree("guilds")?;
+
+    Ok(())
+}
+
+/// Adds the public keys banned by administrators.
+///
+/// * `bans`: public key -> time of the ban
+fn migrate_v7(db: &sled::Db) -> Result<(), DbError> {
+    db.open_tree("bans")?;
+
+    Ok(())
+}
+
+/// Adds the nodes known to the node.
+///
+/// * `peers`: node key -> [`PeerInfo`]
+fn migrate_v8(db: &sled::Db) -> Result<(), DbError> {
+    db.open_tree("peers")?;
+
+    Ok(())
+}
+
+/// Adds the devices revoked by users.
+///
+/// * `revocations`: user public key + device public key -> [`Revocation`]
+fn migrate_v9(db: &sled::Db) -> Result<(), DbError> {
+    db.open_tree("revocations")?;
+
+    Ok(())
+}
+
+/// Adds the public keys replaced by new keys.
+///
+/// * `rotations`: old public key -> [`KeyRotation`]
+fn migrate_v10(db: &sled::Db) -> Result<(), DbError> {
+    db.open_tree("rotations")?;
+
+    Ok(())
+}
+
+/// Counts the messages queued for every recipient, so a full mailbox is found without reading it.
+///
+/// * `mailbox_counts`: recipient public key -> big endian amount of queued messages
+fn migrate_v11(db: &sled::Db) -> Result<(), DbError> {
+    let mailbox = db.open_tree("mailbox")?;
+    let counts = db.open_tree("mailbox_counts")?;
+
+    for entry in mailbox.iter() {
+        let (key, _) = entry?;
+        // Keys end with the 8 bytes of the message ID
+        let recipient = &key[..key.len() - 8];
+        let count = read_count(counts.get(recipient)?.as_deref());
+
+        counts.insert(recipient, &(count + 1).to_be_bytes())?;
+    }
+
+    Ok(())
+}
+
+/// Embedded on-disk storage of a node, used when the "storage" feature is enabled
+pub struct StorageDb {
+    db: sled::Db,
+    users: sled::Tree,
+    subaccounts: sled::Tree,
+    messages: sled::Tree,
+    conversations: sled::Tree,
+    mailbox: sled::Tree,
+    mailbox_counts: sled::Tree,
+    prekeys: sled::Tree,
+    groups: sled::Tree,
+    guilds: sled::Tree,
+    bans: sled::Tree,
+    peers: sled::Tree,
+    revocations: sled::Tree,
+    rotations: sled::Tree,
+}
+
+impl StorageDb {
+    /// Opens or creates the database at a path, migrating it to the latest schema
+    pub fn open(path: &str) -> Result<Self, DbError> {
+        Self::from_db(sled::open(path)?)
+    }
+    /// Creates a database that only lives in memory. Useful for tests
+    pub fn temporary() -> Result<Self, DbError> {
+        Self::from_db(sled::Config::new().temporary(true).open()?)
+    }
+    fn from_db(db: sled::Db) -> Result<Self, DbError> {
+        migrate(&db)?;
+
+        Ok(Self {
+            users: db.open_tree("users")?,
+            subaccounts: db.open_tree("subaccounts")?,
+            messages: db.open_tree("messages")?,
+            conversations: db.open_tree("conversations")?,
+            mailbox: db.open_tree("mailbox")?,
+            mailbox_counts: db.open_tree("mailbox_counts")?,
+            prekeys: db.open_tree("prekeys")?,
+            groups: db.open_tree("groups")?,
+            guilds: db.open_tree("guilds")?,
+            bans: db.open_tree("bans")?,
+            peers: db.open_tree("peers")?,
+            revocations: db.open_tree("revocations")?,
+            rotations: db.open_tree("rotations")?,
+            db,
+        })
+    }
+    /// Deletes a queued message and decrements the count of its recipient. Returns false if it was not queued
+    fn remove_queued(&self, key: &[u8]) -> Result<bool, DbError> {
+        let recipient = &key[..key.len() - 8];
+
+        Ok((&self.mailbox, &self.mailbox_counts).transaction(|(mailbox, counts)| {
+            if mailbox.remove(key)?.is_none() {
+                return Ok(false);
+            }
+            match read_count(counts.get(recipient)?.as_deref()) {
+                0 | 1 => counts.remove(recipient)?,
+                count => counts.insert(recipient, &(count - 1).to_be_bytes())?,
+            };
+
+            Ok(true)
+        })?)
+    }
+}
+
+/// Applies every migration that was not applied yet
+fn migrate(db: &sled::Db) -> Result<(), DbError> {
+    let meta = db.open_tree("meta")?;
+
+    let version = match meta.get(SCHEMA_VERSION_KEY)? {
+        Some(v) if v.len() == 4 => LittleEndian::read_u32(&v) as usize,
+        _ => 0,
+    };
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        tracing::info!("Migrating storage to schema version {}", i + 1);
+        migration(db)?;
+
+        let mut buf = [0u8; 4];
+        LittleEndian::write_u32(&mut buf, (i + 1) as u32);
+        meta.insert(SCHEMA_VERSION_KEY, &buf)?;
+    }
+
+    db.flush()?;
+    Ok(())
+}
+
+/// Returns the ID of the conversation between two public keys. The order of the keys does not matter
+pub fn conversation_id(a: &PubKey, b: &PubKey) -> [u8; 32] {
+    let (first, second) = if a.key <= b.key { (a, b) } else { (b, a) };
+
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&first.key);
+    hasher.update(&second.key);
+
+    *hasher.finalize().as_bytes()
+}
+
+/// Adds the conversation between two public keys to the index of both of them
+fn index_conversation(conversations: &sled::Tree, a: &PubKey, b: &PubKey) -> Result<(), DbError> {
+    let id = conversation_id(a, b);
+
+    conversations.insert(concat_key(&a.key, &id), &b.key[..])?;
+    conversations.insert(concat_key(&b.key, &id), &a.key[..])?;
+
+    Ok(())
+}
+
+fn message_key(conversation: &[u8], id: u64) -> Vec<u8> {
+    let mut id_bytes = [0u8; 8];
+    BigEndian::write_u64(&mut id_bytes, id);
+
+    concat_key(conversation, &id_bytes)
+}
+
+/// Reads an amount of queued messages of the `mailbox_counts` tree. A missing count is zero
+fn read_count(value: Option<&[u8]>) -> u64 {
+    value.filter(|v| v.len() == 8).map(BigEndian::read_u64).unwrap_or(0)
+}
+
+fn concat_key(first: &[u8], second: &[u8]) -> Vec<u8> {
+    let mut key = Vec::with_capacity(first.len() + second.len());
+    key.extend_from_slice(first);
+    key.extend_from_slice(second);
+
+    key
+}
+
+fn encode<T: Serialize>(v: &T) -> Result<Vec<u8>, DbError> {
+    // Serializing the data types of the node cannot fail
+    Ok(serde_cbor::to_vec(v).expect("serializable value"))
+}
+fn decode<T: DeserializeOwned>(v: &[u8]) -> Result<T, DbError> {
+    serde_cbor::from_slice(v).map_err(|e| DbError::Corrupted(e.to_string()))
+}
+
+#[async_trait]
+impl DbApi for StorageDb {
+    async fn create_user(&self, user: &User) -> Result<(), DbError> {
+        let swap = self.users.compare_and_swap(
+            user.pub_key.key,
+            None as Option<&[u8]>,
+            Some(encode(user)?),
+        )?;
+
+        swap.map_err(|_| DbError::Conflict)
+    }
+    async fn get_user(&self, key: &PubKey) -> Result<User, DbError> {
+        match self.users.get(key.key)? {
+            Some(v) => decode(&v),
+            None => Err(DbError::NotFound),
+        }
+    }
+    async fn update_user(&self, user: &User) -> Result<(), DbError> {
+        if !self.users.contains_key(user.pub_key.key)? {
+            return Err(DbError::NotFound);
+        }
+        self.users.insert(user.pub_key.key, encode(user)?)?;
+
+        Ok(())
+    }
+    async fn delete_user(&self, key: &PubKey) -> Result<(), DbError> {
+        match self.users.remove(key.key)? {
+            Some(_) => Ok(()),
+            None => Err(DbError::NotFound),
+        }
+    }
+    async fn get_subaccounts(&self, key: &PubKey) -> Result<Vec<SubAccount>, DbError> {
+        self.subaccounts
+            .scan_prefix(key.key)
+            .map(|v| decode::<SubAccount>(&v?.1))
+            .collect()
+    }
+    async fn add_subaccount(&self, key: &PubKey, sub: &SubAccount) -> Result<(), DbError> {
+        let swap = self.subaccounts.compare_and_swap(
+            concat_key(&key.key, &sub.pub_key.key),
+            None as Option<&[u8]>,
+            Some(encode(sub)?),
+        )?;
+
+        swap.map_err(|_| DbError::Conflict)
+    }
+    async fn set_subaccount_publicity(
+        &self,
+        key: &PubKey,
+        sub: &PubKey,
+        publicity: Publicity,
+    ) -> Result<(), DbError> {
+        let id = concat_key(&key.key, &sub.key);
+
+        let mut account = match self.subaccounts.get(&id)? {
+            Some(v) => decode::<SubAccount>(&v)?,
+            None => return Err(DbError::NotFound),
+        };
+        account.publicity = publicity;
+
+        self.subaccounts.insert(id, encode(&account)?)?;
+        Ok(())
+    }
+    async fn remove_subaccount(&self, key: &PubKey, sub: &PubKey) -> Result<(), DbError> {
+        match self.subaccounts.remove(concat_key(&key.key, &sub.key))? {
+            Some(_) => Ok(()),
+            None => Err(DbError::NotFound),
+        }
+    }
+    async fn store_message(&self, msg: &DirectMessage) -> Result<u64, DbError> {
+        let id = self.db.generate_id()?;
+        let stored = StoredMessage {
+            id,
+            timestamp: Utc::now(),
+            message: msg.clone(),
+        };
+
+        let conversation = conversation_id(&msg.from, &msg.to);
+
+        self.messages
+            .insert(message_key(&conversation, id), encode(&stored)?)?;
+        index_conversation(&self.conversations, &msg.from, &msg.to)?;
+
+        Ok(id)
+    }
+    async fn get_messages(
+        &self,
+        a: &PubKey,
+        b: &PubKey,
+        before: Option<u64>,
+        limit: usize,
+    ) -> Result<Vec<StoredMessage>, DbError> {
+        let conversation = conversation_id(a, b);
+
+        let iter = match before {
+            Some(id) => self
+                .messages
+                .range(message_key(&conversation, 0)..message_key(&conversation, id)),
+            None => self.messages.scan_prefix(conversation),
+        };
+
+        iter.rev()
+            .take(limit)
+            .map(|v| decode::<StoredMessage>(&v?.1))
+            .collect()
+    }
+    async fn delete_message(&self, a: &PubKey, b: &PubKey, id: u64) -> Result<(), DbError> {
+        match self.messages.remove(message_key(&conversation_id(a, b), id))? {
+            Some(_) => Ok(()),
+            None => Err(DbError::NotFound),
+        }
+    }
+    async fn add_contact(&self, a: &PubKey, b: &PubKey) -> Result<(), DbError> {
+        index_conversation(&self.conversations, a, b)
+    }
+    async fn get_contacts(&self, key: &PubKey) -> Result<Vec<PubKey>, DbError> {
+        self.conversations
+            .scan_prefix(key.key)
+            .map(|v| {
+                let other = v?.1;
+                let other = other
+                    .as_ref()
+                    .try_into()
+                    .map_err(|_| DbError::Corrupted("invalid contact public key".to_string()))?;
+
+                Ok(PubKey::new(other))
+            })
+            .collect()
+    }
+    async fn queue_message(
+        &self,
+        recipient: &PubKey,
+        msg: &Message,
+        max: usize,
+        overflow: MailboxOverflow,
+    ) -> Result<u64, DbError> {
+        // Nothing can ever be queued
+        if max == 0 {
+            return Err(DbError::MailboxFull);
+        }
+
+        let id = self.db.generate_id()?;
+        let key = message_key(&recipient.key, id);
+        let value = encode(&QueuedMessage {
+            id,
+            recipient: *recipient,
+            timestamp: Utc::now(),
+            message: msg.clone(),
+        })?;
+
+        loop {
+            // Keys are ordered by ID, the oldest messages come first
+            let queued = self
+                .mailbox
+                .scan_prefix(recipient.key)
+                .keys()
+                .collect::<Result<Vec<_>, _>>()?;
+            let evicted = &queued[..(queued.len() + 1).saturating_sub(max)];
+            if !evicted.is_empty() && overflow == MailboxOverflow::Reject {
+                return Err(DbError::MailboxFull);
+            }
+
+            let result = (&self.mailbox, &self.mailbox_counts).transaction(|(mailbox, counts)| {
+                // The count changes with every message queued or deleted since the mailbox was read
+                if read_count(counts.get(recipient.key)?.as_deref()) != queued.len() as u64 {
+                    return Err(ConflictableTransactionError::Abort(DbError::Conflict));
+                }
+                for old in evicted {
+                    if mailbox.remove(old)?.is_none() {
+                        return Err(ConflictableTransactionError::Abort(DbError::Conflict));
+                    }
+                }
+                mailbox.insert(key.as_slice(), value.as_slice())?;
+                counts.insert(recipient.key.as_slice(), &((queued.len() - evicted.len() + 1) as u64).to_be_bytes())?;
+
+                Ok(())
+            });
+
+            match result {
+                Ok(()) => return Ok(id),
+                // Read the mailbox again
+                Err(TransactionError::Abort(DbError::Conflict)) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+    async fn get_queued(&self, recipient: &PubKey) -> Result<Vec<QueuedMessage>, DbError> {
+        self.mailbox
+            .scan_prefix(recipient.key)
+            .map(|v| decode::<QueuedMessage>(&v?.1))
+            .collect()
+    }
+    async fn ack_queued(&self, recipient: &PubKey, id: u64) -> Result<(), DbError> {
+        match self.remove_queued(&message_key(&recipient.key, id))? {
+            true => Ok(()),
+            false => Err(DbError::NotFound),
+        }
+    }
+    async fn expire_queued(&self, before: DateTime<Utc>) -> Result<usize, DbError> {
+        let mut expired = 0;
+
+        for entry in self.mailbox.iter() {
+            let (key, value) = entry?;
+
+            if decode::<QueuedMessage>(&value)?.timestamp < before && self.remove_queued(&key)? {
+                expired += 1;
+            }
+        }
+
+        Ok(expired)
+    }
+    async fn set_prekeys(&self, bundle: &PrekeyBundle) -> Result<(), DbError> {
+        self.prekeys.insert(bundle.identity.key, encode(bundle)?)?;
+
+        Ok(())
+    }
+    async fn take_prekeys(&self, key: &PubKey) -> Result<PrekeyBundle, DbError> {
+        // Retried until no other connection took a one-time prekey in between, so every one is handed out once
+        loop {
+            let old = match self.prekeys.get(key.key)? {
+                Some(v) => v,
+                None => return Err(DbError::NotFound),
+            };
+
+            let mut bundle = decode::<PrekeyBundle>(&old)?;
+            let one_time = bundle.one_time_prekeys.pop();
+
+            let swap = self
+                .prekeys
+                .compare_and_swap(key.key, Some(&old), Some(encode(&bundle)?))?;
+
+            if swap.is_ok() {
+                bundle.one_time_prekeys = one_time.into_iter().collect();
+                return Ok(bundle);
+            }
+        }
+    }
+    async fn create_group(&self, group: &Group) -> Result<(), DbError> {
+        let swap = self
+            .groups
+            .compare_and_swap(group.id, None as Option<&[u8]>, Some(encode(group)?))?;
+
+        swap.map_err(|_| DbError::Conflict)
+    }
+    async fn get_group(&self, id: &GroupId) -> Result<Group, DbError> {
+        match self.groups.get(id)? {
+            Some(v) => decode(&v),
+            None => Err(DbError::NotFound),
+        }
+    }
+    async fn update_group(&self, previous: &Group, group: &Group) -> Result<(), DbError> {
+        let current = match self.groups.get(group.id)? {
+            Some(v) => v,
+            None => return Err(DbError::NotFound),
+        };
+        // Compared decoded, the order of the encoded sets is not stable
+        if decode::<Group>(&current)? != *previous {
+            return Err(DbError::Conflict);
+        }
+        let swap = self
+            .groups
+            .compare_and_swap(group.id, Some(current), Some(encode(group)?))?;
+
+        swap.map_err(|_| DbError::Conflict)
+    }
+    async fn delete_group(&self, id: &GroupId) -> Result<(), DbError> {
+        match self.groups.remove(id)? {
+            Some(_) => Ok(()),
+            None => Err(DbError::NotFound),
+        }
+    }
+    async fn create_guild(&self, guild: &Guild) -> Result<(), DbError> {
+        let swap = self
+            .guilds
+            .compare_and_swap(guild.id, None as Option<&[u8]>, Some(encode(guild)?))?;
+
+        swap.map_err(|_| DbError::Conflict)
+    }
+    async fn get_guild(&self, id: &GuildId) -> Result<Guild, DbError> {
+        match self.guilds.get(id)? {
+            Some(v) => decode(&v),
+            None => Err(DbError::NotFound),
+        }
+    }
+    async fn update_guild(&self, previous: &Guild, guild: &Guild) -> Result<(), DbError> {
+        let current = match self.guilds.get(guild.id)? {
+            Some(v) => v,
+            None => return Err(DbError::NotFound),
+        };
+        if decode::<Guild>(&current)? != *previous {
+            return Err(DbError::Conflict);
+        }
+        let swap = self
+            .guilds
+            .compare_and_swap(guild.id, Some(current), Some(encode(guild)?))?;
+
+        swap.map_err(|_| DbError::Conflict)
+    }
+    async fn delete_guild(&self, id: &GuildId) -> Result<(), DbError> {
+        match self.guilds.remove(id)? {
+            Some(_) => Ok(()),
+            None => Err(DbError::NotFound),
+        }
+    }
+    async fn add_ban(&self, key: &PubKey) -> Result<(), DbError> {
+        self.bans.insert(key.key, encode(&Utc::now())?)?;
+
+        Ok(())
+    }
+    async fn remove_ban(&self, key: &PubKey) -> Result<(), DbError> {
+        match self.bans.remove(key.key)? {
+            Some(_) => Ok(()),
+            None => Err(DbError::NotFound),
+        }
+    }
+    async fn get_bans(&self) -> Result<Vec<PubKey>, DbError> {
+        self.bans
+            .iter()
+            .map(|v| {
+                let key = v?.0;
+                let key = key
+                    .as_ref()
+                    .try_into()
+                    .map_err(|_| DbError::Corrupted("invalid banned public key".to_string()))?;
+
+                Ok(PubKey::new(key))
+            })
+            .collect()
+    }
+    async fn add_peer(&self, peer: &PeerInfo) -> Result<(), DbError> {
+        self.peers.insert(peer.key.key, encode(peer)?)?;
+
+        Ok(())
+    }
+    async fn remove_peer(&self, key: &PubKey) -> Result<(), DbError> {
+        match self.peers.remove(key.key)? {
+            Some(_) => Ok(()),
+            None => Err(DbError::NotFound),
+        }
+    }
+    async fn get_peers(&self) -> Result<Vec<PeerInfo>, DbError> {
+        self.peers.iter().map(|v| decode(&v?.1)).collect()
+    }
+    async fn add_revocation(&self, revocation: &Revocation) -> Result<(), DbError> {
+        let id = concat_key(&revocation.master.key, &revocation.device.key);
+
+        if let Some(old) = self.revocations.get(&id)? {
+            if decode::<Revocation>(&old)?.timestamp >= revocation.timestamp {
+                return Ok(());
+            }
+        }
+        self.revocations.insert(id, encode(revocation)?)?;
+
+        Ok(())
+    }
+    async fn get_revocations(&self) -> Result<Vec<Revocation>, DbError> {
+        self.revocations.iter().map(|v| decode(&v?.1)).collect()
+    }
+    async fn add_rotation(&self, rotation: &KeyRotation) -> Result<(), DbError> {
+        self.rotations.insert(rotation.old.key, encode(rotation)?)?;
+
+        Ok(())
+    }
+    async fn get_rotations(&self) -> Result<Vec<KeyRotation>, DbError> {
+        self.rotations.iter().map(|v| decode(&v?.1)).collect()
+    }
+    async fn delete_data(&self, key: &PubKey) -> Result<(), DbError> {
+        self.users.remove(key.key)?;
+        self.prekeys.remove(key.key)?;
+
+        for entry in self.groups.iter() {
+            let (id, value) = entry?;
+            let mut group = decode::<Group>(&value)?;
+
+            if group.role(key).is_none() {
+                continue;
+            }
+
+            if group.remove_member(key) {
+                self.groups.insert(id, encode(&group)?)?;
+            } else {
+                self.groups.remove(id)?;
+            }
+        }
+
+        for entry in self.guilds.iter() {
+            let (id, value) = entry?;
+            let mut guild = decode::<Guild>(&value)?;
+
+            if guild.owner == *key {
+                self.guilds.remove(id)?;
+            } else if guild.remove_member(key) {
+                self.guilds.insert(id, encode(&guild)?)?;
+            }
+        }
+
+        for entry in self.mailbox.scan_prefix(key.key) {
+            self.remove_queued(&entry?.0)?;
+        }
+
+        for entry in self.subaccounts.scan_prefix(key.key) {
+            self.subaccounts.remove(entry?.0)?;
+        }
+
+        for entry in self.conversations.scan_prefix(key.key) {
+            let (index, other) = entry?;
+            let conversation = &index[key.key.len()..];
+
+            for msg in self.messages.scan_prefix(conversation) {
+                self.messages.remove(msg?.0)?;
+            }
+
+            self.conversations.remove(&index)?;
+            self.conversations.remove(concat_key(&other, conversation))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::{crypto::PrivKey, envelope::SealedEnvelope, DirectContent, MessageHeader};
+
+    use super::*;
+
+    fn direct_message(from: &PrivKey, to: &PubKey) -> DirectMessage {
+        DirectMessage {
+            from: from.public_key(),
+            to: *to,
+            content: DirectContent::Sealed(SealedEnvelope::seal(from, to, &"hello").unwrap()),
+        }
+    }
+
+    #[tokio::test]
+    async fn migrates_from_the_first_schema() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let sender = PrivKey::random();
+        let recipient = PrivKey::random().public_key();
+
+        // A database written by the first schema, with a message but no index of the conversations
+        migrate_v1(&db).unwrap();
+        let stored = StoredMessage {
+            id: 7,
+            timestamp: Utc::now(),
+            message: direct_message(&sender, &recipient),
+        };
+        let conversation = conversation_id(&sender.public_key(), &recipient);
+        db.open_tree("messages")
+            .unwrap()
+            .insert(message_key(&conversation, stored.id), encode(&stored).unwrap())
+            .unwrap();
+        let mut version = [0u8; 4];
+        LittleEndian::write_u32(&mut version, 1);
+        db.open_tree("meta").unwrap().insert(SCHEMA_VERSION_KEY, &version).unwrap();
+
+        let storage = StorageDb::from_db(db.clone()).unwrap();
+        let version = db.open_tree("meta").unwrap().get(SCHEMA_VERSION_KEY).unwrap().unwrap();
+        assert_eq!(LittleEndian::read_u32(&version) as usize, MIGRATIONS.len());
+
+        assert!(storage.get_contacts(&recipient).await.unwrap() == [sender.public_key()]);
+        let messages = storage.get_messages(&recipient, &sender.public_key(), None, 10).await.unwrap();
+        assert_eq!(messages.iter().map(|m| m.id).collect::<Vec<_>>(), [7]);
+
+        // Opening a migrated database again changes nothing
+        let storage = StorageDb::from_db(db).unwrap();
+        assert_eq!(storage.get_contacts(&recipient).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn queued_messages_are_drained_in_order() {
+        let db = StorageDb::temporary().unwrap();
+        let recipient = PrivKey::random().public_key();
+        let other = PrivKey::random().public_key();
+
+        let mut ids = Vec::new();
+        for n in 0u64..3 {
+            let msg = Message::new(MessageHeader::Error, &n).unwrap();
+            ids.push(db.queue_message(&recipient, &msg, 10, MailboxOverflow::Reject).await.unwrap());
+        }
+        db.queue_message(&other, &Message::new(MessageHeader::Error, &3u64).unwrap(), 10, MailboxOverflow::Reject)
+            .await
+            .unwrap();
+
+        let queued = db.get_queued(&recipient).await.unwrap();
+        assert_eq!(queued.iter().map(|q| q.id).collect::<Vec<_>>(), ids);
+
+        db.ack_queued(&recipient, ids[1]).await.unwrap();
+        assert!(matches!(db.ack_queued(&recipient, ids[1]).await, Err(DbError::NotFound)));
+        let queued = db.get_queued(&recipient).await.unwrap();
+        assert_eq!(queued.iter().map(|q| q.id).collect::<Vec<_>>(), [ids[0], ids[2]]);
+        assert_eq!(db.get_queued(&other).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn subaccounts_are_added_and_removed() {
+        let db = StorageDb::temporary().unwrap();
+        let user = PrivKey::random().public_key();
+        let account = SubAccount {
+            pub_key: PrivKey::random().public_key(),
+            publicity: Publicity::Public,
+            delegation: None,
+        };
+
+        db.add_subaccount(&user, &account).await.unwrap();
+        assert!(matches!(db.add_subaccount(&user, &account).await, Err(DbError::Conflict)));
+        let accounts = db.get_subaccounts(&user).await.unwrap();
+        assert!(accounts.len() == 1 && accounts[0].pub_key == account.pub_key);
+
+        db.remove_subaccount(&user, &account.pub_key).await.unwrap();
+        assert!(matches!(db.remove_subaccount(&user, &account.pub_key).await, Err(DbError::NotFound)));
+        assert!(db.get_subaccounts(&user).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn stale_group_updates_conflict() {
+        let db = StorageDb::temporary().unwrap();
+        let owner = PrivKey::random().public_key();
+        let group = Group::new([1u8; 32], "group".to_string(), owner, []);
+        db.create_group(&group).await.unwrap();
+
+        let mut first = group.clone();
+        first.members.insert(PrivKey::random().public_key());
+        let mut second = group.clone();
+        second.members.insert(PrivKey::random().public_key());
+
+        db.update_group(&group, &first).await.unwrap();
+        assert!(matches!(db.update_group(&group, &second).await, Err(DbError::Conflict)));
+        assert!(db.get_group(&group.id).await.unwrap() == first);
+    }
+
+    #[tokio::test]
+    async fn stale_guild_updates_conflict() {
+        let db = StorageDb::temporary().unwrap();
+        let guild = Guild::new([1u8; 32], "guild".to_string(), PrivKey::random().public_key());
+        db.create_guild(&guild).await.unwrap();
+
+        let mut first = guild.clone();
+        first.name = "first".to_string();
+        let mut second = guild.clone();
+        second.name = "second".to_string();
+
+        db.update_guild(&guild, &first).await.unwrap();
+        assert!(matches!(db.update_guild(&guild, &second).await, Err(DbError::Conflict)));
+        assert!(db.get_guild(&guild.id).await.unwrap() == first);
+    }
+
+    #[tokio::test]
+    async fn full_mailboxes_drop_or_reject() {
+        let db = StorageDb::temporary().unwrap();
+        let recipient = PrivKey::random().public_key();
+        let msg = |n: u64| Message::new(MessageHeader::Error, &n).unwrap();
+
+        for n in 0..3 {
+            db.queue_message(&recipient, &msg(n), 2, MailboxOverflow::DropOldest).await.unwrap();
+        }
+        let queued = db.get_queued(&recipient).await.unwrap();
+        assert_eq!(queued.iter().map(|q| q.message.object.clone()).collect::<Vec<_>>(), [msg(1).object, msg(2).object]);
+
+        let rejected = db.queue_message(&recipient, &msg(3), 2, MailboxOverflow::Reject).await;
+        assert!(matches!(rejected, Err(DbError::MailboxFull)));
+        assert_eq!(db.get_queued(&recipient).await.unwrap().len(), 2);
+
+        // Deleted messages make room
+        db.ack_queued(&recipient, queued[0].id).await.unwrap();
+        db.queue_message(&recipient, &msg(3), 2, MailboxOverflow::Reject).await.unwrap();
+        assert_eq!(db.get_queued(&recipient).await.unwrap().len(), 2);
+
+        let disabled = db.queue_message(&recipient, &msg(4), 0, MailboxOverflow::DropOldest).await;
+        assert!(matches!(disabled, Err(DbError::MailboxFull)));
+    }
+}
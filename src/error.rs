@@ -1,5 +1,7 @@
 use thiserror::Error;
 
+use crate::data::{ErrorCode, ErrorMsg, MessageHeader};
+
 #[derive(Error, Debug)]
 pub enum ConfigError {
     #[error("deserialization of file failed")]
@@ -7,7 +9,15 @@ pub enum ConfigError {
     #[error("password decryption failed")]
     PasswordError(#[from] aes_gcm::Error),
     #[error("cannot read file")]
-    IoError(#[from] tokio::io::Error)
+    IoError(#[from] tokio::io::Error),
+    #[error("key derivation failed")]
+    KdfError(#[from] argon2::Error),
+    #[error("the secrets file has the unsupported version {0}")]
+    UnsupportedVersion(u8),
+    #[error("the file is not a backup of a secrets file")]
+    InvalidBackup,
+    #[error("the secrets file is corrupted: {0}")]
+    Corrupted(String),
 }
 
 #[derive(Error, Debug)]
@@ -25,4 +35,161 @@ impl From<ReadEncryptError> for ConfigError {
             ReadEncryptError::PasswordError(v)    => ConfigError::PasswordError(v)
         }
     }
-}
\ No newline at end of file
+}
+#[derive(Error, Debug)]
+pub enum IdentifyError {
+    #[error("no public keys were provided")]
+    NoIdentities,
+    #[error("the signed message is not the challenge issued by the node")]
+    InvalidChallenge,
+    #[error("the timestamp is outside of the accepted window")]
+    StaleTimestamp,
+    #[error("the signature of a public key is invalid")]
+    InvalidSignature,
+    #[error("a public key is banned from the node")]
+    Banned,
+    #[error("the challenge was already signed by a public key")]
+    Replayed,
+    #[error("the delegation of a public key is invalid or expired")]
+    InvalidDelegation,
+    #[error("the delegation of a public key was revoked")]
+    Revoked,
+    #[error("a public key was replaced by a newer key")]
+    Rotated,
+}
+
+impl From<&IdentifyError> for ErrorMsg {
+    fn from(v : &IdentifyError) -> ErrorMsg {
+        let code = match v {
+            IdentifyError::NoIdentities     => ErrorCode::Malformed,
+            IdentifyError::InvalidChallenge => ErrorCode::InvalidChallenge,
+            IdentifyError::StaleTimestamp   => ErrorCode::StaleTimestamp,
+            IdentifyError::InvalidSignature => ErrorCode::InvalidSignature,
+            IdentifyError::Banned           => ErrorCode::Unauthorized,
+            IdentifyError::Replayed         => ErrorCode::InvalidChallenge,
+            IdentifyError::InvalidDelegation => ErrorCode::InvalidSignature,
+            IdentifyError::Revoked          => ErrorCode::Unauthorized,
+            IdentifyError::Rotated          => ErrorCode::Unauthorized,
+        };
+
+        ErrorMsg::new(code, v.to_string())
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum FrameError {
+    #[error("frame of {size} bytes exceeds the limit of {max} bytes")]
+    Oversized { size: u32, max: u32 },
+    #[error("the stream ended in the middle of a frame")]
+    Truncated,
+    #[error("the read from the stream was cancelled")]
+    Cancelled,
+    #[error("deserialization of frame failed")]
+    DeserializeError(#[from] serde_cbor::Error),
+    #[error("cannot read from or write to the stream")]
+    IoError(#[from] std::io::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum ProxyFormatError {
+    #[error("invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("invalid JSON5: {0}")]
+    Json5(#[from] json5::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum DbError {
+    #[error("the requested data does not exist")]
+    NotFound,
+    #[error("the data conflicts with existing data")]
+    Conflict,
+    #[error("the node does not store data")]
+    Disabled,
+    #[error("the mailbox of the recipient is full")]
+    MailboxFull,
+    #[error("the storage backend failed: {0}")]
+    IoError(String),
+    #[error("stored data is corrupted: {0}")]
+    Corrupted(String),
+}
+
+impl From<sled::Error> for DbError {
+    fn from(v : sled::Error) -> DbError {
+        match v {
+            sled::Error::Corruption { .. } => DbError::Corrupted(v.to_string()),
+            _                              => DbError::IoError(v.to_string()),
+        }
+    }
+}
+
+impl From<sled::transaction::TransactionError<DbError>> for DbError {
+    fn from(v : sled::transaction::TransactionError<DbError>) -> DbError {
+        match v {
+            sled::transaction::TransactionError::Abort(e)   => e,
+            sled::transaction::TransactionError::Storage(e) => e.into(),
+        }
+    }
+}
+
+impl From<&DbError> for ErrorMsg {
+    fn from(v : &DbError) -> ErrorMsg {
+        match v {
+            DbError::NotFound   => ErrorMsg::new(ErrorCode::NotFound, v.to_string()),
+            DbError::Conflict   => ErrorMsg::new(ErrorCode::Conflict, v.to_string()),
+            DbError::Disabled   => ErrorMsg::new(ErrorCode::Unsupported, v.to_string()),
+            DbError::MailboxFull => ErrorMsg::new(ErrorCode::Unreachable, v.to_string()),
+            // Backend details are only logged by the node
+            DbError::IoError(_) |
+            DbError::Corrupted(_) => ErrorMsg::new(ErrorCode::StorageFailure, "the storage of the node failed"),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum EnvelopeError {
+    #[error("the public key is not a valid point")]
+    InvalidKey(#[from] libsecp256k1::Error),
+    #[error("the envelope cannot be opened with the keys")]
+    DecryptError(#[from] aes_gcm::Error),
+    #[error("serialization of the contents failed")]
+    SerializeError(#[from] serde_cbor::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum RatchetError {
+    #[error("the public key is not a valid point")]
+    InvalidKey(#[from] libsecp256k1::Error),
+    #[error("the signature of the prekey bundle is invalid")]
+    InvalidSignature,
+    #[error("the session was started with a prekey that is not known")]
+    UnknownPrekey,
+    #[error("the message skips more than {0} messages of the session")]
+    TooManySkipped(u32),
+    #[error("the message cannot be decrypted by the session")]
+    DecryptError(#[from] aes_gcm::Error),
+    #[error("serialization of the contents failed")]
+    SerializeError(#[from] serde_cbor::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum PeerError {
+    #[error("the node sent an unexpected {0:?} message")]
+    UnexpectedMessage(MessageHeader),
+    #[error("the node closed the link")]
+    Closed,
+    #[error("cannot resolve the address {0}")]
+    Unresolved(String),
+    #[error("the node key is not the expected one")]
+    UnexpectedKey,
+    #[error("the signature of the node is invalid")]
+    InvalidSignature,
+    #[error("the timestamp is outside of the accepted window")]
+    StaleTimestamp,
+    #[error("the frame was sent out of order")]
+    OutOfOrder,
+    #[error("cannot exchange frames with the node")]
+    FrameError(#[from] FrameError),
+    #[error("serialization of a message failed")]
+    SerializeError(#[from] serde_cbor::Error),
+}
@@ -1,6 +1,8 @@
 use std::net::{AddrParseError, SocketAddr};
 
-pub fn parse_ip(ip: &String, port: u16) -> Result<SocketAddr, AddrParseError> {
+/// Parses an IP address and a port. A prefix length (e.g `::/0`) is ignored, so the address can be written as a range
+pub fn parse_ip(ip: &str, port: u16) -> Result<SocketAddr, AddrParseError> {
+    let ip = ip.split('/').next().unwrap_or(ip);
     let chars = ip.chars().collect::<Vec<char>>();
 
     // Is IPv6
@@ -9,5 +11,5 @@ pub fn parse_ip(ip: &String, port: u16) -> Result<SocketAddr, AddrParseError> {
     }
 
     // Is IPv4
-    return format!("{}:{}", ip, port).parse::<SocketAddr>();
+    format!("{}:{}", ip, port).parse::<SocketAddr>()
 }
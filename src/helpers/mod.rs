@@ -1,8 +1,12 @@
 pub use self::file::*;
+pub use self::secrets::*;
 
 mod file;
 pub mod ip;
+mod secrets;
 
-pub fn hash_s(s: &str) -> [u8; 32] {
-    blake3::hash(s.as_bytes()).as_bytes().clone()
+/// The key of secrets files written before the Argon2id header: an unsalted blake3 hash of the password.
+/// Only used to read them once, they are written again with a derived key
+pub fn legacy_secrets_key(pass: &str) -> [u8; 32] {
+    *blake3::hash(pass.as_bytes()).as_bytes()
 }
@@ -0,0 +1,195 @@
+use argon2::{Algorithm, Argon2, Params, Version};
+use byteorder::{ByteOrder, LittleEndian};
+use rand::{rngs::OsRng, RngCore};
+
+use crate::error::ConfigError;
+
+/// Written at the start of every secrets file whose key is derived with Argon2id.
+/// Older secrets files start directly with the ciphertext
+pub const SECRETS_MAGIC: &[u8; 4] = b"CCSF";
+/// Version of the secrets files written by the node. Version 1 files read their nonce from a separate `nonce` file
+pub const SECRETS_VERSION: u8 = 2;
+/// Length of the header of version 1: magic, version, Argon2id costs and salt
+const V1_HEADER_LEN: usize = 4 + 1 + 3 * 4 + 16;
+/// Length of the header of version 2: the header of version 1 followed by the nonce
+const V2_HEADER_LEN: usize = V1_HEADER_LEN + 12;
+
+/// Memory cost in KiB, time cost and parallelism of new secrets files
+const DEFAULT_M_COST: u32 = 19456;
+const DEFAULT_T_COST: u32 = 2;
+const DEFAULT_P_COST: u32 = 1;
+/// How many times the default costs a secrets file can require.
+/// Imported backups are untrusted, their costs must not exhaust the memory or the time of the node
+const MAX_COST_FACTOR: u32 = 8;
+
+/// Parameters deriving the key of the secrets file from its password
+#[derive(Clone, Copy)]
+pub struct KdfParams {
+    /// Memory cost in KiB
+    pub m_cost: u32,
+    /// Amount of passes over the memory
+    pub t_cost: u32,
+    pub p_cost: u32,
+    pub salt: [u8; 16],
+}
+
+impl KdfParams {
+    /// Creates the parameters of a new secrets file, with a random salt
+    pub fn random() -> Self {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+
+        Self {
+            m_cost: DEFAULT_M_COST,
+            t_cost: DEFAULT_T_COST,
+            p_cost: DEFAULT_P_COST,
+            salt,
+        }
+    }
+    /// Derives the key of the secrets file from the password with Argon2id
+    pub fn derive(&self, pass: &str) -> Result<[u8; 32], argon2::Error> {
+        let params = Params::new(self.m_cost, self.t_cost, self.p_cost, Some(32))?;
+        let mut key = [0u8; 32];
+
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params).hash_password_into(pass.as_bytes(), &self.salt, &mut key)?;
+        Ok(key)
+    }
+}
+
+/// The contents of a secrets file
+pub struct SecretsFile<'a> {
+    pub params: KdfParams,
+    /// The nonce of the ciphertext. [`None`] for version 1 files, which read it from the `nonce` file
+    pub nonce: Option<[u8; 12]>,
+    pub ciphertext: &'a [u8],
+}
+
+impl<'a> SecretsFile<'a> {
+    /// Reads the header of a secrets file, rejecting costs above [`MAX_COST_FACTOR`] times the defaults.
+    /// Returns [`None`] if the file has no header, which is the case of secrets files written before the header
+    pub fn parse(file: &'a [u8]) -> Result<Option<Self>, ConfigError> {
+        if file.len() < V1_HEADER_LEN || &file[..4] != SECRETS_MAGIC {
+            return Ok(None);
+        }
+
+        let params = KdfParams {
+            m_cost: LittleEndian::read_u32(&file[5..9]),
+            t_cost: LittleEndian::read_u32(&file[9..13]),
+            p_cost: LittleEndian::read_u32(&file[13..17]),
+            salt: file[17..V1_HEADER_LEN].try_into().unwrap(),
+        };
+        let costs = [
+            (params.m_cost, DEFAULT_M_COST),
+            (params.t_cost, DEFAULT_T_COST),
+            (params.p_cost, DEFAULT_P_COST),
+        ];
+        if costs.iter().any(|(cost, default)| *cost > default * MAX_COST_FACTOR) {
+            return Err(ConfigError::Corrupted("the key derivation costs are too high".to_string()));
+        }
+
+        match file[4] {
+            1 => Ok(Some(Self {
+                params,
+                nonce: None,
+                ciphertext: &file[V1_HEADER_LEN..],
+            })),
+            2 if file.len() >= V2_HEADER_LEN => Ok(Some(Self {
+                params,
+                nonce: Some(file[V1_HEADER_LEN..V2_HEADER_LEN].try_into().unwrap()),
+                ciphertext: &file[V2_HEADER_LEN..],
+            })),
+            v => Err(ConfigError::UnsupportedVersion(v)),
+        }
+    }
+    /// Writes a secrets file of the current version
+    pub fn encode(params: &KdfParams, nonce: &[u8; 12], ciphertext: &[u8]) -> Vec<u8> {
+        let mut file = Vec::with_capacity(V2_HEADER_LEN + ciphertext.len());
+
+        file.extend(SECRETS_MAGIC);
+        file.push(SECRETS_VERSION);
+        for cost in [params.m_cost, params.t_cost, params.p_cost] {
+            file.extend(cost.to_le_bytes());
+        }
+        file.extend(params.salt);
+        file.extend(nonce);
+        file.extend(ciphertext);
+
+        file
+    }
+}
+
+/// Returns a random nonce. Every write of the secrets file uses a new nonce
+pub fn random_nonce() -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    OsRng.fill_bytes(&mut nonce);
+
+    nonce
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::helpers::legacy_secrets_key;
+
+    use super::*;
+
+    /// Parameters cheap enough for tests
+    fn params(salt: u8) -> KdfParams {
+        KdfParams {
+            m_cost: 8,
+            t_cost: 1,
+            p_cost: 1,
+            salt: [salt; 16],
+        }
+    }
+
+    #[test]
+    fn keys_depend_on_the_password_and_the_salt() {
+        let key = params(1).derive("password").unwrap();
+
+        assert_eq!(params(1).derive("password").unwrap(), key);
+        assert_ne!(params(2).derive("password").unwrap(), key);
+        assert_ne!(params(1).derive("other").unwrap(), key);
+        assert_ne!(legacy_secrets_key("password"), key);
+    }
+
+    #[test]
+    fn headers_round_trip() {
+        let file = SecretsFile::encode(&params(1), &[3; 12], b"ciphertext");
+        let parsed = SecretsFile::parse(&file).unwrap().unwrap();
+
+        assert_eq!((parsed.params.m_cost, parsed.params.t_cost, parsed.params.p_cost), (8, 1, 1));
+        assert_eq!(parsed.params.salt, [1; 16]);
+        assert_eq!(parsed.nonce, Some([3; 12]));
+        assert_eq!(parsed.ciphertext, b"ciphertext");
+    }
+
+    #[test]
+    fn files_without_a_known_header_are_told_apart() {
+        // Written before the header, the file is only the ciphertext
+        assert!(SecretsFile::parse(b"ciphertext of a legacy secrets file").unwrap().is_none());
+
+        let mut file = SecretsFile::encode(&params(1), &[3; 12], b"ciphertext");
+        file[4] = 1;
+        let parsed = SecretsFile::parse(&file).unwrap().unwrap();
+        assert!(parsed.nonce.is_none());
+
+        file[4] = SECRETS_VERSION + 1;
+        assert!(matches!(SecretsFile::parse(&file), Err(ConfigError::UnsupportedVersion(3))));
+    }
+
+    #[test]
+    fn excessive_costs_are_rejected() {
+        let mut bounded = params(1);
+        bounded.t_cost = DEFAULT_T_COST * MAX_COST_FACTOR;
+        assert!(SecretsFile::parse(&SecretsFile::encode(&bounded, &[3; 12], b"ciphertext")).is_ok());
+
+        for excessive in [
+            KdfParams { m_cost: DEFAULT_M_COST * MAX_COST_FACTOR + 1, ..params(1) },
+            KdfParams { t_cost: DEFAULT_T_COST * MAX_COST_FACTOR + 1, ..params(1) },
+            KdfParams { p_cost: u32::MAX, ..params(1) },
+        ] {
+            let file = SecretsFile::encode(&excessive, &[3; 12], b"ciphertext");
+            assert!(matches!(SecretsFile::parse(&file), Err(ConfigError::Corrupted(_))));
+        }
+    }
+}
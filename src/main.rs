@@ -1,22 +1,15 @@
-#![feature(let_chains)]
-
-use aes_gcm::aead::OsRng;
-use config::{ConfigManager, SecretConfiguration};
-use error::ConfigError;
-use server::start_empty;
-use tokio::io::AsyncReadExt;
-use std::io::Write;
+use config::{ConfigManager, SecretConfiguration, CONFIG_PATH};
+use quinn::ServerConfig;
+use server::NodeService;
+use std::error::Error;
 use std::sync::Arc;
-use std::{error::Error, time::Duration};
-use tokio::time::sleep;
-use tokio::{
-    fs::File,
-    io::{AsyncWriteExt},
-};
-use rpassword::read_password;
+use tokio::sync::watch;
 
-use crate::config::{Configuration};
+use crate::config::Configuration;
+use crate::data::crypto::PrivKey;
+use crate::db::{DbApi, EmptyDb, StorageDb};
 
+pub mod cli;
 pub mod config;
 pub mod data;
 pub mod db;
@@ -26,76 +19,133 @@ pub mod server;
 
 #[tokio::main(flavor = "multi_thread")]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let (config, mgr) = ConfigManager::get_config("./Config.toml").await?;
+    tracing_subscriber::fmt::init();
+
+    let (config, mgr) = ConfigManager::get_config(CONFIG_PATH).await?;
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("secrets") {
+        return cli::secrets(&config, &mgr, &args[1..]).await;
+    }
+
+    let (secret, _) = cli::unlock_secrets(&config, &mgr, true).await?;
+
+    if config.main_config.features.contains("storage") {
+        tracing::info!("Opening storage at {}", config.storage.location);
+        let db = StorageDb::open(&config.storage.location)?;
+
+        run(config, mgr, secret, db).await
+    } else {
+        run(config, mgr, secret, EmptyDb {}).await
+    }
+}
+
+/// Starts every service enabled in the configuration and waits for a shutdown signal
+async fn run<T: DbApi + 'static>(
+    config: Arc<Configuration>,
+    mgr: ConfigManager,
+    secret: SecretConfiguration,
+    db: T,
+) -> Result<(), Box<dyn Error>> {
+    // Feature Checks
+    let features = &config.main_config.features;
 
-    let pass = match &config.secret_config.password {
-        Some(v) => v.clone(),
-        None => {
-            tracing::info!("Please type the password for the secrets file.");
-            read_password().unwrap()
-        }
+    // The node key identifies the node to other nodes and clients
+    let (node_key, node_rotation) = match secret.private_key {
+        Some(_) if config.secret_config.restart_key => (PrivKey::random(), None),
+        Some(v) => (PrivKey::new(v)?, secret.node_rotation),
+        None => return Err("the secrets file has no node key".into()),
     };
-
-    let secret = match mgr.get_secrets(&pass).await {
-        Ok(v) => {
-            Some(v)
-        }
-        Err(e) => {
-            match e {
-                ConfigError::DeserializeError(v) => {
-                    // There shouldn't be errors deserializing
-                    panic!("{}", v)
-                }
-                ConfigError::IoError(_) => {
-                    Some(mgr.create_secrets(&pass).await?)
-                }
-                ConfigError::PasswordError(_) => {
-                    None                
-                }
+    tracing::info!(
+        "Node key {} (fingerprint {})",
+        node_key.public_key().to_hex(),
+        node_key.public_key().fingerprint()
+    );
+
+    let node = Arc::new(NodeService::new(config.clone(), node_key, node_rotation, secret.admin_pass, db));
+    node.load_bans().await?;
+    node.load_revocations().await?;
+    node.load_rotations().await?;
+    node.load_peers().await?;
+    let (shutdown_send, shutdown_recv) = watch::channel(false);
+    let mut services = Vec::new();
+
+    if features.contains("base") {
+        tracing::info!("Starting base node...");
+
+        let (certs, key) = mgr.get_or_create_certs().await?;
+        let server_config = ServerConfig::with_single_cert(certs, key)?;
+
+        let node = node.clone();
+        let shutdown = shutdown_recv.clone();
+
+        services.push(tokio::spawn(async move {
+            if let Err(e) = node.server(server_config, shutdown).await {
+                tracing::error!("Base node stopped: {}", e);
             }
-        }
-    };
+        }));
+    }
 
-    let secret = match secret {
-        Some(v) => v,
-        None => {
-            let mut val = None;
+    if features.contains("storage") {
+        let node = node.clone();
+        let shutdown = shutdown_recv.clone();
 
-            for i in 1..5 {
-                tracing::info!("Please type the password for the secrets file. {}/5", i + 1);
-                let password = read_password().unwrap();
+        services.push(tokio::spawn(async move {
+            node.expire_mailbox(shutdown).await;
+        }));
+    }
 
-                val = Some(mgr.get_secrets(&password).await?);
+    if features.contains("federation") {
+        tracing::info!("Starting federation...");
 
-                if val.is_some() {
-                    break;
-                }
-            }
+        let node = node.clone();
+        let shutdown = shutdown_recv.clone();
+
+        services.push(tokio::spawn(async move {
+            tokio::join!(node.maintain_peers(shutdown.clone()), node.maintain_dht(shutdown));
+        }));
+    }
 
-            match val {
-                Some(v) => v,
-                None => { 
-                    let mut v = SecretConfiguration::default();
-                    v.private_key = Some(libsecp256k1::SecretKey::random(&mut OsRng).serialize());
-                    v
-                }
+    if features.contains("proxy") {
+        tracing::info!("Starting proxy...");
+
+        let node = node.clone();
+        let shutdown = shutdown_recv.clone();
+
+        services.push(tokio::spawn(async move {
+            if let Err(e) = node.proxy(shutdown).await {
+                tracing::error!("Proxy stopped: {}", e);
             }
-        }
-    };
+        }));
+    }
 
-    // Feature Checks
-    let features = &config.main_config.features;
+    shutdown_signal().await?;
+    tracing::info!("Shutting down...");
 
+    // Every service stops accepting connections and closes the existing ones
+    let _ = shutdown_send.send(true);
+    for service in services {
+        let _ = service.await;
+    }
 
-    // if features.contains("base") {
-    //     tracing::info!("Starting base node...");
+    Ok(())
+}
 
-    //     let c = config.clone();
+/// Completes when the process receives SIGINT or SIGTERM
+async fn shutdown_signal() -> Result<(), Box<dyn Error>> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
 
-    //     tokio::spawn(async move {
-    //         start_empty(c, server_config).await;
-    //     });
-    // }
+        let mut terminate = signal(SignalKind::terminate())?;
+
+        tokio::select! {
+            v = tokio::signal::ctrl_c() => v?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
 
-    loop {}
-}
\ No newline at end of file
+    Ok(())
+}
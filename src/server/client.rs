@@ -1,89 +1,1738 @@
-use std::{collections::HashSet, error::Error, fmt::Display};
+use std::{collections::HashSet, error::Error, net::SocketAddr, sync::Arc};
 
-use futures::{channel::mpsc, io::Take, select_biased, AsyncReadExt, FutureExt, StreamExt};
-use quinn::{NewConnection, RecvStream};
+use chrono::{Duration, Utc};
+use futures::{channel::mpsc, pin_mut, select_biased, FutureExt, StreamExt};
+use quinn::{NewConnection, RecvStream, SendStream};
+use rand::{rngs::OsRng, RngCore};
+use serde::{de::DeserializeOwned, Serialize};
 
-use crate::data::{crypto::PubKey, Identifier, Message, MessageHeader, StreamIdentify};
+use crate::{
+    data::{
+        crypto::{PubKey, SignedMsg},
+        ratchet::PrekeyBundle,
+        AdminAuth, AdminChallenge, AdminCommand, AdminResponse, Channel, ChannelId,
+        CommunicationAccepted, CommunicationRequest, ConnectionInfo, Delegation, DirectMessage,
+        ErrorCode, ErrorMsg, Group, GroupCreate, GroupId, GroupInvite, GroupKick, GroupLeave,
+        GroupMessage, GroupRole, GroupSetRole, Guild, GuildCreate, GuildDeleteChannel,
+        GuildDeleteRole, GuildId, GuildInvite, GuildKick, GuildLeave, GuildMember,
+        GuildMemberRoles, GuildMessage, GuildSetChannel, GuildSetRole, HomeEntry, HomeLookup,
+        HomeRecord, Identifier, IdentifyAccepted, IdentifyChallenge, KeyRotation, Message,
+        MessageHeader, NodeStats, Permissions, PrekeyRequest, Publicity, QueuedAck, Revocation,
+        Role, RotationRequest, StreamIdentify, SubAccount, SubAccountList, SubAccountsRequest,
+        Transport, EVERYONE_ROLE,
+    },
+    error::{DbError, FrameError, IdentifyError},
+};
 
-#[derive(Debug)]
-pub struct CancelError;
-
-impl Error for CancelError {}
-impl Display for CancelError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "the read from the stream was cancelled")
-    }
-}
+use super::{
+    codec::{FrameReader, FrameWriter, MAX_FRAME_SIZE, MAX_NODE_FRAME_SIZE},
+    dht::{lookup, publish},
+    node::NodeState,
+    peer::{accept_peer, local_records},
+    registry::ConnectionId,
+};
 
 pub struct Client {
-    pub receive: ClientReceiver,
+    /// Unique ID of the connection
+    pub id: ConnectionId,
+    /// Messages sent to the client. Drained by the task writing to the stream
+    pub outgoing: mpsc::UnboundedSender<Message>,
     pub identities: HashSet<PubKey>,
+    /// The type of the stream, if the client identified it
+    pub stream_type: Option<StreamIdentify>,
+    /// Address the client connected from
+    address: SocketAddr,
+    /// Opened conversations as pairs of (sender, recipient) public keys
+    conversations: HashSet<(PubKey, PubKey)>,
+    /// The challenge the client has to sign to identify. Replaced after every identify
+    challenge: [u8; 32],
+    /// The challenge of the administration stream, if the client identified the stream as one
+    admin_challenge: Option<[u8; 32]>,
+    /// If the client authenticated with the admin password
+    admin: bool,
+    state: Arc<NodeState>,
 }
 
 impl Client {
-    pub fn new(receive: ClientReceiver) -> Self {
+    /// Creates a client and adds it to the registry. `canceller` closes the connection when a value is sent to it
+    pub fn new(
+        state: Arc<NodeState>,
+        outgoing: mpsc::UnboundedSender<Message>,
+        canceller: mpsc::UnboundedSender<()>,
+        address: SocketAddr,
+        transport: Transport,
+    ) -> Self {
+        let mut challenge = [0u8; 32];
+        OsRng.fill_bytes(&mut challenge);
+
+        let id = state.registry.next_id();
+        let info = ConnectionInfo {
+            id,
+            address: address.to_string(),
+            transport,
+            connected_at: Utc::now(),
+            stream_type: None,
+            identities: Vec::new(),
+        };
+        state.registry.connect(info, canceller);
+
         Self {
-            receive,
+            id,
+            outgoing,
             identities: HashSet::default(),
+            stream_type: None,
+            address,
+            conversations: HashSet::default(),
+            challenge,
+            admin_challenge: None,
+            admin: false,
+            state,
+        }
+    }
+    /// Verifies every identity of an [`Identifier`] and adds the public keys to the identities of the client.
+    /// Either all of the identities are accepted, or none of them are.
+    /// The challenge is consumed by every attempt, so a new one has to be sent afterwards
+    pub fn identify(&mut self, identifier: Identifier) -> Result<Vec<PubKey>, IdentifyError> {
+        let challenge = self.challenge;
+        OsRng.fill_bytes(&mut self.challenge);
+
+        if identifier.identities.is_empty() {
+            return Err(IdentifyError::NoIdentities);
+        }
+        if identifier.sig_msg != challenge {
+            return Err(IdentifyError::InvalidChallenge);
+        }
+
+        let config = self.state.config();
+        let window = Duration::seconds(config.identify.clock_skew_secs as i64);
+
+        let age = Utc::now().signed_duration_since(identifier.timestamp);
+        if age > window || -age > window {
+            return Err(IdentifyError::StaleTimestamp);
+        }
+
+        let msg = SignedMsg::from_identity(&identifier.sig_msg, &identifier.timestamp);
+
+        let mut keys = Vec::with_capacity(identifier.identities.len());
+        for mut identity in identifier.identities {
+            match msg.verify(&mut identity.key, &identity.signature) {
+                Ok(true) => keys.push(identity.key),
+                _ => return Err(IdentifyError::InvalidSignature),
+            }
+
+            if self.state.rotation(&identity.key).is_some() {
+                return Err(IdentifyError::Rotated);
+            }
+
+            // The device also identifies as its user
+            if let Some(delegation) = identity.delegation {
+                if delegation.device != identity.key || delegation.is_expired() || !delegation.verify() {
+                    return Err(IdentifyError::InvalidDelegation);
+                }
+                if self.state.is_revoked(&delegation) {
+                    return Err(IdentifyError::Revoked);
+                }
+                if !keys.contains(&delegation.master) {
+                    keys.push(delegation.master);
+                }
+            }
+        }
+
+        if keys.iter().any(|key| self.state.is_banned(key)) {
+            return Err(IdentifyError::Banned);
+        }
+        if !self.state.replay.insert(&keys, &identifier.sig_msg, &identifier.timestamp, window) {
+            return Err(IdentifyError::Replayed);
+        }
+
+        for key in &keys {
+            if self.identities.insert(*key) {
+                self.state.registry.register(*key, self.id, self.outgoing.clone());
+            }
+        }
+
+        Ok(keys)
+    }
+    /// Helper method to create a message from an object and queue it to be sent to the client
+    pub fn send_obj<T: Serialize>(&self, header: MessageHeader, obj: &T) -> Result<(), Box<dyn Error>> {
+        self.outgoing.unbounded_send(Message::new(header, obj)?)?;
+
+        Ok(())
+    }
+    /// Queues an error message to be sent to the client
+    pub fn send_error(&self, error: ErrorMsg) -> Result<(), Box<dyn Error>> {
+        self.send_obj(MessageHeader::Error, &error)
+    }
+    /// Decodes the object of a message, or sends a malformed error to the client and returns [`None`]
+    fn decode<T: DeserializeOwned>(&self, object: serde_cbor::Value) -> Result<Option<T>, Box<dyn Error>> {
+        match serde_cbor::value::from_value(object) {
+            Ok(v) => Ok(Some(v)),
+            Err(e) => {
+                self.send_error(ErrorMsg::new(ErrorCode::Malformed, e.to_string()))?;
+                Ok(None)
+            }
+        }
+    }
+    /// Sends the challenge the client has to sign to identify
+    pub fn send_challenge(&self) -> Result<(), Box<dyn Error>> {
+        let challenge = IdentifyChallenge {
+            sig_msg: self.challenge,
+            node_key: self.state.node_pubkey,
+            node_rotation: self.state.node_rotation,
+        };
+
+        self.send_obj(MessageHeader::IdentifyChallenge, &challenge)
+    }
+    /// Returns the challenge the client currently has to sign
+    pub fn challenge(&self) -> [u8; 32] {
+        self.challenge
+    }
+    /// Sends every message queued for a public key while it was not connected
+    async fn send_queued(&self, key: &PubKey) -> Result<(), Box<dyn Error>> {
+        let queued = match self.state.db.get_queued(key).await {
+            Ok(v) => v,
+            Err(DbError::Disabled) => return Ok(()),
+            Err(e) => {
+                tracing::warn!("Cannot read queued messages: {}", e);
+                return Ok(());
+            }
+        };
+
+        for msg in queued {
+            self.send_obj(MessageHeader::QueuedMessage, &msg)?;
+        }
+
+        Ok(())
+    }
+    /// Sends a new challenge for the admin password
+    fn send_admin_challenge(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut nonce = [0u8; 32];
+        OsRng.fill_bytes(&mut nonce);
+        self.admin_challenge = Some(nonce);
+
+        self.send_obj(MessageHeader::AdminChallenge, &AdminChallenge { nonce })
+    }
+    /// Runs a command of an authenticated administrator
+    async fn run_admin_command(&self, command: AdminCommand) -> Result<AdminResponse, Box<dyn Error>> {
+        let state = &self.state;
+
+        Ok(match command {
+            AdminCommand::ListConnections => AdminResponse::Connections(state.registry.list()),
+            AdminCommand::Kick { key } => AdminResponse::Kicked(state.registry.kick(&key)),
+            AdminCommand::Ban { key } => AdminResponse::Kicked(state.ban(&key).await?),
+            AdminCommand::Unban { key } => {
+                state.unban(&key).await?;
+                AdminResponse::Done
+            }
+            AdminCommand::ListBans => AdminResponse::Bans(state.bans()),
+            AdminCommand::ReloadConfig => {
+                state.reload_config().await?;
+                AdminResponse::Done
+            }
+            AdminCommand::Stats => AdminResponse::Stats(Box::new(NodeStats {
+                version: state.config().main_config.version.clone(),
+                node_key: state.node_pubkey,
+                fingerprint: state.node_pubkey.fingerprint(),
+                started_at: state.started_at,
+                connections: state.registry.connection_count(),
+                online_keys: state.registry.online_count(),
+                bans: state.bans().len(),
+                peers: state.peers.count(),
+            })),
+        })
+    }
+    /// Returns a group, or sends an error to the client if it cannot be read
+    async fn load_group(&self, id: &GroupId) -> Result<Option<Group>, Box<dyn Error>> {
+        match self.state.db.get_group(id).await {
+            Ok(v) => Ok(Some(v)),
+            Err(e) => {
+                self.send_error((&e).into())?;
+                Ok(None)
+            }
+        }
+    }
+    /// Stores a changed group and sends it to every member, and to the member that was removed if there is one.
+    /// Sends a [`ErrorCode::Conflict`] error to the client if the group is no longer `previous`
+    async fn save_group(&self, previous: &Group, group: &Group, removed: Option<&PubKey>) -> Result<(), Box<dyn Error>> {
+        if let Err(e) = self.state.db.update_group(previous, group).await {
+            return self.send_error((&e).into());
+        }
+
+        let update = Message::new(MessageHeader::GroupUpdate, group)?;
+        self.state.deliver_group(group, &update, None).await;
+
+        if let Some(key) = removed {
+            if let Err(e) = self.state.deliver(key, &update).await {
+                tracing::debug!("Cannot notify removed group member: {}", e);
+            }
+        }
+
+        Ok(())
+    }
+    /// Returns a guild, or sends an error to the client if it cannot be read
+    async fn load_guild(&self, id: &GuildId) -> Result<Option<Guild>, Box<dyn Error>> {
+        match self.state.db.get_guild(id).await {
+            Ok(v) => Ok(Some(v)),
+            Err(e) => {
+                self.send_error((&e).into())?;
+                Ok(None)
+            }
+        }
+    }
+    /// Stores a changed guild and sends it to every member, and to the member that was removed if there is one.
+    /// Sends a [`ErrorCode::Conflict`] error to the client if the guild is no longer `previous`
+    async fn save_guild(&self, previous: &Guild, guild: &Guild, removed: Option<&PubKey>) -> Result<(), Box<dyn Error>> {
+        if let Err(e) = self.state.db.update_guild(previous, guild).await {
+            return self.send_error((&e).into());
+        }
+
+        let update = Message::new(MessageHeader::GuildUpdate, guild)?;
+        self.state.deliver_guild(guild, None, &update, None).await;
+
+        if let Some(key) = removed {
+            if let Err(e) = self.state.deliver(key, &update).await {
+                tracing::debug!("Cannot notify removed guild member: {}", e);
+            }
+        }
+
+        Ok(())
+    }
+    /// Checks that the client is identified as `from` and that `from` has a permission in the guild, or in a channel of it.
+    /// Sends an [`ErrorCode::Unauthorized`] error and returns false if not
+    fn check_guild_permission(
+        &self,
+        guild: &Guild,
+        from: &PubKey,
+        permission: Permissions,
+        channel: Option<ChannelId>,
+    ) -> Result<bool, Box<dyn Error>> {
+        if !self.check_identity(from, "not identified as the acting public key")? {
+            return Ok(false);
+        }
+        if !guild.is_member(from) {
+            self.send_error(ErrorMsg::new(ErrorCode::Unauthorized, "not a member of the guild"))?;
+            return Ok(false);
+        }
+
+        let permissions = match channel {
+            Some(channel) => guild.channel_permissions(from, channel),
+            None => guild.permissions(from),
+        };
+        if !permissions.contains(permission) {
+            self.send_error(ErrorMsg::new(
+                ErrorCode::Unauthorized,
+                format!("missing permissions {:?}", permission),
+            ))?;
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+    /// Sends an [`ErrorCode::Unauthorized`] error if the client is not identified as the public key.
+    /// Returns true if it is
+    fn check_identity(&self, key: &PubKey, description: &str) -> Result<bool, Box<dyn Error>> {
+        if self.identities.contains(key) {
+            return Ok(true);
+        }
+
+        self.send_error(ErrorMsg::new(ErrorCode::Unauthorized, description))?;
+        Ok(false)
+    }
+    /// Sends the sub accounts of a user. Private sub accounts are only sent if the client is identified as the user
+    async fn send_subaccounts(&self, key: &PubKey) -> Result<(), Box<dyn Error>> {
+        let mut accounts = match self.state.db.get_subaccounts(key).await {
+            Ok(v) => v,
+            // Delegations still work without storage, but are not listed
+            Err(DbError::Disabled) => Vec::new(),
+            Err(e) => return self.send_error((&e).into()),
+        };
+        if !self.identities.contains(key) {
+            accounts.retain(|a| a.publicity == Publicity::Public);
+        }
+
+        self.send_obj(MessageHeader::SubAccountList, &SubAccountList { key: *key, accounts })
+    }
+    /// Handles a message received from the client
+    pub async fn handle_message(&mut self, msg: Message) -> Result<(), Box<dyn Error>> {
+        match msg.header {
+            // 0: STREAM IDENTIFY
+            // The client identifies the QUIC stream type
+            MessageHeader::StreamIdentify => {
+                let obj = match self.decode::<StreamIdentify>(msg.object)? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+                self.stream_type = Some(obj);
+                self.state.registry.set_stream_type(self.id, obj);
+
+                if obj == StreamIdentify::Administration {
+                    if self.state.admin_pass.is_none() {
+                        return self.send_error(ErrorMsg::new(
+                            ErrorCode::Unsupported,
+                            "administration is disabled on this node",
+                        ));
+                    }
+
+                    self.send_admin_challenge()?;
+                }
+            }
+            // 1: IDENTIFY
+            // The client identifies themself with one or more public keys
+            MessageHeader::Identify => {
+                let obj = match self.decode::<Identifier>(msg.object)? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+
+                match self.identify(obj) {
+                    Ok(keys) => {
+                        self.send_obj(MessageHeader::IdentifyAccepted, &IdentifyAccepted { keys: keys.clone() })?;
+                        self.state.peers.announce_local(&local_records(&self.state, &keys));
+
+                        for key in &keys {
+                            self.send_queued(key).await?;
+                        }
+                    }
+                    Err(e) => {
+                        tracing::debug!("Identify rejected: {}", e);
+                        self.send_error((&e).into())?;
+                    }
+                }
+
+                self.send_challenge()?;
+            }
+            // 5: COMMUNICATION REQUEST
+            // The client opens a conversation with a public key
+            MessageHeader::CommunicationRequest => {
+                let obj = match self.decode::<CommunicationRequest>(msg.object)? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+
+                if !self.identities.contains(&obj.from) {
+                    return self.send_error(ErrorMsg::new(
+                        ErrorCode::Unauthorized,
+                        "not identified as the sender public key",
+                    ));
+                }
+
+                // The client migrates the conversation to the new key
+                if let Some(rotation) = self.state.rotation(&obj.public_key) {
+                    self.send_obj(MessageHeader::KeyRotated, &rotation)?;
+                }
+
+                self.conversations.insert((obj.from, obj.public_key));
+                self.state.add_contact(&obj.from, &obj.public_key).await;
+
+                // Let the recipient know about the conversation
+                let online = self.state.registry.send_to(
+                    &obj.public_key,
+                    &Message::new(MessageHeader::CommunicationRequest, &obj)?,
+                );
+
+                let accepted = CommunicationAccepted {
+                    public_key: obj.public_key,
+                    online,
+                };
+                self.send_obj(MessageHeader::CommunicationAccepted, &accepted)?;
+            }
+            // 7: DIRECT MESSAGE
+            // The client sends a message to a public key
+            MessageHeader::DirectMessage => {
+                let obj = match self.decode::<DirectMessage>(msg.object)? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+
+                if !self.identities.contains(&obj.from) || !self.conversations.contains(&(obj.from, obj.to)) {
+                    return self.send_error(ErrorMsg::new(
+                        ErrorCode::Unauthorized,
+                        "no conversation is open between the public keys",
+                    ));
+                }
+                self.state.add_contact(&obj.from, &obj.to).await;
+
+                let relayed = Message::new(MessageHeader::DirectMessage, &obj)?;
+
+                match self.state.deliver(&obj.to, &relayed).await {
+                    Ok(_) => {}
+                    Err(DbError::Disabled) => {
+                        self.send_error(ErrorMsg::new(
+                            ErrorCode::Unreachable,
+                            "the recipient is not connected",
+                        ))?;
+                    }
+                    Err(e) => self.send_error((&e).into())?,
+                }
+            }
+            // 9: QUEUED ACK
+            // The client received queued messages, which can be deleted
+            MessageHeader::QueuedAck => {
+                let obj = match self.decode::<QueuedAck>(msg.object)? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+
+                if !self.identities.contains(&obj.recipient) {
+                    return self.send_error(ErrorMsg::new(
+                        ErrorCode::Unauthorized,
+                        "not identified as the recipient public key",
+                    ));
+                }
+
+                for id in obj.ids {
+                    match self.state.db.ack_queued(&obj.recipient, id).await {
+                        // Already acknowledged by another connection
+                        Ok(()) | Err(DbError::NotFound) => {}
+                        Err(e) => return self.send_error((&e).into()),
+                    }
+                }
+            }
+            // 10: PUBLISH PREKEYS
+            // The client publishes the prekeys of one of its identities
+            MessageHeader::PublishPrekeys => {
+                let obj = match self.decode::<PrekeyBundle>(msg.object)? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+
+                if !self.identities.contains(&obj.identity) {
+                    return self.send_error(ErrorMsg::new(
+                        ErrorCode::Unauthorized,
+                        "not identified as the public key of the prekeys",
+                    ));
+                }
+                if !obj.verify() {
+                    return self.send_error(ErrorMsg::new(
+                        ErrorCode::InvalidSignature,
+                        "the signature of the signed prekey is invalid",
+                    ));
+                }
+
+                if let Err(e) = self.state.db.set_prekeys(&obj).await {
+                    self.send_error((&e).into())?;
+                }
+            }
+            // 11: PREKEY REQUEST
+            // The client requests the prekeys of a public key to start a session
+            MessageHeader::PrekeyRequest => {
+                let obj = match self.decode::<PrekeyRequest>(msg.object)? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+
+                match self.state.db.take_prekeys(&obj.public_key).await {
+                    Ok(bundle) => self.send_obj(MessageHeader::PrekeyBundle, &bundle)?,
+                    Err(e) => self.send_error((&e).into())?,
+                }
+            }
+            // 13: GROUP CREATE
+            // The client creates a group owned by one of its identities
+            MessageHeader::GroupCreate => {
+                let obj = match self.decode::<GroupCreate>(msg.object)? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+
+                if !self.check_identity(&obj.owner, "not identified as the owner public key")? {
+                    return Ok(());
+                }
+
+                let mut id = [0u8; 32];
+                OsRng.fill_bytes(&mut id);
+                let group = Group::new(id, obj.name, obj.owner, obj.members);
+
+                if let Err(e) = self.state.db.create_group(&group).await {
+                    return self.send_error((&e).into());
+                }
+
+                let update = Message::new(MessageHeader::GroupUpdate, &group)?;
+                self.state.deliver_group(&group, &update, None).await;
+            }
+            // 14: GROUP INVITE
+            // An admin of a group adds a public key to it
+            MessageHeader::GroupInvite => {
+                let obj = match self.decode::<GroupInvite>(msg.object)? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+
+                if !self.check_identity(&obj.from, "not identified as the inviting public key")? {
+                    return Ok(());
+                }
+                let mut group = match self.load_group(&obj.group).await? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+                let previous = group.clone();
+
+                if !group.can_invite(&obj.from) {
+                    return self.send_error(ErrorMsg::new(
+                        ErrorCode::Unauthorized,
+                        "only admins can invite to the group",
+                    ));
+                }
+                if !group.members.insert(obj.member) {
+                    return self.send_error(ErrorMsg::new(ErrorCode::Conflict, "already a member of the group"));
+                }
+
+                self.save_group(&previous, &group, None).await?;
+            }
+            // 15: GROUP LEAVE
+            // The client leaves a group
+            MessageHeader::GroupLeave => {
+                let obj = match self.decode::<GroupLeave>(msg.object)? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+
+                if !self.check_identity(&obj.member, "not identified as the leaving public key")? {
+                    return Ok(());
+                }
+                let mut group = match self.load_group(&obj.group).await? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+                let previous = group.clone();
+
+                if group.role(&obj.member).is_none() {
+                    return self.send_error(ErrorMsg::new(ErrorCode::NotFound, "not a member of the group"));
+                }
+
+                if group.remove_member(&obj.member) {
+                    self.save_group(&previous, &group, Some(&obj.member)).await?;
+                } else if let Err(e) = self.state.db.delete_group(&group.id).await {
+                    self.send_error((&e).into())?;
+                }
+            }
+            // 16: GROUP KICK
+            // An admin of a group removes a member with a lower role
+            MessageHeader::GroupKick => {
+                let obj = match self.decode::<GroupKick>(msg.object)? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+
+                if !self.check_identity(&obj.from, "not identified as the kicking public key")? {
+                    return Ok(());
+                }
+                let mut group = match self.load_group(&obj.group).await? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+                let previous = group.clone();
+
+                if !group.can_kick(&obj.from, &obj.member) {
+                    return self.send_error(ErrorMsg::new(
+                        ErrorCode::Unauthorized,
+                        "only members with a lower role can be kicked",
+                    ));
+                }
+
+                // Cannot empty the group, the kicking member is still in it
+                group.remove_member(&obj.member);
+                self.save_group(&previous, &group, Some(&obj.member)).await?;
+            }
+            // 17: GROUP SET ROLE
+            // The owner of a group promotes or demotes a member
+            MessageHeader::GroupSetRole => {
+                let obj = match self.decode::<GroupSetRole>(msg.object)? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+
+                if !self.check_identity(&obj.from, "not identified as the owner public key")? {
+                    return Ok(());
+                }
+                let mut group = match self.load_group(&obj.group).await? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+                let previous = group.clone();
+
+                if group.owner != obj.from {
+                    return self.send_error(ErrorMsg::new(
+                        ErrorCode::Unauthorized,
+                        "only the owner can change roles",
+                    ));
+                }
+
+                match (group.role(&obj.member), obj.role) {
+                    (None, _) => {
+                        return self.send_error(ErrorMsg::new(ErrorCode::NotFound, "not a member of the group"));
+                    }
+                    (Some(GroupRole::Owner), _) | (_, GroupRole::Owner) => {
+                        return self.send_error(ErrorMsg::new(
+                            ErrorCode::Unsupported,
+                            "the role of the owner cannot be changed",
+                        ));
+                    }
+                    (_, GroupRole::Admin) => {
+                        group.admins.insert(obj.member);
+                    }
+                    (_, GroupRole::Member) => {
+                        group.admins.remove(&obj.member);
+                    }
+                }
+
+                self.save_group(&previous, &group, None).await?;
+            }
+            // 19: GROUP MESSAGE
+            // The client sends a message to every member of a group
+            MessageHeader::GroupMessage => {
+                let obj = match self.decode::<GroupMessage>(msg.object)? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+
+                if !self.check_identity(&obj.from, "not identified as the sender public key")? {
+                    return Ok(());
+                }
+                let group = match self.load_group(&obj.group).await? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+
+                if group.role(&obj.from).is_none() {
+                    return self.send_error(ErrorMsg::new(ErrorCode::Unauthorized, "not a member of the group"));
+                }
+
+                let relayed = Message::new(MessageHeader::GroupMessage, &obj)?;
+                self.state.deliver_group(&group, &relayed, Some(&obj.from)).await;
+            }
+            // 20: GUILD CREATE
+            // The client creates a guild owned by one of its identities
+            MessageHeader::GuildCreate => {
+                let obj = match self.decode::<GuildCreate>(msg.object)? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+
+                if !self.check_identity(&obj.owner, "not identified as the owner public key")? {
+                    return Ok(());
+                }
+
+                let mut id = [0u8; 32];
+                OsRng.fill_bytes(&mut id);
+                let guild = Guild::new(id, obj.name, obj.owner);
+
+                if let Err(e) = self.state.db.create_guild(&guild).await {
+                    return self.send_error((&e).into());
+                }
+
+                let update = Message::new(MessageHeader::GuildUpdate, &guild)?;
+                self.state.deliver_guild(&guild, None, &update, None).await;
+            }
+            // 21: GUILD INVITE
+            // A member adds a public key to a guild
+            MessageHeader::GuildInvite => {
+                let obj = match self.decode::<GuildInvite>(msg.object)? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+                let mut guild = match self.load_guild(&obj.guild).await? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+                let previous = guild.clone();
+
+                if !self.check_guild_permission(&guild, &obj.from, Permissions::INVITE_MEMBERS, None)? {
+                    return Ok(());
+                }
+                if guild.is_member(&obj.member) {
+                    return self.send_error(ErrorMsg::new(ErrorCode::Conflict, "already a member of the guild"));
+                }
+
+                guild.members.push(GuildMember {
+                    key: obj.member,
+                    roles: HashSet::new(),
+                });
+                self.save_guild(&previous, &guild, None).await?;
+            }
+            // 22: GUILD LEAVE
+            // The client leaves a guild
+            MessageHeader::GuildLeave => {
+                let obj = match self.decode::<GuildLeave>(msg.object)? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+
+                if !self.check_identity(&obj.member, "not identified as the leaving public key")? {
+                    return Ok(());
+                }
+                let mut guild = match self.load_guild(&obj.guild).await? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+                let previous = guild.clone();
+
+                if guild.owner == obj.member {
+                    // The guild ends with its owner
+                    if guild.members.len() > 1 {
+                        return self.send_error(ErrorMsg::new(
+                            ErrorCode::Unsupported,
+                            "the owner cannot leave a guild with other members",
+                        ));
+                    }
+                    if let Err(e) = self.state.db.delete_guild(&guild.id).await {
+                        self.send_error((&e).into())?;
+                    }
+                    return Ok(());
+                }
+
+                if !guild.remove_member(&obj.member) {
+                    return self.send_error(ErrorMsg::new(ErrorCode::NotFound, "not a member of the guild"));
+                }
+                self.save_guild(&previous, &guild, Some(&obj.member)).await?;
+            }
+            // 23: GUILD KICK
+            // A member removes another member from a guild
+            MessageHeader::GuildKick => {
+                let obj = match self.decode::<GuildKick>(msg.object)? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+                let mut guild = match self.load_guild(&obj.guild).await? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+                let previous = guild.clone();
+
+                // Members cannot kick members with permissions they do not have
+                let permissions = Permissions::KICK_MEMBERS | guild.permissions(&obj.member);
+                if !self.check_guild_permission(&guild, &obj.from, permissions, None)? {
+                    return Ok(());
+                }
+                if guild.owner == obj.member {
+                    return self.send_error(ErrorMsg::new(ErrorCode::Unauthorized, "the owner cannot be kicked"));
+                }
+
+                if !guild.remove_member(&obj.member) {
+                    return self.send_error(ErrorMsg::new(ErrorCode::NotFound, "not a member of the guild"));
+                }
+                self.save_guild(&previous, &guild, Some(&obj.member)).await?;
+            }
+            // 24: GUILD SET CHANNEL
+            // A member creates or replaces a channel
+            MessageHeader::GuildSetChannel => {
+                let obj = match self.decode::<GuildSetChannel>(msg.object)? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+                let mut guild = match self.load_guild(&obj.guild).await? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+                let previous = guild.clone();
+
+                if !self.check_guild_permission(&guild, &obj.from, Permissions::MANAGE_CHANNELS, obj.id)? {
+                    return Ok(());
+                }
+                // Members cannot give or take permissions they do not have in the guild
+                let overwritten = obj
+                    .overwrites
+                    .iter()
+                    .fold(Permissions::empty(), |p, o| p | o.allow | o.deny);
+                let permissions = guild.permissions(&obj.from);
+                if !permissions.contains(overwritten) {
+                    return self.send_error(ErrorMsg::new(
+                        ErrorCode::Unauthorized,
+                        format!("missing permissions {:?}", overwritten - permissions),
+                    ));
+                }
+
+                let channel = Channel {
+                    id: obj.id.unwrap_or_else(|| guild.next_channel_id()),
+                    name: obj.name,
+                    overwrites: obj.overwrites,
+                };
+
+                match guild.channels.iter_mut().find(|c| c.id == channel.id) {
+                    Some(v) => *v = channel,
+                    None if obj.id.is_none() => guild.channels.push(channel),
+                    None => return self.send_error(ErrorMsg::new(ErrorCode::NotFound, "the channel does not exist")),
+                }
+                self.save_guild(&previous, &guild, None).await?;
+            }
+            // 25: GUILD DELETE CHANNEL
+            // A member deletes a channel
+            MessageHeader::GuildDeleteChannel => {
+                let obj = match self.decode::<GuildDeleteChannel>(msg.object)? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+                let mut guild = match self.load_guild(&obj.guild).await? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+                let previous = guild.clone();
+
+                if guild.channel(obj.channel).is_none() {
+                    return self.send_error(ErrorMsg::new(ErrorCode::NotFound, "the channel does not exist"));
+                }
+                if !self.check_guild_permission(&guild, &obj.from, Permissions::MANAGE_CHANNELS, Some(obj.channel))? {
+                    return Ok(());
+                }
+
+                guild.channels.retain(|c| c.id != obj.channel);
+                self.save_guild(&previous, &guild, None).await?;
+            }
+            // 26: GUILD SET ROLE
+            // A member creates or replaces a role
+            MessageHeader::GuildSetRole => {
+                let obj = match self.decode::<GuildSetRole>(msg.object)? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+                let mut guild = match self.load_guild(&obj.guild).await? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+                let previous = guild.clone();
+
+                let replaced = match obj.id {
+                    Some(id) => match guild.role(id) {
+                        Some(v) => v.permissions,
+                        None => return self.send_error(ErrorMsg::new(ErrorCode::NotFound, "the role does not exist")),
+                    },
+                    None => Permissions::empty(),
+                };
+                // Members cannot give permissions they do not have, nor change a role with permissions they do not have
+                if !self.check_guild_permission(
+                    &guild,
+                    &obj.from,
+                    Permissions::MANAGE_ROLES | obj.permissions | replaced,
+                    None,
+                )? {
+                    return Ok(());
+                }
+
+                let role = Role {
+                    id: obj.id.unwrap_or_else(|| guild.next_role_id()),
+                    name: obj.name,
+                    permissions: obj.permissions,
+                };
+
+                match guild.roles.iter_mut().find(|r| r.id == role.id) {
+                    Some(v) => *v = role,
+                    None => guild.roles.push(role),
+                }
+                self.save_guild(&previous, &guild, None).await?;
+            }
+            // 27: GUILD DELETE ROLE
+            // A member deletes a role, removing it from every member and channel
+            MessageHeader::GuildDeleteRole => {
+                let obj = match self.decode::<GuildDeleteRole>(msg.object)? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+                let mut guild = match self.load_guild(&obj.guild).await? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+                let previous = guild.clone();
+
+                let permissions = match guild.role(obj.role) {
+                    Some(v) if v.id != EVERYONE_ROLE => v.permissions,
+                    Some(_) => {
+                        return self.send_error(ErrorMsg::new(
+                            ErrorCode::Unsupported,
+                            "the everyone role cannot be deleted",
+                        ));
+                    }
+                    None => return self.send_error(ErrorMsg::new(ErrorCode::NotFound, "the role does not exist")),
+                };
+                if !self.check_guild_permission(&guild, &obj.from, Permissions::MANAGE_ROLES | permissions, None)? {
+                    return Ok(());
+                }
+
+                guild.roles.retain(|r| r.id != obj.role);
+                for member in &mut guild.members {
+                    member.roles.remove(&obj.role);
+                }
+                for channel in &mut guild.channels {
+                    channel.overwrites.retain(|o| o.role != obj.role);
+                }
+                self.save_guild(&previous, &guild, None).await?;
+            }
+            // 28: GUILD MEMBER ROLES
+            // A member replaces the roles of another member
+            MessageHeader::GuildMemberRoles => {
+                let obj = match self.decode::<GuildMemberRoles>(msg.object)? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+                let mut guild = match self.load_guild(&obj.guild).await? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+                let previous = guild.clone();
+
+                let current = match guild.member(&obj.member) {
+                    Some(v) => v.roles.clone(),
+                    None => return self.send_error(ErrorMsg::new(ErrorCode::NotFound, "not a member of the guild")),
+                };
+
+                // Members cannot give or take roles with permissions they do not have
+                let mut permissions = Permissions::MANAGE_ROLES;
+                for id in current.symmetric_difference(&obj.roles) {
+                    match guild.role(*id) {
+                        Some(role) if role.id != EVERYONE_ROLE => permissions |= role.permissions,
+                        _ => return self.send_error(ErrorMsg::new(ErrorCode::NotFound, "the role does not exist")),
+                    }
+                }
+                if !self.check_guild_permission(&guild, &obj.from, permissions, None)? {
+                    return Ok(());
+                }
+
+                // Cannot fail, the member was found above
+                guild.member_mut(&obj.member).unwrap().roles = obj.roles;
+                self.save_guild(&previous, &guild, None).await?;
+            }
+            // 30: GUILD MESSAGE
+            // The client sends a message to a channel of a guild
+            MessageHeader::GuildMessage => {
+                let obj = match self.decode::<GuildMessage>(msg.object)? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+                let guild = match self.load_guild(&obj.guild).await? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+
+                if guild.channel(obj.channel).is_none() {
+                    return self.send_error(ErrorMsg::new(ErrorCode::NotFound, "the channel does not exist"));
+                }
+                if !self.check_guild_permission(&guild, &obj.from, Permissions::SEND_MESSAGES, Some(obj.channel))? {
+                    return Ok(());
+                }
+
+                let relayed = Message::new(MessageHeader::GuildMessage, &obj)?;
+                self.state
+                    .deliver_guild(&guild, Some(obj.channel), &relayed, Some(&obj.from))
+                    .await;
+            }
+            // 32: ADMIN AUTH
+            // The client answers the challenge of the administration stream
+            MessageHeader::AdminAuth => {
+                let obj = match self.decode::<AdminAuth>(msg.object)? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+
+                // Every challenge can only be answered once
+                let (nonce, pass) = match (self.admin_challenge.take(), &self.state.admin_pass) {
+                    (Some(nonce), Some(pass)) => (nonce, pass),
+                    _ => {
+                        return self.send_error(ErrorMsg::new(
+                            ErrorCode::InvalidChallenge,
+                            "the stream was not identified as an administration stream",
+                        ));
+                    }
+                };
+
+                let config = self.state.config();
+                let ip = self.address.ip();
+                let lockout = Duration::seconds(config.identify.admin_lockout_secs as i64);
+
+                // Answers are not even checked once the address is locked out, and the connection is closed
+                if self.state.admin_lockout.is_locked(ip, config.identify.admin_max_failures, lockout) {
+                    tracing::warn!(target: "audit", "Connection {} from locked out address {} tried to authenticate as administrator", self.id, ip);
+                    self.send_error(ErrorMsg::new(ErrorCode::Unauthorized, "too many failed attempts, try again later"))?;
+                    return Err("too many failed administrator authentications".into());
+                }
+
+                // Comparing a blake3 hash takes constant time
+                if blake3::keyed_hash(pass, &nonce) == obj.response {
+                    tracing::info!(target: "audit", "Connection {} authenticated as administrator", self.id);
+                    self.state.admin_lockout.clear(ip);
+                    self.admin = true;
+                    self.send_obj(MessageHeader::AdminAccepted, &())?;
+                } else {
+                    let failures = self.state.admin_lockout.fail(ip, lockout);
+                    tracing::warn!(target: "audit", "Connection {} failed to authenticate as administrator ({}/{} from {})", self.id, failures, config.identify.admin_max_failures, ip);
+                    self.send_error(ErrorMsg::new(ErrorCode::Unauthorized, "invalid admin password"))?;
+
+                    if failures >= config.identify.admin_max_failures {
+                        return Err("too many failed administrator authentications".into());
+                    }
+                    self.send_admin_challenge()?;
+                }
+            }
+            // 34: ADMIN COMMAND
+            // An administrator sends a command to the node
+            MessageHeader::AdminCommand => {
+                let obj = match self.decode::<AdminCommand>(msg.object)? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+
+                if !self.admin {
+                    tracing::warn!(target: "audit", "Connection {} sent {:?} without authenticating", self.id, obj);
+                    return self.send_error(ErrorMsg::new(ErrorCode::Unauthorized, "not authenticated as administrator"));
+                }
+
+                tracing::info!(target: "audit", "Connection {} runs {:?}", self.id, obj);
+
+                match self.run_admin_command(obj).await {
+                    Ok(response) => self.send_obj(MessageHeader::AdminResponse, &response)?,
+                    Err(e) => {
+                        tracing::warn!(target: "audit", "Command of connection {} failed: {}", self.id, e);
+
+                        match e.downcast_ref::<DbError>() {
+                            Some(e) => self.send_error(e.into())?,
+                            None => self.send_error(ErrorMsg::new(ErrorCode::StorageFailure, e.to_string()))?,
+                        }
+                    }
+                }
+            }
+            // 36: NODE IDENTIFY
+            // Links with other nodes are handled by the connection before reaching the client
+            MessageHeader::NodeIdentify => {
+                self.send_error(ErrorMsg::new(ErrorCode::Unsupported, "federation is disabled on this node"))?;
+            }
+            // 46: PUBLISH HOME
+            // The client publishes in the DHT that one of its public keys is hosted by the node
+            MessageHeader::PublishHome => {
+                let obj = match self.decode::<HomeRecord>(msg.object)? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+
+                if !self.check_identity(&obj.key, "not identified as the public key of the record")? {
+                    return Ok(());
+                }
+                if obj.node != self.state.node_pubkey {
+                    return self.send_error(ErrorMsg::new(ErrorCode::Conflict, "the record points to another node"));
+                }
+
+                let config = self.state.config();
+                let address = match &config.peers.public_address {
+                    Some(v) if config.main_config.features.contains("federation") => v.clone(),
+                    _ => {
+                        return self.send_error(ErrorMsg::new(
+                            ErrorCode::Unsupported,
+                            "the node does not accept links from other nodes",
+                        ));
+                    }
+                };
+
+                let skew = Duration::seconds(config.identify.clock_skew_secs as i64);
+                if (Utc::now() - obj.timestamp).abs() > skew {
+                    return self.send_error(ErrorMsg::new(ErrorCode::StaleTimestamp, "the record is not recent"));
+                }
+                if !obj.verify() {
+                    return self.send_error(ErrorMsg::new(ErrorCode::InvalidSignature, "invalid record signature"));
+                }
+
+                let entry = HomeEntry { record: obj, address };
+                let state = self.state.clone();
+                let published = entry.clone();
+
+                // Linked nodes route the public key to this node once the record proves it
+                self.state.peers.announce_local(std::slice::from_ref(&entry.record));
+
+                tokio::spawn(async move {
+                    publish(&state, published).await;
+                });
+                self.send_obj(MessageHeader::HomeEntry, &entry)?;
+            }
+            // 47: HOME LOOKUP
+            // The client looks for the node hosting a public key
+            MessageHeader::HomeLookup => {
+                let obj = match self.decode::<HomeLookup>(msg.object)? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+
+                if !self.state.config().main_config.features.contains("federation") {
+                    return self.send_error(ErrorMsg::new(ErrorCode::Unsupported, "federation is disabled on this node"));
+                }
+
+                match lookup(&self.state, &obj.public_key).await {
+                    Some(entry) => self.send_obj(MessageHeader::HomeEntry, &entry)?,
+                    None => self.send_error(ErrorMsg::new(ErrorCode::NotFound, "no node hosts the public key"))?,
+                }
+            }
+            // 49: DELEGATE
+            // The user adds a device to its sub accounts
+            MessageHeader::Delegate => {
+                let obj = match self.decode::<Delegation>(msg.object)? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+
+                if !self.check_identity(&obj.master, "not identified as the master public key")? {
+                    return Ok(());
+                }
+                if obj.is_expired() {
+                    return self.send_error(ErrorMsg::new(ErrorCode::StaleTimestamp, "the delegation expired"));
+                }
+                if !obj.verify() {
+                    return self.send_error(ErrorMsg::new(ErrorCode::InvalidSignature, "invalid delegation signature"));
+                }
+                if self.state.is_revoked(&obj) {
+                    return self.send_error(ErrorMsg::new(ErrorCode::Unauthorized, "the device was revoked after the delegation"));
+                }
+
+                // A new delegation of the same device replaces the previous one
+                let previous = match self.state.db.get_subaccounts(&obj.master).await {
+                    Ok(v) => v.into_iter().find(|a| a.pub_key == obj.device),
+                    Err(e) => return self.send_error((&e).into()),
+                };
+                if let Some(previous) = &previous {
+                    if let Err(e) = self.state.db.remove_subaccount(&obj.master, &previous.pub_key).await {
+                        return self.send_error((&e).into());
+                    }
+                }
+
+                let account = SubAccount {
+                    pub_key: obj.device,
+                    publicity: previous.map_or(Publicity::Private, |a| a.publicity),
+                    delegation: Some(obj),
+                };
+                if let Err(e) = self.state.db.add_subaccount(&obj.master, &account).await {
+                    return self.send_error((&e).into());
+                }
+
+                self.send_subaccounts(&obj.master).await?;
+            }
+            // 50: REVOKE
+            // The user cuts off a device. Any client can send a revocation signed by the user
+            MessageHeader::Revoke => {
+                let obj = match self.decode::<Revocation>(msg.object)? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+
+                if !obj.verify() {
+                    return self.send_error(ErrorMsg::new(ErrorCode::InvalidSignature, "invalid revocation signature"));
+                }
+
+                match self.state.revoke(&obj).await {
+                    Ok(n) => tracing::info!("Revoked a device of {:?}, closing {} connections", obj.master, n),
+                    Err(e) => return self.send_error((&e).into()),
+                }
+
+                self.send_subaccounts(&obj.master).await?;
+            }
+            // 51: SUB ACCOUNTS REQUEST
+            // The client requests the sub accounts of a user
+            MessageHeader::SubAccountsRequest => {
+                let obj = match self.decode::<SubAccountsRequest>(msg.object)? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+
+                self.send_subaccounts(&obj.key).await?;
+            }
+            // 53: ROTATE KEY
+            // A public key is replaced by a new key. Any client can send a rotation signed by the old key
+            MessageHeader::RotateKey => {
+                let obj = match self.decode::<KeyRotation>(msg.object)? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+
+                let skew = Duration::seconds(self.state.config().identify.clock_skew_secs as i64);
+                if obj.timestamp > Utc::now() + skew {
+                    return self.send_error(ErrorMsg::new(ErrorCode::StaleTimestamp, "the rotation is in the future"));
+                }
+                if !obj.verify() {
+                    return self.send_error(ErrorMsg::new(ErrorCode::InvalidSignature, "invalid rotation signature"));
+                }
+
+                match self.state.rotate(&obj).await {
+                    Ok(n) => tracing::info!("Rotated public key {:?}, closing {} connections", obj.old, n),
+                    Err(DbError::Conflict) => {
+                        return self.send_error(ErrorMsg::new(ErrorCode::Conflict, "the public key was already rotated"));
+                    }
+                    Err(e) => return self.send_error((&e).into()),
+                }
+
+                let state = self.state.clone();
+                tokio::spawn(async move {
+                    match state.announce_rotation(&obj).await {
+                        Ok(0) => {}
+                        Ok(n) => tracing::debug!("Cannot send key rotation to {} contacts", n),
+                        Err(e) => tracing::warn!("Cannot send key rotation: {}", e),
+                    }
+                });
+
+                self.send_obj(MessageHeader::KeyRotated, &obj)?;
+            }
+            // 55: ROTATION REQUEST
+            // The client requests the rotation of a public key
+            MessageHeader::RotationRequest => {
+                let obj = match self.decode::<RotationRequest>(msg.object)? {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+
+                match self.state.rotation(&obj.key) {
+                    Some(rotation) => self.send_obj(MessageHeader::KeyRotated, &rotation)?,
+                    None => self.send_error(ErrorMsg::new(ErrorCode::NotFound, "the public key was not rotated"))?,
+                }
+            }
+            _ => {}
         }
+
+        Ok(())
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        let offline = self.state.registry.unregister(&self.identities, self.id);
+        self.state.peers.withdraw_local(&offline);
+        self.state.registry.disconnect(self.id);
     }
 }
 
 pub struct ClientReceiver {
     canceller: mpsc::UnboundedReceiver<()>,
-    stream: Take<RecvStream>,
+    reader: FrameReader<RecvStream>,
 }
 
 impl ClientReceiver {
     /// Creates a new client receiver
-    pub fn new(canceller: mpsc::UnboundedReceiver<()>, stream: RecvStream, max_bytes: u64) -> Self {
+    pub fn new(canceller: mpsc::UnboundedReceiver<()>, stream: RecvStream, max_frame_size: u32) -> Self {
         Self {
             canceller,
-            stream: stream.take(max_bytes),
+            reader: FrameReader::new(stream, max_frame_size),
         }
     }
-    /// Helper method to receive bytes and be cancellable by an unbounded receiver
-    pub async fn receive(&mut self) -> Result<Message, Box<dyn Error>> {
-        let mut buf = Vec::with_capacity(512);
-
+    /// Changes the maximum size of the next frames received
+    pub fn set_max_frame_size(&mut self, max_frame_size: u32) {
+        self.reader.set_max_size(max_frame_size);
+    }
+    /// Helper method to receive a message and be cancellable by an unbounded receiver.
+    /// Returns [`None`] if the client finished the stream
+    pub async fn receive(&mut self) -> Result<Option<Message>, FrameError> {
         // Reading from the stream
-        let mut fut1 = AsyncReadExt::read_to_end(&mut self.stream, &mut buf).fuse();
+        let fut1 = self.reader.read().fuse();
+        pin_mut!(fut1);
         // Reading from the receiver
         let mut fut2 = self.canceller.next().fuse();
 
-        let read = select_biased! {
+        select_biased! {
             // The receive was cancelled
-            _ = fut2 => return Err(Box::new(CancelError{})),
+            _ = fut2 => Err(FrameError::Cancelled),
             // The read is completed
             v1 = fut1 => v1
-        }?;
+        }
+    }
+}
 
-        buf.truncate(read);
+pub struct ClientSender {
+    writer: FrameWriter<SendStream>,
+}
 
-        // Deserialize the bytes
-        let a = serde_cbor::from_slice::<Message>(buf.as_ref())?;
-        Ok(a)
+impl ClientSender {
+    /// Creates a new client sender
+    pub fn new(stream: SendStream, max_frame_size: u32) -> Self {
+        Self {
+            writer: FrameWriter::new(stream, max_frame_size),
+        }
+    }
+    /// Serializes a message and writes it to the stream as a single frame
+    pub async fn send(&mut self, msg: &Message) -> Result<(), FrameError> {
+        self.writer.write(msg).await
     }
 }
 
-pub async fn handle_connection(connection: NewConnection) -> Result<(), Box<dyn Error>> {
+/// Spawns the task writing the messages of a channel to a stream, and returns the channel.
+/// Every message goes through a channel, so other connections can relay messages
+pub fn spawn_writer(stream: SendStream) -> mpsc::UnboundedSender<Message> {
+    let (out_send, mut out_recv) = mpsc::unbounded::<Message>();
+    let mut send = ClientSender::new(stream, MAX_NODE_FRAME_SIZE);
+
+    tokio::spawn(async move {
+        while let Some(msg) = out_recv.next().await {
+            if let Err(e) = send.send(&msg).await {
+                tracing::debug!("Cannot send message to client: {}", e);
+
+                // Oversized messages are dropped, other errors mean the stream is unusable
+                if !matches!(e, FrameError::Oversized { .. }) {
+                    break;
+                }
+            }
+        }
+    });
+
+    out_send
+}
+
+pub async fn handle_connection(
+    connection: NewConnection,
+    state: Arc<NodeState>,
+) -> Result<(), Box<dyn Error>> {
     // Create a bidirectional stream from the new connection
-    let (_send, recv) = connection.connection.open_bi().await?;
+    let (send, recv) = connection.connection.open_bi().await?;
+    let address = connection.connection.remote_address();
     // Create an unbounded channel that can cancel a receive
-    let (_c_send, c_recv) = mpsc::unbounded();
+    let (c_send, c_recv) = mpsc::unbounded();
     // Create a wrapper receiver
-    let mut client = Client::new(ClientReceiver::new(c_recv, recv, 32768));
+    let mut receive = ClientReceiver::new(c_recv, recv, MAX_FRAME_SIZE);
 
-    while let Ok(msg) = client.receive.receive().await {
-        match msg.header {
-            // 0: STREAM IDENTIFY
-            // The client identifies the QUIC stream type
-            MessageHeader::StreamIdentify => {
-                let obj = serde_cbor::value::from_value::<StreamIdentify>(msg.object)?;
+    let out_send = spawn_writer(send);
+
+    let mut client = Client::new(state.clone(), out_send, c_send.clone(), address, Transport::Quic);
+    client.send_challenge()?;
+
+    loop {
+        let msg = match receive.receive().await {
+            Ok(Some(v)) => v,
+            Ok(None) => break,
+            // The frame was read completely, so the next frames can still be read
+            Err(e @ FrameError::DeserializeError(_)) => {
+                client.send_error(ErrorMsg::new(ErrorCode::Malformed, e.to_string()))?;
+                continue;
             }
-            _ => {}
+            // The body of the frame was not read, the stream cannot be read past it
+            Err(e @ FrameError::Oversized { .. }) => {
+                client.send_error(ErrorMsg::new(ErrorCode::Malformed, e.to_string()))?;
+                break;
+            }
+            Err(e) => {
+                tracing::debug!("Cannot receive message from client: {}", e);
+                break;
+            }
+        };
+
+        // Another node identifies the connection as a link between both nodes
+        if msg.header == MessageHeader::NodeIdentify && state.config().main_config.features.contains("federation") {
+            let challenge = client.challenge();
+            let outgoing = client.outgoing.clone();
+            drop(client);
+
+            return Ok(accept_peer(state, msg, challenge, address, receive, outgoing, c_send).await?);
         }
+
+        client.handle_message(msg).await?;
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration as StdDuration;
+
+    use chrono::DateTime;
+
+    use crate::{
+        config::Configuration,
+        data::{crypto::PrivKey, envelope::SealedEnvelope, DirectContent, Identity, PermissionOverwrite},
+        db::StorageDb,
+        server::NodeService,
+    };
+
+    use super::*;
+
+    /// A client connected to the node, with the messages the node sends to it
+    struct TestClient {
+        client: Client,
+        received: mpsc::UnboundedReceiver<Message>,
+        _cancelled: mpsc::UnboundedReceiver<()>,
+    }
+
+    fn node() -> Arc<NodeState> {
+        let db = StorageDb::temporary().unwrap();
+        let node = NodeService::new(Arc::new(Configuration::default()), PrivKey::random(), None, None, db);
+
+        node.state().clone()
+    }
+
+    /// Signs a challenge with a single public key
+    fn identifier(key: &PrivKey, sig_msg: [u8; 32], timestamp: DateTime<Utc>) -> Identifier {
+        Identifier {
+            identities: vec![Identity {
+                key: key.public_key(),
+                signature: SignedMsg::from_identity(&sig_msg, &timestamp).sign(key),
+                delegation: None,
+            }],
+            timestamp,
+            sig_msg,
+        }
+    }
+
+    /// Connects a client identified as `key`
+    fn connect(state: &Arc<NodeState>, key: &PrivKey) -> TestClient {
+        let (outgoing, received) = mpsc::unbounded();
+        let (canceller, cancelled) = mpsc::unbounded();
+        let mut client = Client::new(
+            state.clone(),
+            outgoing,
+            canceller,
+            "127.0.0.1:1".parse().unwrap(),
+            Transport::Quic,
+        );
+
+        let challenge = client.challenge();
+        client.identify(identifier(key, challenge, Utc::now())).unwrap();
+
+        TestClient {
+            client,
+            received,
+            _cancelled: cancelled,
+        }
+    }
+
+    /// Waits for the next message with a header sent to the client
+    async fn expect(client: &mut TestClient, header: MessageHeader) -> Message {
+        let wait = async {
+            loop {
+                let msg = client.received.next().await.unwrap();
+                if msg.header == header {
+                    return msg;
+                }
+            }
+        };
+
+        tokio::time::timeout(StdDuration::from_secs(5), wait).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn rotation_is_announced_to_contacts() {
+        let state = node();
+        let (alice_key, bob_key) = (PrivKey::random(), PrivKey::random());
+        let (alice, bob) = (alice_key.public_key(), bob_key.public_key());
+        let mut alice_client = connect(&state, &alice_key);
+        let mut bob_client = connect(&state, &bob_key);
+
+        let request = CommunicationRequest { from: alice, public_key: bob };
+        let content = DirectContent::Sealed(SealedEnvelope::seal(&alice_key, &bob, &"hello").unwrap());
+        let direct = DirectMessage { from: alice, to: bob, content };
+        alice_client
+            .client
+            .handle_message(Message::new(MessageHeader::CommunicationRequest, &request).unwrap())
+            .await
+            .unwrap();
+        alice_client
+            .client
+            .handle_message(Message::new(MessageHeader::DirectMessage, &direct).unwrap())
+            .await
+            .unwrap();
+        expect(&mut bob_client, MessageHeader::DirectMessage).await;
+
+        let new_key = PrivKey::random().public_key();
+        let rotation = KeyRotation::new(&alice_key, new_key, Utc::now());
+        alice_client
+            .client
+            .handle_message(Message::new(MessageHeader::RotateKey, &rotation).unwrap())
+            .await
+            .unwrap();
+
+        let announced = expect(&mut bob_client, MessageHeader::KeyRotated).await;
+        let announced = serde_cbor::value::from_value::<KeyRotation>(announced.object).unwrap();
+        assert_eq!(announced.old, alice);
+        assert_eq!(announced.new, new_key);
+        assert_eq!(state.db.get_contacts(&bob).await.unwrap(), vec![alice]);
+    }
+
+    #[tokio::test]
+    async fn countersignature_needs_stored_delegation() {
+        let state = node();
+        let (device_key, master_key, other_master) = (PrivKey::random(), PrivKey::random(), PrivKey::random());
+        let device = device_key.public_key();
+
+        let first = KeyRotation::new(&device_key, PrivKey::random().public_key(), Utc::now());
+        state.rotate(&first).await.unwrap();
+
+        // A master key that never added the device cannot take over the rotation
+        let mut hijack = KeyRotation::new(&device_key, PrivKey::random().public_key(), Utc::now());
+        hijack.countersign(&other_master, Delegation::new(&other_master, device, Utc::now(), None));
+        assert!(hijack.verify());
+        assert!(matches!(state.rotate(&hijack).await, Err(DbError::Conflict)));
+
+        let delegation = Delegation::new(&master_key, device, Utc::now(), None);
+        let account = SubAccount {
+            pub_key: device,
+            publicity: Publicity::Private,
+            delegation: Some(delegation),
+        };
+        state.db.add_subaccount(&master_key.public_key(), &account).await.unwrap();
+
+        let mut approved = KeyRotation::new(&device_key, PrivKey::random().public_key(), Utc::now());
+        approved.countersign(&master_key, delegation);
+        state.rotate(&approved).await.unwrap();
+        assert_eq!(state.rotation(&device).unwrap().new, approved.new);
+    }
+
+    /// Stores a guild where `manager` can manage roles and channels, but is not an administrator
+    async fn managed_guild(state: &Arc<NodeState>, owner: PubKey, manager: PubKey) -> Guild {
+        let mut guild = Guild::new([1u8; 32], "guild".to_string(), owner);
+        guild.roles.push(Role {
+            id: 1,
+            name: "admin".to_string(),
+            permissions: Permissions::ADMINISTRATOR,
+        });
+        guild.roles.push(Role {
+            id: 2,
+            name: "manager".to_string(),
+            permissions: Permissions::MANAGE_ROLES | Permissions::MANAGE_CHANNELS,
+        });
+        guild.members.push(GuildMember {
+            key: manager,
+            roles: HashSet::from([2]),
+        });
+        state.db.create_guild(&guild).await.unwrap();
+
+        guild
+    }
+
+    /// Waits for the next error sent to the client and returns its code
+    async fn expect_error(client: &mut TestClient) -> ErrorCode {
+        let msg = expect(client, MessageHeader::Error).await;
+
+        serde_cbor::value::from_value::<ErrorMsg>(msg.object).unwrap().code
+    }
+
+    #[tokio::test]
+    async fn roles_above_the_member_cannot_be_replaced() {
+        let state = node();
+        let manager_key = PrivKey::random();
+        let manager = manager_key.public_key();
+        let guild = managed_guild(&state, PrivKey::random().public_key(), manager).await;
+        let mut client = connect(&state, &manager_key);
+
+        let set_role = GuildSetRole {
+            guild: guild.id,
+            from: manager,
+            id: Some(1),
+            name: "demoted".to_string(),
+            permissions: Permissions::empty(),
+        };
+        client
+            .client
+            .handle_message(Message::new(MessageHeader::GuildSetRole, &set_role).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(expect_error(&mut client).await, ErrorCode::Unauthorized);
+        let stored = state.db.get_guild(&guild.id).await.unwrap();
+        assert_eq!(stored.role(1).unwrap().permissions, Permissions::ADMINISTRATOR);
+    }
+
+    #[tokio::test]
+    async fn overwrites_cannot_give_missing_permissions() {
+        let state = node();
+        let manager_key = PrivKey::random();
+        let manager = manager_key.public_key();
+        let guild = managed_guild(&state, PrivKey::random().public_key(), manager).await;
+        let mut client = connect(&state, &manager_key);
+
+        let set_channel = GuildSetChannel {
+            guild: guild.id,
+            from: manager,
+            id: Some(0),
+            name: "general".to_string(),
+            overwrites: vec![PermissionOverwrite {
+                role: 2,
+                allow: Permissions::KICK_MEMBERS,
+                deny: Permissions::empty(),
+            }],
+        };
+        client
+            .client
+            .handle_message(Message::new(MessageHeader::GuildSetChannel, &set_channel).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(expect_error(&mut client).await, ErrorCode::Unauthorized);
+        let stored = state.db.get_guild(&guild.id).await.unwrap();
+        assert!(stored.channel(0).unwrap().overwrites.is_empty());
+    }
+
+    #[tokio::test]
+    async fn members_above_the_kicker_cannot_be_kicked() {
+        let state = node();
+        let manager_key = PrivKey::random();
+        let manager = manager_key.public_key();
+        let (admin, member) = (PrivKey::random().public_key(), PrivKey::random().public_key());
+        let guild = managed_guild(&state, PrivKey::random().public_key(), manager).await;
+
+        let mut updated = guild.clone();
+        updated.roles[2].permissions = Permissions::KICK_MEMBERS;
+        updated.members.push(GuildMember {
+            key: admin,
+            roles: HashSet::from([1]),
+        });
+        updated.members.push(GuildMember {
+            key: member,
+            roles: HashSet::new(),
+        });
+        state.db.update_guild(&guild, &updated).await.unwrap();
+        let mut client = connect(&state, &manager_key);
+
+        let kick = GuildKick {
+            guild: guild.id,
+            from: manager,
+            member: admin,
+        };
+        client
+            .client
+            .handle_message(Message::new(MessageHeader::GuildKick, &kick).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(expect_error(&mut client).await, ErrorCode::Unauthorized);
+
+        let kick = GuildKick { member, ..kick };
+        client
+            .client
+            .handle_message(Message::new(MessageHeader::GuildKick, &kick).unwrap())
+            .await
+            .unwrap();
+        let stored = state.db.get_guild(&guild.id).await.unwrap();
+        assert!(stored.is_member(&admin) && !stored.is_member(&member));
+    }
+
+    #[tokio::test]
+    async fn identifies_outside_the_clock_skew_are_rejected() {
+        let state = node();
+        let key = PrivKey::random();
+        let mut client = connect(&state, &key).client;
+        let skew = Duration::seconds(state.config().identify.clock_skew_secs as i64 + 60);
+
+        for timestamp in [Utc::now() - skew, Utc::now() + skew] {
+            let challenge = client.challenge();
+            assert!(matches!(
+                client.identify(identifier(&key, challenge, timestamp)),
+                Err(IdentifyError::StaleTimestamp)
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn challenges_cannot_be_signed_twice() {
+        let state = node();
+        let key = PrivKey::random();
+        let mut client = connect(&state, &key).client;
+
+        // Every attempt consumes the challenge, even a rejected one
+        let challenge = client.challenge();
+        let mut signed = identifier(&key, challenge, Utc::now());
+        signed.timestamp += Duration::seconds(1);
+        assert!(matches!(client.identify(signed.clone()), Err(IdentifyError::InvalidSignature)));
+        signed.timestamp -= Duration::seconds(1);
+        assert!(matches!(client.identify(signed), Err(IdentifyError::InvalidChallenge)));
+
+        let challenge = client.challenge();
+        let signed = identifier(&key, challenge, Utc::now());
+        client.identify(signed.clone()).unwrap();
+        assert!(matches!(client.identify(signed), Err(IdentifyError::InvalidChallenge)));
+    }
+
+    #[tokio::test]
+    async fn malformed_stream_identifies_are_answered() {
+        let state = node();
+        let mut client = connect(&state, &PrivKey::random());
+
+        let malformed = Message::new(MessageHeader::StreamIdentify, &"not a stream type").unwrap();
+        client.client.handle_message(malformed).await.unwrap();
+
+        assert_eq!(expect_error(&mut client).await, ErrorCode::Malformed);
+    }
+}
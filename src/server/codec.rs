@@ -0,0 +1,159 @@
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{data::Message, error::FrameError};
+
+/// The default maximum size of the body of a single frame in bytes
+pub const MAX_FRAME_SIZE: u32 = 32768;
+/// The maximum size of the body of a frame written by the node, or exchanged between nodes.
+/// Leaves room for the node to wrap a client frame of [`MAX_FRAME_SIZE`] bytes
+pub const MAX_NODE_FRAME_SIZE: u32 = MAX_FRAME_SIZE + 4096;
+
+/// Reads length prefixed CBOR messages from a stream.
+/// Every frame is a little endian `u32` containing the size of the body, followed by the body.
+pub struct FrameReader<R> {
+    stream: R,
+    max_size: u32,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    pub fn new(stream: R, max_size: u32) -> Self {
+        Self { stream, max_size }
+    }
+    /// Changes the maximum size of the next frames
+    pub fn set_max_size(&mut self, max_size: u32) {
+        self.max_size = max_size;
+    }
+    /// Reads the body of a single frame. Returns [`None`] if the stream ended between two frames
+    pub async fn read_frame(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        let mut prefix = [0u8; 4];
+        let mut read = 0;
+
+        // Reading the length prefix
+        while read < prefix.len() {
+            let n = self.stream.read(&mut prefix[read..]).await?;
+
+            if n == 0 {
+                return match read {
+                    0 => Ok(None),
+                    _ => Err(FrameError::Truncated),
+                };
+            }
+            read += n;
+        }
+
+        let size = LittleEndian::read_u32(&prefix);
+        if size > self.max_size {
+            return Err(FrameError::Oversized {
+                size,
+                max: self.max_size,
+            });
+        }
+
+        // Reading the body
+        let mut buf = vec![0u8; size as usize];
+        match self.stream.read_exact(&mut buf).await {
+            Ok(()) => Ok(Some(buf)),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Err(FrameError::Truncated),
+            Err(e) => Err(e.into()),
+        }
+    }
+    /// Reads and deserializes a single message. Returns [`None`] if the stream ended between two frames
+    pub async fn read(&mut self) -> Result<Option<Message>, FrameError> {
+        match self.read_frame().await? {
+            Some(buf) => Ok(Some(serde_cbor::from_slice::<Message>(&buf)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Writes length prefixed CBOR messages to a stream. The counterpart of [`FrameReader`]
+pub struct FrameWriter<W> {
+    stream: W,
+    max_size: u32,
+}
+
+impl<W: AsyncWrite + Unpin> FrameWriter<W> {
+    pub fn new(stream: W, max_size: u32) -> Self {
+        Self { stream, max_size }
+    }
+    /// Writes the body of a single frame
+    pub async fn write_frame(&mut self, body: &[u8]) -> Result<(), FrameError> {
+        let size = match u32::try_from(body.len()) {
+            Ok(v) if v <= self.max_size => v,
+            _ => {
+                return Err(FrameError::Oversized {
+                    size: u32::try_from(body.len()).unwrap_or(u32::MAX),
+                    max: self.max_size,
+                })
+            }
+        };
+
+        let mut buf = Vec::with_capacity(body.len() + 4);
+        // Writing to a vector cannot fail
+        let _ = buf.write_u32::<LittleEndian>(size);
+        buf.extend_from_slice(body);
+
+        self.stream.write_all(&buf).await?;
+        Ok(())
+    }
+    /// Serializes and writes a single message
+    pub async fn write(&mut self, msg: &Message) -> Result<(), FrameError> {
+        let buf = serde_cbor::to_vec(msg)?;
+
+        self.write_frame(&buf).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::io::Cursor;
+
+    use crate::data::MessageHeader;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn frames_round_trip() {
+        let mut writer = FrameWriter::new(Cursor::new(Vec::new()), MAX_FRAME_SIZE);
+        writer.write(&Message::new(MessageHeader::Error, &1u64).unwrap()).await.unwrap();
+        writer.write_frame(&[]).await.unwrap();
+
+        let mut reader = FrameReader::new(Cursor::new(writer.stream.into_inner()), MAX_FRAME_SIZE);
+        let msg = reader.read().await.unwrap().unwrap();
+        assert!(msg.header == MessageHeader::Error && msg.object == serde_cbor::Value::Integer(1));
+        assert_eq!(reader.read_frame().await.unwrap(), Some(Vec::new()));
+        assert!(reader.read().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn oversized_frames_are_rejected() {
+        let mut writer = FrameWriter::new(Cursor::new(Vec::new()), 4);
+        assert!(matches!(
+            writer.write_frame(&[0; 5]).await,
+            Err(FrameError::Oversized { size: 5, max: 4 })
+        ));
+
+        // The body is not read, only the prefix announcing it
+        let mut reader = FrameReader::new(Cursor::new(vec![5, 0, 0, 0]), 4);
+        assert!(matches!(
+            reader.read_frame().await,
+            Err(FrameError::Oversized { size: 5, max: 4 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn truncated_frames_are_rejected() {
+        let mut reader = FrameReader::new(Cursor::new(vec![5, 0]), MAX_FRAME_SIZE);
+        assert!(matches!(reader.read_frame().await, Err(FrameError::Truncated)));
+
+        let mut reader = FrameReader::new(Cursor::new(vec![5, 0, 0, 0, 1, 2]), MAX_FRAME_SIZE);
+        assert!(matches!(reader.read_frame().await, Err(FrameError::Truncated)));
+    }
+
+    #[tokio::test]
+    async fn malformed_bodies_are_rejected() {
+        let mut reader = FrameReader::new(Cursor::new(vec![1, 0, 0, 0, 0xff]), MAX_FRAME_SIZE);
+        assert!(matches!(reader.read().await, Err(FrameError::DeserializeError(_))));
+    }
+}
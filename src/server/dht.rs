@@ -0,0 +1,587 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use futures::{channel::oneshot, future::join_all};
+use rand::{rngs::OsRng, RngCore};
+use tokio::sync::watch;
+
+use crate::{
+    config::Configuration,
+    data::{
+        crypto::PubKey, dht_id, DhtFind, DhtId, DhtResponse, DhtStore, HomeEntry, HomeRecord, Message, MessageHeader,
+        PeerInfo,
+    },
+};
+
+use super::{
+    node::NodeState,
+    peer::{link_once, request_peer},
+};
+
+/// Maximum amount of nodes in a bucket, and amount of nodes returned by a lookup
+pub const K: usize = 20;
+/// Amount of nodes queried at the same time by a lookup
+const ALPHA: usize = 3;
+/// Amount of nodes an entry is stored on, besides the node hosting the public key
+const REPLICATION: usize = 3;
+/// Maximum amount of entries stored for other nodes
+const MAX_ENTRIES: usize = 65536;
+/// Time a node has to answer a request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time a lookup that found nothing is remembered, so messages to unknown public keys do not flood the DHT
+const MISS_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Returns the XOR distance between two positions
+fn distance(a: &DhtId, b: &DhtId) -> DhtId {
+    let mut d = [0u8; 32];
+    for (i, v) in d.iter_mut().enumerate() {
+        *v = a[i] ^ b[i];
+    }
+
+    d
+}
+
+/// An entry stored by the node
+struct StoredEntry {
+    entry: HomeEntry,
+    expires: DateTime<Utc>,
+}
+
+/// Kademlia-style DHT mapping public keys to the nodes hosting them. Positions are blake3 hashes of public keys,
+/// and entries are stored on the nodes whose keys are the closest by XOR distance
+pub struct Dht {
+    /// Position of this node
+    id: DhtId,
+    /// Known nodes, by the amount of leading bits their position shares with this node
+    buckets: RwLock<Vec<Vec<PeerInfo>>>,
+    entries: RwLock<HashMap<DhtId, StoredEntry>>,
+    /// Entries of the public keys hosted by this node, stored again on other nodes until they expire
+    published: RwLock<HashMap<DhtId, StoredEntry>>,
+    /// Recent lookups that found nothing
+    misses: Mutex<HashMap<DhtId, Instant>>,
+    /// Requests waiting for a response, by ID, with the key of the queried node
+    pending: Mutex<HashMap<u64, (PubKey, oneshot::Sender<DhtResponse>)>>,
+    next_request: AtomicU64,
+}
+
+impl Dht {
+    pub fn new(node: &PubKey) -> Self {
+        Self {
+            id: dht_id(node),
+            buckets: RwLock::new(vec![Vec::new(); 256]),
+            entries: RwLock::default(),
+            published: RwLock::default(),
+            misses: Mutex::default(),
+            pending: Mutex::default(),
+            next_request: AtomicU64::new(OsRng.next_u64()),
+        }
+    }
+    /// Adds a node to the routing table, or moves it to the end of its bucket if it is already known.
+    /// Nodes are not added to full buckets, the nodes known for longer are kept
+    pub fn insert(&self, peer: PeerInfo) {
+        let id = dht_id(&peer.key);
+        let d = distance(&self.id, &id);
+        let index = d.iter().enumerate().find(|(_, b)| **b != 0).map(|(i, b)| i * 8 + b.leading_zeros() as usize);
+        let index = match index {
+            Some(v) => v,
+            // This node
+            None => return,
+        };
+
+        let mut buckets = self.buckets.write().unwrap();
+        let bucket = &mut buckets[index];
+
+        if let Some(i) = bucket.iter().position(|p| p.key == peer.key) {
+            bucket.remove(i);
+        } else if bucket.len() >= K {
+            return;
+        }
+        bucket.push(peer);
+    }
+    /// Removes a node that did not answer from the routing table
+    pub fn remove(&self, key: &PubKey) {
+        for bucket in self.buckets.write().unwrap().iter_mut() {
+            bucket.retain(|p| p.key != *key);
+        }
+    }
+    /// Returns the `count` known nodes closest to a position
+    pub fn closest(&self, target: &DhtId, count: usize) -> Vec<PeerInfo> {
+        let mut peers: Vec<PeerInfo> = self.buckets.read().unwrap().iter().flatten().cloned().collect();
+
+        peers.sort_by_key(|p| distance(target, &dht_id(&p.key)));
+        peers.truncate(count);
+        peers
+    }
+    /// Stores an entry if its record is valid and newer than the stored one. Returns false if it is rejected
+    pub fn store(&self, entry: HomeEntry, config: &Configuration) -> bool {
+        let expires = match valid_until(&entry.record, config) {
+            Some(v) => v,
+            None => return false,
+        };
+        let id = dht_id(&entry.record.key);
+        let mut entries = self.entries.write().unwrap();
+
+        match entries.get(&id) {
+            Some(old) if old.entry.record.timestamp >= entry.record.timestamp => return true,
+            None if entries.len() >= MAX_ENTRIES => return false,
+            _ => {}
+        }
+        entries.insert(id, StoredEntry { entry, expires });
+        self.misses.lock().unwrap().remove(&id);
+
+        true
+    }
+    /// Returns the stored entry at a position, if it has not expired
+    pub fn get(&self, target: &DhtId) -> Option<HomeEntry> {
+        match self.entries.read().unwrap().get(target) {
+            Some(v) if v.expires > Utc::now() => Some(v.entry.clone()),
+            _ => None,
+        }
+    }
+    /// Returns the record of a public key hosted by this node, if it published one that has not expired
+    pub fn published_record(&self, key: &PubKey) -> Option<HomeRecord> {
+        match self.published.read().unwrap().get(&dht_id(key)) {
+            Some(v) if v.expires > Utc::now() => Some(v.entry.record.clone()),
+            _ => None,
+        }
+    }
+    /// Remembers an entry of a public key hosted by this node, so it is stored again on other nodes
+    fn publish_local(&self, entry: HomeEntry, expires: DateTime<Utc>) {
+        let id = dht_id(&entry.record.key);
+
+        self.published.write().unwrap().insert(id, StoredEntry { entry, expires });
+    }
+    /// Deletes the expired entries
+    fn expire(&self) {
+        let now = Utc::now();
+
+        self.entries.write().unwrap().retain(|_, v| v.expires > now);
+        self.published.write().unwrap().retain(|_, v| v.expires > now);
+        self.misses.lock().unwrap().retain(|_, t| t.elapsed() < MISS_TIMEOUT);
+    }
+    /// Completes the request the response answers, if `remote` is the node it was sent to
+    pub fn resolve(&self, remote: &PubKey, response: DhtResponse) {
+        let mut pending = self.pending.lock().unwrap();
+
+        if pending.get(&response.id).map(|(node, _)| node) != Some(remote) {
+            return;
+        }
+        if let Some((_, sender)) = pending.remove(&response.id) {
+            let _ = sender.send(response);
+        }
+    }
+    fn next_id(&self) -> u64 {
+        self.next_request.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+/// Returns the time a record expires, or [`None`] if the record is not valid now
+pub(super) fn valid_until(record: &HomeRecord, config: &Configuration) -> Option<DateTime<Utc>> {
+    let now = Utc::now();
+    let skew = chrono::Duration::seconds(config.identify.clock_skew_secs as i64);
+    let expires = record.timestamp + chrono::Duration::hours(config.peers.home_record_ttl_hours as i64);
+
+    if record.timestamp > now + skew || expires <= now || !record.verify() {
+        return None;
+    }
+
+    Some(expires)
+}
+
+/// Sends a request to a node and waits for its response.
+/// Nodes that do not answer are removed from the routing table
+async fn request(state: &Arc<NodeState>, peer: &PeerInfo, id: u64, msg: Message) -> Option<DhtResponse> {
+    let (send, recv) = oneshot::channel();
+    state.dht.pending.lock().unwrap().insert(id, (peer.key, send));
+
+    let result = tokio::time::timeout(REQUEST_TIMEOUT, request_peer(state, peer, msg, recv)).await;
+    state.dht.pending.lock().unwrap().remove(&id);
+
+    match result {
+        Ok(Ok(v)) => Some(v),
+        Ok(Err(e)) => {
+            tracing::debug!("DHT request to node {:?} failed: {}", peer.key, e);
+            state.dht.remove(&peer.key);
+            None
+        }
+        Err(_) => {
+            tracing::debug!("DHT request to node {:?} timed out", peer.key);
+            state.dht.remove(&peer.key);
+            None
+        }
+    }
+}
+
+/// Asks a node for the entry at a position, and for the nodes it knows closest to it
+async fn find(state: &Arc<NodeState>, peer: &PeerInfo, target: DhtId) -> Option<DhtResponse> {
+    let id = state.dht.next_id();
+    let msg = Message::new(MessageHeader::DhtFind, &DhtFind { id, target }).ok()?;
+
+    request(state, peer, id, msg).await
+}
+
+/// Queries the nodes closest to a position, getting closer at every step.
+/// Stops at the first valid entry if `find_entry` is set, otherwise returns the closest nodes that answered
+async fn iterate(state: &Arc<NodeState>, target: DhtId, find_entry: bool) -> (Option<HomeEntry>, Vec<PeerInfo>) {
+    let mut shortlist = state.dht.closest(&target, K);
+    let mut queried = HashSet::new();
+    let mut answered = Vec::new();
+
+    loop {
+        let batch: Vec<PeerInfo> = shortlist
+            .iter()
+            .filter(|p| !queried.contains(&p.key))
+            .take(ALPHA)
+            .cloned()
+            .collect();
+        if batch.is_empty() {
+            break;
+        }
+        queried.extend(batch.iter().map(|p| p.key));
+
+        let responses = join_all(batch.iter().map(|p| find(state, p, target))).await;
+        let config = state.config();
+
+        for (peer, response) in batch.into_iter().zip(responses) {
+            let response = match response {
+                Some(v) => v,
+                None => continue,
+            };
+
+            if find_entry {
+                if let Some(entry) = response.entry {
+                    if dht_id(&entry.record.key) == target && valid_until(&entry.record, &config).is_some() {
+                        return (Some(entry), answered);
+                    }
+                }
+            }
+            answered.push(peer);
+
+            for p in response.closer {
+                if p.key != state.node_pubkey && !shortlist.iter().any(|s| s.key == p.key) {
+                    state.dht.insert(p.clone());
+                    shortlist.push(p);
+                }
+            }
+        }
+
+        shortlist.sort_by_key(|p| distance(&target, &dht_id(&p.key)));
+        shortlist.truncate(K);
+    }
+
+    answered.sort_by_key(|p| distance(&target, &dht_id(&p.key)));
+    answered.truncate(K);
+    (None, answered)
+}
+
+/// Returns the entry of the node hosting a public key, from this node or from the DHT
+pub async fn lookup(state: &Arc<NodeState>, key: &PubKey) -> Option<HomeEntry> {
+    let target = dht_id(key);
+
+    if let Some(entry) = state.dht.get(&target) {
+        return Some(entry);
+    }
+    if let Some(time) = state.dht.misses.lock().unwrap().get(&target) {
+        if time.elapsed() < MISS_TIMEOUT {
+            return None;
+        }
+    }
+
+    match iterate(state, target, true).await {
+        (Some(entry), _) => {
+            // Kept as a cache until it expires
+            state.dht.store(entry.clone(), &state.config());
+            Some(entry)
+        }
+        (None, _) => {
+            state.dht.misses.lock().unwrap().insert(target, Instant::now());
+            None
+        }
+    }
+}
+
+/// Stores the entry of a public key hosted by this node, on this node and on the nodes closest to it.
+/// The entry is stored again every hour until it expires
+pub async fn publish(state: &Arc<NodeState>, entry: HomeEntry) -> bool {
+    let config = state.config();
+    let expires = match valid_until(&entry.record, &config) {
+        Some(v) => v,
+        None => return false,
+    };
+
+    state.dht.store(entry.clone(), &config);
+    state.dht.publish_local(entry.clone(), expires);
+
+    replicate(state, entry).await;
+    true
+}
+
+/// Stores an entry on the nodes closest to it
+async fn replicate(state: &Arc<NodeState>, entry: HomeEntry) {
+    let (_, closest) = iterate(state, dht_id(&entry.record.key), false).await;
+
+    let requests = closest.iter().take(REPLICATION).map(|peer| {
+        let id = state.dht.next_id();
+        let store = DhtStore {
+            id,
+            entry: entry.clone(),
+        };
+
+        async move {
+            match Message::new(MessageHeader::DhtStore, &store) {
+                Ok(msg) => request(state, peer, id, msg).await.is_some(),
+                Err(_) => false,
+            }
+        }
+    });
+    let stored = join_all(requests).await.into_iter().filter(|v| *v).count();
+
+    tracing::debug!("Stored home entry of {:?} on {} nodes", entry.record.key, stored);
+}
+
+/// Forwards a message to the node hosting the recipient according to the DHT, linking with it if needed.
+/// Returns false if no other node hosts the recipient, or if the node cannot be reached
+pub async fn forward_home(state: &Arc<NodeState>, recipient: &PubKey, msg: &Message) -> bool {
+    let entry = match lookup(state, recipient).await {
+        Some(v) => v,
+        None => return false,
+    };
+    let node = entry.record.node;
+    if node == state.node_pubkey {
+        return false;
+    }
+
+    if !state.peers.is_linked(&node) {
+        // Messages to the same node wait on the same link attempt
+        link_once(state, entry.address, node);
+
+        if !state.peers.wait_linked(&node, REQUEST_TIMEOUT).await {
+            return false;
+        }
+    }
+
+    state.peers.forward_via(&node, recipient, msg)
+}
+
+/// Deletes expired entries and stores the entries of the public keys hosted by this node again every hour.
+/// Runs until `shutdown` changes
+pub async fn maintain_dht(state: Arc<NodeState>, mut shutdown: watch::Receiver<bool>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(3600));
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.changed() => break,
+        }
+
+        state.dht.expire();
+
+        let published: Vec<HomeEntry> = state
+            .dht
+            .published
+            .read()
+            .unwrap()
+            .values()
+            .map(|v| v.entry.clone())
+            .collect();
+        for entry in published {
+            tokio::select! {
+                _ = replicate(&state, entry) => {}
+                _ = shutdown.changed() => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::crypto::PrivKey;
+
+    use super::*;
+
+    fn peer(key: PubKey) -> PeerInfo {
+        PeerInfo {
+            key,
+            address: "127.0.0.1:1".to_string(),
+            last_seen: Utc::now(),
+        }
+    }
+
+    fn entry(key: &PrivKey, timestamp: DateTime<Utc>) -> HomeEntry {
+        HomeEntry {
+            record: HomeRecord::new(key, PrivKey::random().public_key(), timestamp),
+            address: "127.0.0.1:1".to_string(),
+        }
+    }
+
+    #[test]
+    fn distance_is_the_xor_of_positions() {
+        let (a, b) = ([0b1010_0000; 32], [0b0110_0000; 32]);
+
+        assert_eq!(distance(&a, &b), [0b1100_0000; 32]);
+        assert_eq!(distance(&a, &b), distance(&b, &a));
+        assert_eq!(distance(&a, &a), [0; 32]);
+    }
+
+    #[test]
+    fn nodes_are_inserted_in_the_bucket_of_their_distance() {
+        let node = PrivKey::random().public_key();
+        let dht = Dht::new(&node);
+
+        // This node is never in its own routing table
+        dht.insert(peer(node));
+        assert!(dht.closest(&dht.id, K).is_empty());
+
+        // More keys than a bucket holds whose position differs from this node from the first bit,
+        // and a key whose position differs from the second bit
+        let mut far = Vec::new();
+        let mut near = None;
+        while far.len() <= K || near.is_none() {
+            let key = PrivKey::random().public_key();
+            match distance(&dht.id, &dht_id(&key))[0] {
+                d if d & 0x80 != 0 => far.push(key),
+                0x40..=0x7f => near = Some(key),
+                _ => {}
+            }
+        }
+        let near = near.unwrap();
+
+        for key in &far {
+            dht.insert(peer(*key));
+        }
+        dht.insert(peer(near));
+        dht.insert(peer(far[0]));
+
+        let buckets = dht.buckets.read().unwrap();
+        // Full buckets keep the nodes known for longer, known nodes move to the end
+        assert_eq!(buckets[0].len(), K);
+        assert!(buckets[0].iter().all(|p| p.key != far[K]));
+        assert!(buckets[0].last().unwrap().key == far[0]);
+        assert!(buckets[1].len() == 1 && buckets[1][0].key == near);
+    }
+
+    #[test]
+    fn closest_nodes_are_sorted_by_distance() {
+        let dht = Dht::new(&PrivKey::random().public_key());
+        for _ in 0..10 {
+            dht.insert(peer(PrivKey::random().public_key()));
+        }
+        let target = [7u8; 32];
+
+        let closest = dht.closest(&target, 5);
+        let distances: Vec<DhtId> = closest.iter().map(|p| distance(&target, &dht_id(&p.key))).collect();
+        assert_eq!(closest.len(), 5);
+        assert!(distances.windows(2).all(|w| w[0] <= w[1]));
+    }
+
+    #[test]
+    fn records_are_only_valid_in_their_lifetime() {
+        let config = Configuration::default();
+        let key = PrivKey::random();
+        let skew = chrono::Duration::seconds(config.identify.clock_skew_secs as i64);
+        let ttl = chrono::Duration::hours(config.peers.home_record_ttl_hours as i64);
+        let now = Utc::now();
+
+        let record = entry(&key, now).record;
+        assert!(valid_until(&record, &config).is_some_and(|t| t == now + ttl));
+        assert!(valid_until(&entry(&key, now + skew / 2).record, &config).is_some());
+        assert!(valid_until(&entry(&key, now + skew * 2).record, &config).is_none());
+        assert!(valid_until(&entry(&key, now - ttl).record, &config).is_none());
+
+        let mut tampered = record;
+        tampered.node = PrivKey::random().public_key();
+        assert!(valid_until(&tampered, &config).is_none());
+    }
+
+    #[test]
+    fn newer_records_replace_stored_ones() {
+        let config = Configuration::default();
+        let dht = Dht::new(&PrivKey::random().public_key());
+        let key = PrivKey::random();
+        let id = dht_id(&key.public_key());
+
+        let old = entry(&key, Utc::now() - chrono::Duration::minutes(1));
+        let new = entry(&key, Utc::now());
+        assert!(dht.store(new.clone(), &config));
+        // Accepted but ignored, the stored record is newer
+        assert!(dht.store(old, &config));
+        assert!(dht.get(&id).unwrap().record.node == new.record.node);
+
+        let newer = entry(&key, Utc::now() + chrono::Duration::seconds(1));
+        assert!(dht.store(newer.clone(), &config));
+        assert!(dht.get(&id).unwrap().record.node == newer.record.node);
+
+        let mut forged = entry(&key, Utc::now() + chrono::Duration::seconds(2));
+        forged.record.node = PrivKey::random().public_key();
+        assert!(!dht.store(forged, &config));
+        assert!(dht.get(&id).unwrap().record.node == newer.record.node);
+    }
+
+    #[test]
+    fn stored_entries_are_capped() {
+        let config = Configuration::default();
+        let dht = Dht::new(&PrivKey::random().public_key());
+        let key = PrivKey::random();
+        let stored = entry(&key, Utc::now());
+        assert!(dht.store(stored.clone(), &config));
+
+        {
+            let mut entries = dht.entries.write().unwrap();
+            for i in 1..MAX_ENTRIES as u32 {
+                let mut id = [0u8; 32];
+                id[..4].copy_from_slice(&i.to_le_bytes());
+                let expires = Utc::now() + chrono::Duration::hours(1);
+                entries.insert(id, StoredEntry { entry: stored.clone(), expires });
+            }
+        }
+
+        assert!(!dht.store(entry(&PrivKey::random(), Utc::now()), &config));
+        // Entries that are already stored can still be replaced
+        assert!(dht.store(entry(&key, Utc::now() + chrono::Duration::seconds(1)), &config));
+    }
+
+    #[test]
+    fn expired_entries_are_not_returned() {
+        let dht = Dht::new(&PrivKey::random().public_key());
+        let key = PrivKey::random();
+        let id = dht_id(&key.public_key());
+
+        dht.entries.write().unwrap().insert(
+            id,
+            StoredEntry {
+                entry: entry(&key, Utc::now()),
+                expires: Utc::now() - chrono::Duration::seconds(1),
+            },
+        );
+        assert!(dht.get(&id).is_none());
+
+        dht.expire();
+        assert!(dht.entries.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn only_the_queried_node_resolves_a_request() {
+        let dht = Dht::new(&PrivKey::random().public_key());
+        let (queried, other) = (PrivKey::random().public_key(), PrivKey::random().public_key());
+        let (send, mut recv) = oneshot::channel();
+        dht.pending.lock().unwrap().insert(1, (queried, send));
+        let response = DhtResponse {
+            id: 1,
+            entry: None,
+            closer: Vec::new(),
+        };
+
+        dht.resolve(&other, response.clone());
+        assert!(recv.try_recv().unwrap().is_none());
+
+        dht.resolve(&queried, response);
+        assert!(recv.try_recv().unwrap().is_some());
+    }
+}
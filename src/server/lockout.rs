@@ -0,0 +1,64 @@
+use std::{collections::HashMap, net::IpAddr, sync::Mutex};
+
+use chrono::{DateTime, Duration, Utc};
+
+/// Failed administrator authentications by IP address.
+/// An address is locked out once it failed too many times, until its last failure is older than the lockout
+#[derive(Default)]
+pub struct AdminLockout {
+    /// Amount of failures and time of the last failure of every address
+    failures: Mutex<HashMap<IpAddr, (u32, DateTime<Utc>)>>,
+}
+
+impl AdminLockout {
+    /// Returns true if the address failed at least `max_failures` times during the last `lockout`
+    pub fn is_locked(&self, address: IpAddr, max_failures: u32, lockout: Duration) -> bool {
+        match self.failures.lock().unwrap().get(&address) {
+            Some((count, last)) => *count >= max_failures && *last + lockout > Utc::now(),
+            None => false,
+        }
+    }
+    /// Records a failure of the address, returning the amount of failures during the last `lockout`
+    pub fn fail(&self, address: IpAddr, lockout: Duration) -> u32 {
+        let mut failures = self.failures.lock().unwrap();
+        let now = Utc::now();
+
+        // Forget the addresses that can try again
+        failures.retain(|_, (_, last)| *last + lockout > now);
+
+        let entry = failures.entry(address).or_insert((0, now));
+        entry.0 += 1;
+        entry.1 = now;
+
+        entry.0
+    }
+    /// Forgets the failures of an address that authenticated
+    pub fn clear(&self, address: IpAddr) {
+        self.failures.lock().unwrap().remove(&address);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_are_locked_after_too_many_failures() {
+        let lockout = AdminLockout::default();
+        let address: IpAddr = "127.0.0.1".parse().unwrap();
+        let other: IpAddr = "127.0.0.2".parse().unwrap();
+        let duration = Duration::minutes(5);
+
+        assert_eq!(lockout.fail(address, duration), 1);
+        assert!(!lockout.is_locked(address, 2, duration));
+        assert_eq!(lockout.fail(address, duration), 2);
+        assert!(lockout.is_locked(address, 2, duration));
+        assert!(!lockout.is_locked(other, 2, duration));
+
+        // The failures are forgotten once they are older than the lockout
+        assert!(!lockout.is_locked(address, 2, Duration::zero()));
+
+        lockout.clear(address);
+        assert!(!lockout.is_locked(address, 2, duration));
+    }
+}
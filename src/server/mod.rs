@@ -1,4 +1,17 @@
+pub use self::codec::*;
+pub use self::lockout::*;
 pub use self::node::*;
+pub use self::peer::*;
+pub use self::proxy::*;
+pub use self::registry::*;
+pub use self::replay::*;
 
 mod client;
+mod codec;
+mod dht;
+mod lockout;
 mod node;
+mod peer;
+mod proxy;
+mod registry;
+mod replay;
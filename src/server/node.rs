@@ -1,54 +1,509 @@
-use std::{error::Error, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    sync::{Arc, RwLock},
+};
 
-use futures::{channel::mpsc, StreamExt};
+use chrono::{DateTime, Duration, Utc};
+use futures::StreamExt;
 use quinn::{Endpoint, NewConnection, ServerConfig};
+use tokio::{net::TcpListener, sync::watch, task::JoinSet};
 
 use crate::{
-    config::Configuration,
-    db::{DbApi, EmptyDb},
+    config::{ConfigManager, Configuration, CONFIG_PATH},
+    data::{
+        crypto::{PrivKey, PubKey, SignedMsg},
+        ChannelId, Delegation, Group, Guild, KeyRotation, Message, MessageHeader, Permissions, Revocation,
+    },
+    db::DbApi,
+    error::DbError,
     helpers::ip::parse_ip,
 };
 
-use super::client::handle_connection;
+use super::{
+    client::handle_connection,
+    dht::{forward_home, maintain_dht, Dht},
+    lockout::AdminLockout,
+    peer::{connect_peer, maintain_peers, Peers},
+    proxy::{handle_websocket, ProxyFormat},
+    registry::Registry,
+    replay::ReplayCache,
+};
+
+/// State of a node shared by every connection
+pub struct NodeState {
+    /// Configuration for the node service. Replaced when the configuration is reloaded
+    config: RwLock<Arc<Configuration>>,
+    /// Database manager for the node
+    pub db: Arc<dyn DbApi>,
+    /// Public keys identified by the clients of the node
+    pub registry: Registry,
+    /// The blake3 hash of the admin password. Administration streams are rejected if [`None`]
+    pub admin_pass: Option<[u8; 32]>,
+    /// Addresses that failed to authenticate as administrator
+    pub admin_lockout: AdminLockout,
+    /// Public keys that cannot identify
+    bans: RwLock<HashSet<PubKey>>,
+    /// Time of the last revocation of every device, by user and device public keys
+    revocations: RwLock<HashMap<(PubKey, PubKey), DateTime<Utc>>>,
+    /// Public keys replaced by new keys, by old public key
+    rotations: RwLock<HashMap<PubKey, KeyRotation>>,
+    /// Recently accepted identifies
+    pub replay: ReplayCache,
+    /// Key identifying the node to other nodes
+    node_key: PrivKey,
+    pub node_pubkey: PubKey,
+    /// The rotation from the previous node key, if the node key was rotated
+    pub node_rotation: Option<KeyRotation>,
+    /// Links with other nodes
+    pub peers: Peers,
+    /// Routing table and records of the DHT finding the nodes hosting public keys
+    pub dht: Dht,
+    pub started_at: DateTime<Utc>,
+}
+
+impl NodeState {
+    /// Returns the current configuration of the node
+    pub fn config(&self) -> Arc<Configuration> {
+        self.config.read().unwrap().clone()
+    }
+    /// Reads the configuration file again. Services that already started keep their addresses and features
+    pub async fn reload_config(&self) -> Result<(), Box<dyn Error>> {
+        let (config, _) = ConfigManager::get_config(CONFIG_PATH).await?;
+        *self.config.write().unwrap() = config;
+
+        Ok(())
+    }
+    /// Signs a message with the node key
+    pub fn sign(&self, msg: &SignedMsg) -> [u8; 64] {
+        msg.sign(&self.node_key)
+    }
+    pub fn is_banned(&self, key: &PubKey) -> bool {
+        self.bans.read().unwrap().contains(key)
+    }
+    pub fn bans(&self) -> Vec<PubKey> {
+        self.bans.read().unwrap().iter().copied().collect()
+    }
+    /// Bans a public key and closes its connections, returning the amount of closed connections.
+    /// The ban only lasts until the node stops if the node does not store data
+    pub async fn ban(&self, key: &PubKey) -> Result<usize, DbError> {
+        match self.db.add_ban(key).await {
+            Ok(()) | Err(DbError::Disabled) => {}
+            Err(e) => return Err(e),
+        }
+        self.bans.write().unwrap().insert(*key);
+
+        Ok(self.registry.kick(key))
+    }
+    /// Removes the ban of a public key. Fails with [`DbError::NotFound`] if the public key is not banned
+    pub async fn unban(&self, key: &PubKey) -> Result<(), DbError> {
+        if !self.bans.write().unwrap().remove(key) {
+            return Err(DbError::NotFound);
+        }
+
+        match self.db.remove_ban(key).await {
+            Ok(()) | Err(DbError::Disabled) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+    /// Returns true if the device of the delegation was revoked after the delegation was made
+    pub fn is_revoked(&self, delegation: &Delegation) -> bool {
+        match self.revocations.read().unwrap().get(&(delegation.master, delegation.device)) {
+            Some(revoked) => *revoked >= delegation.timestamp,
+            None => false,
+        }
+    }
+    /// Revokes a device of a user, removes it from the sub accounts of the user and closes its connections.
+    /// Returns the amount of closed connections. The revocation only lasts until the node stops if the node does not store data
+    pub async fn revoke(&self, revocation: &Revocation) -> Result<usize, DbError> {
+        match self.db.add_revocation(revocation).await {
+            Ok(()) | Err(DbError::Disabled) => {}
+            Err(e) => return Err(e),
+        }
+        match self.db.remove_subaccount(&revocation.master, &revocation.device).await {
+            Ok(()) | Err(DbError::NotFound) | Err(DbError::Disabled) => {}
+            Err(e) => return Err(e),
+        }
+        self.revocations
+            .write()
+            .unwrap()
+            .entry((revocation.master, revocation.device))
+            .and_modify(|t| *t = (*t).max(revocation.timestamp))
+            .or_insert(revocation.timestamp);
+
+        Ok(self.registry.kick(&revocation.device))
+    }
+    /// Returns the rotation of a public key, if it was replaced by a new key
+    pub fn rotation(&self, key: &PubKey) -> Option<KeyRotation> {
+        self.rotations.read().unwrap().get(key).copied()
+    }
+    /// Replaces a public key by a new key and closes the connections of the old key.
+    /// Returns the amount of closed connections.
+    /// Fails with [`DbError::Conflict`] if the public key was already rotated, unless only the new rotation is countersigned
+    /// by a user the old key is a sub account of
+    pub async fn rotate(&self, rotation: &KeyRotation) -> Result<usize, DbError> {
+        if let Some(old) = self.rotation(&rotation.old) {
+            if old.countersignature.is_some() || !self.is_countersigned_by_user(rotation).await? {
+                return Err(DbError::Conflict);
+            }
+        }
+
+        match self.db.add_rotation(rotation).await {
+            Ok(()) | Err(DbError::Disabled) => {}
+            Err(e) => return Err(e),
+        }
+        self.rotations.write().unwrap().insert(rotation.old, *rotation);
+
+        Ok(self.registry.kick(&rotation.old))
+    }
+    /// Records that two public keys have a conversation, so the rotation of either key is sent to the other
+    pub async fn add_contact(&self, a: &PubKey, b: &PubKey) {
+        match self.db.add_contact(a, b).await {
+            Ok(()) | Err(DbError::Disabled) => {}
+            Err(e) => tracing::warn!("Cannot store contact: {}", e),
+        }
+    }
+    /// Returns true if the rotation is countersigned by a user that added the old key to its sub accounts.
+    /// Anyone can create a master key and delegate to a key they control, so only a stored delegation is trusted
+    async fn is_countersigned_by_user(&self, rotation: &KeyRotation) -> Result<bool, DbError> {
+        let counter = match &rotation.countersignature {
+            Some(v) => v,
+            None => return Ok(false),
+        };
+
+        match self.db.get_subaccounts(&counter.delegation.master).await {
+            Ok(accounts) => Ok(accounts
+                .iter()
+                .any(|a| a.pub_key == rotation.old && a.delegation.is_some())),
+            Err(DbError::Disabled) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+    /// Sends the rotation of a public key to every public key it has a conversation with.
+    /// Returns the amount of contacts it could neither be sent to nor queued for
+    pub async fn announce_rotation(self: &Arc<Self>, rotation: &KeyRotation) -> Result<usize, Box<dyn Error>> {
+        let contacts = match self.db.get_contacts(&rotation.old).await {
+            Ok(v) => v,
+            Err(DbError::Disabled) => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let msg = Message::new(MessageHeader::KeyRotated, rotation)?;
+        let mut failed = 0;
+
+        for contact in contacts {
+            if let Err(e) = self.deliver(&contact, &msg).await {
+                tracing::debug!("Cannot deliver key rotation: {}", e);
+                failed += 1;
+            }
+        }
+
+        Ok(failed)
+    }
+    /// Sends a message to every connection of a public key, or to the linked node hosting it if the message can be forwarded.
+    /// If the public key is not connected to any of them, the message is queued.
+    /// If the "federation" feature is enabled, the node hosting the public key is then looked for in the DHT in the background,
+    /// and the queued message is deleted once it was forwarded to it.
+    /// Returns true if the message was sent, and false if it was queued or is looked for in the DHT
+    pub async fn deliver(self: &Arc<Self>, recipient: &PubKey, msg: &Message) -> Result<bool, DbError> {
+        if self.registry.send_to(recipient, msg) {
+            return Ok(true);
+        }
+        let federated = msg.header.is_forwardable() && self.config().main_config.features.contains("federation");
+        if msg.header.is_forwardable() && self.peers.forward(recipient, msg) {
+            return Ok(true);
+        }
+
+        let queued = self.queue(recipient, msg).await;
+        if !federated {
+            return queued.map(|_| false);
+        }
+        let id = match queued {
+            Ok(id) => Some(id),
+            // Only the node hosting the recipient can take the message
+            Err(DbError::Disabled) => None,
+            Err(e) => return Err(e),
+        };
+
+        // Looking for the home node can take seconds, the sender and the other recipients do not wait for it
+        let state = self.clone();
+        let (recipient, msg) = (*recipient, msg.clone());
+        tokio::spawn(async move {
+            if !forward_home(&state, &recipient, &msg).await {
+                return;
+            }
+            if let Some(id) = id {
+                match state.db.ack_queued(&recipient, id).await {
+                    // The recipient connected and received it in between
+                    Ok(()) | Err(DbError::NotFound) => {}
+                    Err(e) => tracing::warn!("Cannot delete forwarded queued message: {}", e),
+                }
+            }
+        });
+
+        Ok(false)
+    }
+    /// Queues a message for a recipient that is not connected, within the mailbox limits of the configuration
+    pub async fn queue(&self, recipient: &PubKey, msg: &Message) -> Result<u64, DbError> {
+        let config = self.config();
+
+        self.db
+            .queue_message(recipient, msg, config.storage.mailbox_max_messages, config.storage.mailbox_overflow)
+            .await
+    }
+    /// Delivers a message to every member of a group except `except`.
+    /// Returns the amount of members the message could neither be sent to nor queued for
+    pub async fn deliver_group(self: &Arc<Self>, group: &Group, msg: &Message, except: Option<&PubKey>) -> usize {
+        let mut failed = 0;
+
+        for member in &group.members {
+            if except == Some(member) {
+                continue;
+            }
+
+            if let Err(e) = self.deliver(member, msg).await {
+                tracing::debug!("Cannot deliver group message: {}", e);
+                failed += 1;
+            }
+        }
 
-pub async fn start_empty(conf: Arc<Configuration>, server_config: ServerConfig) {
-    let mut node = NodeService::new(conf, EmptyDb {});
+        failed
+    }
+    /// Delivers a message to every member of a guild that can view a channel, except `except`.
+    /// Returns the amount of members the message could neither be sent to nor queued for
+    pub async fn deliver_guild(
+        self: &Arc<Self>,
+        guild: &Guild,
+        channel: Option<ChannelId>,
+        msg: &Message,
+        except: Option<&PubKey>,
+    ) -> usize {
+        let mut failed = 0;
+
+        for member in &guild.members {
+            if except == Some(&member.key) {
+                continue;
+            }
+            if let Some(channel) = channel {
+                if !guild
+                    .channel_permissions(&member.key, channel)
+                    .contains(Permissions::VIEW_CHANNEL)
+                {
+                    continue;
+                }
+            }
+
+            if let Err(e) = self.deliver(&member.key, msg).await {
+                tracing::debug!("Cannot deliver guild message: {}", e);
+                failed += 1;
+            }
+        }
 
-    let _ = node.server(server_config).await;
+        failed
+    }
 }
 
 /// Represents a QUIC node service running
-pub struct NodeService<T> {
-    /// Configuration for the node service
-    config: Arc<Configuration>,
-    /// Database manager for the node
-    db: T,
+pub struct NodeService {
+    state: Arc<NodeState>,
 }
 
-impl<T> NodeService<T> {
-    pub fn new(config: Arc<Configuration>, db: T) -> Self {
-        Self { config, db }
+impl NodeService {
+    pub fn new<T: DbApi + 'static>(
+        config: Arc<Configuration>,
+        node_key: PrivKey,
+        node_rotation: Option<KeyRotation>,
+        admin_pass: Option<[u8; 32]>,
+        db: T,
+    ) -> Self {
+        let replay = ReplayCache::new(config.identify.replay_cache_size);
+
+        Self {
+            state: Arc::new(NodeState {
+                config: RwLock::new(config),
+                db: Arc::new(db),
+                registry: Registry::new(),
+                admin_pass,
+                admin_lockout: AdminLockout::default(),
+                bans: RwLock::default(),
+                revocations: RwLock::default(),
+                rotations: RwLock::default(),
+                replay,
+                node_key,
+                node_pubkey: node_key.public_key(),
+                // Only sent if it is the rotation to the current key
+                node_rotation: node_rotation.filter(|r| r.new == node_key.public_key()),
+                peers: Peers::new(),
+                dht: Dht::new(&node_key.public_key()),
+                started_at: Utc::now(),
+            }),
+        }
     }
-}
+    pub fn state(&self) -> &Arc<NodeState> {
+        &self.state
+    }
+    /// Loads the banned public keys from the database
+    pub async fn load_bans(&self) -> Result<(), DbError> {
+        let bans = match self.state.db.get_bans().await {
+            Ok(v) => v,
+            Err(DbError::Disabled) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        self.state.bans.write().unwrap().extend(bans);
+        Ok(())
+    }
+    /// Loads the devices revoked by users from the database
+    pub async fn load_revocations(&self) -> Result<(), DbError> {
+        let revocations = match self.state.db.get_revocations().await {
+            Ok(v) => v,
+            Err(DbError::Disabled) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        let mut revoked = self.state.revocations.write().unwrap();
+        for revocation in revocations {
+            revoked.insert((revocation.master, revocation.device), revocation.timestamp);
+        }
+        Ok(())
+    }
+    /// Loads the public keys replaced by new keys from the database
+    pub async fn load_rotations(&self) -> Result<(), DbError> {
+        let rotations = match self.state.db.get_rotations().await {
+            Ok(v) => v,
+            Err(DbError::Disabled) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        let mut rotated = self.state.rotations.write().unwrap();
+        for rotation in rotations {
+            rotated.insert(rotation.old, rotation);
+        }
+        Ok(())
+    }
+    /// Loads the nodes learned from other nodes from the database
+    pub async fn load_peers(&self) -> Result<(), DbError> {
+        let peers = match self.state.db.get_peers().await {
+            Ok(v) => v,
+            Err(DbError::Disabled) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        for peer in peers {
+            self.state.dht.insert(peer.clone());
+            self.state.peers.learn(peer);
+        }
+        Ok(())
+    }
+    /// Deletes expired queued messages every hour. Runs until `shutdown` changes
+    pub async fn expire_mailbox(&self, mut shutdown: watch::Receiver<bool>) {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(3600));
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown.changed() => break,
+            }
+
+            // Read on every run, so reloading the configuration changes the retention
+            let retention = Duration::hours(self.state.config().storage.mailbox_retention_hours as i64);
 
-impl<T: DbApi> NodeService<T> {
-    pub async fn server(&mut self, server_config: ServerConfig) -> Result<(), Box<dyn Error>> {
-        let addr = parse_ip(&self.config.quic.address, self.config.quic.port)?;
+            match self.state.db.expire_queued(Utc::now() - retention).await {
+                Ok(0) => {}
+                Ok(n) => tracing::info!("Deleted {} expired queued messages", n),
+                Err(e) => tracing::warn!("Cannot delete expired queued messages: {}", e),
+            }
+        }
+    }
+    /// Starts the QUIC node. Runs until `shutdown` changes, then closes every connection
+    pub async fn server(
+        &self,
+        server_config: ServerConfig,
+        mut shutdown: watch::Receiver<bool>,
+    ) -> Result<(), Box<dyn Error>> {
+        let config = self.state.config();
+        let addr = parse_ip(&config.quic.address, config.quic.port)?;
 
-        let (_endpoint, mut incoming) = Endpoint::server(server_config, addr)?;
+        let (endpoint, mut incoming) = Endpoint::server(server_config, addr)?;
+        tracing::info!("Node listening on {}", addr);
 
-        // TODO: Make database request API. Right now, no messages or other data will be stored
-        let (_db_send, _db_recv) = mpsc::unbounded::<String>();
+        loop {
+            let conn = tokio::select! {
+                v = incoming.next() => match v {
+                    Some(v) => v,
+                    None => break,
+                },
+                _ = shutdown.changed() => break,
+            };
 
-        while let Some(conn) = incoming.next().await {
-            let connection: NewConnection = conn.await?;
+            let state = self.state.clone();
 
             // Handle a new connection
             tokio::spawn(async move {
-                let _ = handle_connection(connection).await;
+                let connection: NewConnection = match conn.await {
+                    Ok(v) => v,
+                    Err(e) => {
+                        tracing::debug!("Connection failed: {}", e);
+                        return;
+                    }
+                };
+
+                let _ = handle_connection(connection, state).await;
             });
         }
 
-        return Ok(());
+        // Close every connection and wait for the peers to be notified
+        endpoint.close(0u32.into(), b"node shutting down");
+        endpoint.wait_idle().await;
+
+        Ok(())
+    }
+    /// Connects to another node at `address`, as `host:port`, and runs the link until either node closes it.
+    /// If `expected` is set, the link is only established if the node has that key
+    pub async fn connect_peer(&self, address: &str, expected: Option<PubKey>) -> Result<(), Box<dyn Error>> {
+        connect_peer(self.state.clone(), address, expected).await
+    }
+    /// Links with the bootstrap nodes and the nodes learned from other nodes, reconnecting with a backoff.
+    /// Runs until `shutdown` changes
+    pub async fn maintain_peers(&self, shutdown: watch::Receiver<bool>) {
+        maintain_peers(self.state.clone(), shutdown).await
+    }
+    /// Deletes expired DHT records and stores the records of the public keys hosted by the node again every hour.
+    /// Runs until `shutdown` changes
+    pub async fn maintain_dht(&self, shutdown: watch::Receiver<bool>) {
+        maintain_dht(self.state.clone(), shutdown).await
+    }
+    /// Starts the HTTP/WebSocket proxy, allowing browsers to connect to the node.
+    /// Runs until `shutdown` changes, then waits for every WebSocket to close
+    pub async fn proxy(&self, mut shutdown: watch::Receiver<bool>) -> Result<(), Box<dyn Error>> {
+        let config = self.state.config();
+        let addr = parse_ip(&config.proxy.address, config.proxy.port)?;
+        let formats = Arc::new(ProxyFormat::from_features(&config.main_config.features));
+
+        let listener = TcpListener::bind(addr).await?;
+        tracing::info!("Proxy listening on {}", addr);
+
+        let mut connections = JoinSet::new();
+
+        loop {
+            let (stream, peer) = tokio::select! {
+                v = listener.accept() => v?,
+                _ = shutdown.changed() => break,
+            };
+
+            let formats = formats.clone();
+            let state = self.state.clone();
+            let shutdown = shutdown.clone();
+
+            // Handle a new browser connection
+            connections.spawn(async move {
+                if let Err(e) = handle_websocket(stream, peer, formats, state, shutdown).await {
+                    tracing::debug!("Proxy connection from {} closed: {}", peer, e);
+                }
+            });
+        }
+
+        while connections.join_next().await.is_some() {}
+
+        Ok(())
     }
 }
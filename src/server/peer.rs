@@ -0,0 +1,1077 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    net::SocketAddr,
+    sync::{Arc, Mutex, RwLock},
+    time::{Instant, SystemTime},
+};
+
+use chrono::{DateTime, Duration, Utc};
+use futures::{
+    channel::{mpsc, oneshot},
+    StreamExt,
+};
+use quinn::{ClientConfig, Endpoint, NewConnection, TransportConfig};
+use rand::{rngs::OsRng, Rng, RngCore};
+use serde::de::DeserializeOwned;
+use tokio::{
+    net::lookup_host,
+    sync::{watch, Notify},
+    task::JoinSet,
+};
+
+use crate::{
+    data::{
+        crypto::{PubKey, SignedMsg},
+        dht_id, DhtFind, DhtResponse, DhtStore, ErrorCode, ErrorMsg, HomeRecord, IdentifyChallenge,
+        KeyRotation, Message, MessageHeader, NodeAccepted, NodeForward, NodeIdentify, PeerFrame,
+        PeerInfo, PeerList, RouteAnnounce, RouteWithdraw,
+    },
+    error::{DbError, FrameError, PeerError},
+};
+
+use super::{
+    client::{spawn_writer, ClientReceiver},
+    codec::MAX_NODE_FRAME_SIZE,
+    dht::{valid_until, K},
+    node::NodeState,
+    registry::ConnectionId,
+};
+
+/// Server name used when connecting to another node. Nodes authenticate each other with their node keys
+const PEER_SERVER_NAME: &str = "cacophoney";
+/// Interval of the keep alive packets of a link. Shorter than the idle timeout of QUIC connections
+const PEER_KEEP_ALIVE: std::time::Duration = std::time::Duration::from_secs(4);
+/// Maximum amount of nodes learned from other nodes
+const MAX_KNOWN_PEERS: usize = 1024;
+
+/// An identified link with another node
+struct PeerLink {
+    id: ConnectionId,
+    address: SocketAddr,
+    /// Messages sent to the node. Drained by the task signing the frames
+    sender: mpsc::UnboundedSender<Message>,
+    /// Closes the link when a value is sent
+    canceller: mpsc::UnboundedSender<()>,
+    /// If this node dialed the other node
+    dialed: bool,
+}
+
+/// The stream of a link with another node, once both nodes are identified
+struct LinkStream {
+    receive: ClientReceiver,
+    /// Messages written to the stream
+    outgoing: mpsc::UnboundedSender<Message>,
+    /// Closes the link when a value is sent
+    canceller: mpsc::UnboundedSender<()>,
+    /// If this node dialed the other node
+    dialed: bool,
+    /// If the link only carries requests of the dialing node. See [`NodeIdentify::transient`]
+    transient: bool,
+}
+
+/// Node-wide links with other nodes, and the public keys they host
+#[derive(Default)]
+pub struct Peers {
+    links: RwLock<HashMap<PubKey, PeerLink>>,
+    /// The node hosting every public key announced by a linked node
+    routes: RwLock<HashMap<PubKey, PubKey>>,
+    /// Nodes accepting links, learned from the configuration, the storage or other nodes
+    known: RwLock<HashMap<PubKey, PeerInfo>>,
+    /// Notified every time a link is added
+    linked: Notify,
+    /// Nodes dialed by [`link_once`] and not linked yet
+    dialing: Mutex<HashSet<PubKey>>,
+}
+
+impl Peers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Adds a link with a node, replacing the previous link with the same node.
+    /// Returns false if the previous link is kept instead
+    fn add(&self, local: &PubKey, node: PubKey, link: PeerLink) -> bool {
+        let mut links = self.links.write().unwrap();
+
+        self.dialing.lock().unwrap().remove(&node);
+
+        if let Some(old) = links.get(&node) {
+            // Both nodes dialed each other. Both of them keep the link dialed by the node with the lowest key
+            if old.dialed != link.dialed && old.dialed == (local.key < node.key) {
+                return false;
+            }
+            let _ = old.canceller.unbounded_send(());
+        }
+        links.insert(node, link);
+        self.linked.notify_waiters();
+
+        true
+    }
+    /// Removes a closed link and the routes announced through it.
+    /// Does nothing if the link was already replaced by a newer one
+    fn remove(&self, node: &PubKey, id: ConnectionId) {
+        let mut links = self.links.write().unwrap();
+
+        if links.get(node).map(|l| l.id) != Some(id) {
+            return;
+        }
+        links.remove(node);
+        self.routes.write().unwrap().retain(|_, n| n != node);
+    }
+    /// Routes public keys to the node announcing them
+    fn announce(&self, node: &PubKey, keys: &[PubKey]) {
+        let mut routes = self.routes.write().unwrap();
+
+        for key in keys {
+            routes.insert(*key, *node);
+        }
+    }
+    /// Removes the routes of public keys, if they still point to the node withdrawing them
+    fn withdraw(&self, node: &PubKey, keys: &[PubKey]) {
+        let mut routes = self.routes.write().unwrap();
+
+        for key in keys {
+            if routes.get(key) == Some(node) {
+                routes.remove(key);
+            }
+        }
+    }
+    /// Returns true if the node has a link with another node
+    pub fn is_linked(&self, node: &PubKey) -> bool {
+        self.links.read().unwrap().contains_key(node)
+    }
+    /// Waits until the node has a link with another node. Returns false if there is still none after `timeout`
+    pub async fn wait_linked(&self, node: &PubKey, timeout: std::time::Duration) -> bool {
+        let deadline = tokio::time::Instant::now() + timeout;
+
+        loop {
+            // Created before checking, so a link added in between is not missed
+            let notified = self.linked.notified();
+            if self.is_linked(node) {
+                return true;
+            }
+
+            tokio::select! {
+                _ = notified => {}
+                _ = tokio::time::sleep_until(deadline) => return self.is_linked(node),
+            }
+        }
+    }
+    /// Returns the key and address of every linked node
+    pub fn list(&self) -> Vec<(PubKey, SocketAddr)> {
+        self.links
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(key, link)| (*key, link.address))
+            .collect()
+    }
+    /// Returns the amount of linked nodes
+    pub fn count(&self) -> usize {
+        self.links.read().unwrap().len()
+    }
+    /// Closes the link with a node, returning false if there is none
+    pub fn disconnect(&self, node: &PubKey) -> bool {
+        match self.links.read().unwrap().get(node) {
+            Some(link) => link.canceller.unbounded_send(()).is_ok(),
+            None => false,
+        }
+    }
+    /// Adds a node accepting links, or updates it if the information is newer.
+    /// Returns true if the node was added or updated
+    pub fn learn(&self, peer: PeerInfo) -> bool {
+        let mut known = self.known.write().unwrap();
+
+        match known.get(&peer.key) {
+            Some(old) if old.last_seen >= peer.last_seen => false,
+            None if known.len() >= MAX_KNOWN_PEERS => false,
+            _ => {
+                known.insert(peer.key, peer);
+                true
+            }
+        }
+    }
+    /// Removes the nodes not seen since `before`, returning their keys
+    pub fn forget(&self, before: DateTime<Utc>) -> Vec<PubKey> {
+        let mut known = self.known.write().unwrap();
+        let forgotten: Vec<PubKey> = known
+            .values()
+            .filter(|peer| peer.last_seen < before)
+            .map(|peer| peer.key)
+            .collect();
+
+        for key in &forgotten {
+            known.remove(key);
+        }
+
+        forgotten
+    }
+    /// Returns the known node with the key
+    pub fn get_known(&self, key: &PubKey) -> Option<PeerInfo> {
+        self.known.read().unwrap().get(key).cloned()
+    }
+    /// Returns every known node
+    pub fn known(&self) -> Vec<PeerInfo> {
+        self.known.read().unwrap().values().cloned().collect()
+    }
+    /// Returns the linked node hosting a public key
+    pub fn route(&self, key: &PubKey) -> Option<PubKey> {
+        self.routes.read().unwrap().get(key).copied()
+    }
+    /// Sends a message to a linked node. Returns false if there is no link with the node
+    pub fn send(&self, node: &PubKey, msg: Message) -> bool {
+        match self.links.read().unwrap().get(node) {
+            Some(link) => link.sender.unbounded_send(msg).is_ok(),
+            None => false,
+        }
+    }
+    /// Sends a message to every linked node
+    pub fn broadcast(&self, msg: &Message) {
+        for link in self.links.read().unwrap().values() {
+            let _ = link.sender.unbounded_send(msg.clone());
+        }
+    }
+    /// Forwards a message to the node hosting the recipient.
+    /// Returns true if a linked node hosts the recipient and the message was sent to it
+    pub fn forward(&self, recipient: &PubKey, msg: &Message) -> bool {
+        match self.route(recipient) {
+            Some(node) => self.forward_via(&node, recipient, msg),
+            None => false,
+        }
+    }
+    /// Forwards a message for the recipient to a linked node, even if the node did not announce the recipient.
+    /// Returns false if there is no link with the node
+    pub fn forward_via(&self, node: &PubKey, recipient: &PubKey, msg: &Message) -> bool {
+        let forward = NodeForward {
+            recipient: *recipient,
+            message: msg.clone(),
+        };
+
+        match Message::new(MessageHeader::NodeForward, &forward) {
+            Ok(v) => self.send(node, v),
+            Err(e) => {
+                tracing::debug!("Cannot forward message: {}", e);
+                false
+            }
+        }
+    }
+    /// Announces public keys that identified on this node to every linked node, with the records they published
+    pub fn announce_local(&self, records: &[HomeRecord]) {
+        if records.is_empty() {
+            return;
+        }
+        if let Ok(msg) = Message::new(MessageHeader::RouteAnnounce, &RouteAnnounce { records: records.to_vec() }) {
+            self.broadcast(&msg);
+        }
+    }
+    /// Withdraws public keys that are no longer connected to this node from every linked node
+    pub fn withdraw_local(&self, keys: &[PubKey]) {
+        if keys.is_empty() {
+            return;
+        }
+        if let Ok(msg) = Message::new(MessageHeader::RouteWithdraw, &RouteWithdraw { keys: keys.to_vec() }) {
+            self.broadcast(&msg);
+        }
+    }
+}
+
+/// Accepts any certificate. Nodes are authenticated by the signatures of their node keys instead
+struct SkipCertVerification;
+
+impl rustls::client::ServerCertVerifier for SkipCertVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+}
+
+/// The QUIC configuration used to connect to other nodes
+fn peer_client_config() -> ClientConfig {
+    let crypto = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(SkipCertVerification))
+        .with_no_client_auth();
+
+    // Links are idle between messages. The dialing node keeps them open for both nodes
+    let mut transport = TransportConfig::default();
+    transport.keep_alive_interval(Some(PEER_KEEP_ALIVE));
+
+    let mut config = ClientConfig::new(Arc::new(crypto));
+    config.transport = Arc::new(transport);
+
+    config
+}
+
+/// Derives the ID of a link from the challenges of both nodes
+fn link_id(acceptor_challenge: &[u8; 32], dialer_challenge: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(acceptor_challenge);
+    hasher.update(dialer_challenge);
+
+    *hasher.finalize().as_bytes()
+}
+
+/// Checks that a handshake timestamp is within the clock skew accepted by the node
+fn check_timestamp(state: &NodeState, timestamp: &DateTime<Utc>) -> Result<(), PeerError> {
+    let window = Duration::seconds(state.config().identify.clock_skew_secs as i64);
+    let age = Utc::now().signed_duration_since(*timestamp);
+
+    if age > window || -age > window {
+        return Err(PeerError::StaleTimestamp);
+    }
+
+    Ok(())
+}
+
+/// Checks that the node key signed a message between two nodes
+fn check_signature(msg: &SignedMsg, key: &PubKey, signature: &[u8; 64]) -> Result<(), PeerError> {
+    let mut key = *key;
+
+    match msg.verify(&mut key, signature) {
+        Ok(true) => Ok(()),
+        _ => Err(PeerError::InvalidSignature),
+    }
+}
+
+/// Receives a handshake message of a specific type
+async fn expect<T: DeserializeOwned>(receive: &mut ClientReceiver, header: MessageHeader) -> Result<T, PeerError> {
+    let msg = receive.receive().await?.ok_or(PeerError::Closed)?;
+
+    if msg.header != header {
+        return Err(PeerError::UnexpectedMessage(msg.header));
+    }
+
+    Ok(serde_cbor::value::from_value(msg.object)?)
+}
+
+/// Verifies the [`NodeIdentify`] of a node connecting to this one, answers it and runs the link.
+/// `challenge` is the [`IdentifyChallenge`] sent on the connection
+pub(super) async fn accept_peer(
+    state: Arc<NodeState>,
+    msg: Message,
+    challenge: [u8; 32],
+    address: SocketAddr,
+    receive: ClientReceiver,
+    outgoing: mpsc::UnboundedSender<Message>,
+    canceller: mpsc::UnboundedSender<()>,
+) -> Result<(), PeerError> {
+    let verify = || -> Result<NodeIdentify, PeerError> {
+        let obj = serde_cbor::value::from_value::<NodeIdentify>(msg.object)?;
+
+        if obj.key == state.node_pubkey {
+            return Err(PeerError::UnexpectedKey);
+        }
+        check_timestamp(&state, &obj.timestamp)?;
+        check_signature(
+            &SignedMsg::from_node(&obj.key, &state.node_pubkey, &challenge, &obj.timestamp),
+            &obj.key,
+            &obj.signature,
+        )?;
+
+        Ok(obj)
+    };
+
+    let obj = match verify() {
+        Ok(v) => v,
+        Err(e) => {
+            let code = match e {
+                PeerError::StaleTimestamp => ErrorCode::StaleTimestamp,
+                PeerError::InvalidSignature => ErrorCode::InvalidSignature,
+                PeerError::UnexpectedKey => ErrorCode::Unauthorized,
+                _ => ErrorCode::Malformed,
+            };
+            outgoing
+                .unbounded_send(Message::new(MessageHeader::Error, &ErrorMsg::new(code, e.to_string()))?)
+                .map_err(|_| PeerError::Closed)?;
+            return Err(e);
+        }
+    };
+
+    let timestamp = Utc::now();
+    let accepted = NodeAccepted {
+        key: state.node_pubkey,
+        timestamp,
+        signature: state.sign(&SignedMsg::from_node(&state.node_pubkey, &obj.key, &obj.challenge, &timestamp)),
+    };
+    outgoing
+        .unbounded_send(Message::new(MessageHeader::NodeAccepted, &accepted)?)
+        .map_err(|_| PeerError::Closed)?;
+
+    let link = link_id(&challenge, &obj.challenge);
+    let stream = LinkStream {
+        receive,
+        outgoing,
+        canceller,
+        dialed: false,
+        transient: obj.transient,
+    };
+    run_link(state, obj.key, address, link, stream).await
+}
+
+/// An identified link dialed by this node, before it runs
+struct Dialed {
+    remote: PubKey,
+    address: SocketAddr,
+    link: [u8; 32],
+    stream: LinkStream,
+}
+
+/// Connects to the node at `address`, as `host:port`, and identifies both nodes.
+/// If `expected` is set, the link is only established if the node has that key
+async fn dial(
+    state: &NodeState,
+    address: &str,
+    expected: Option<PubKey>,
+    transient: bool,
+) -> Result<Dialed, Box<dyn Error>> {
+    let resolved = lookup_host(address)
+        .await?
+        .next()
+        .ok_or_else(|| PeerError::Unresolved(address.to_string()))?;
+    let bind = match resolved {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    let mut endpoint = Endpoint::client(bind.parse()?)?;
+    endpoint.set_default_client_config(peer_client_config());
+
+    let NewConnection { mut bi_streams, .. } = endpoint.connect(resolved, PEER_SERVER_NAME)?.await?;
+    // The node opens the stream and sends its challenge
+    let (send, recv) = bi_streams.next().await.ok_or(PeerError::Closed)??;
+
+    let (c_send, c_recv) = mpsc::unbounded();
+    let mut receive = ClientReceiver::new(c_recv, recv, MAX_NODE_FRAME_SIZE);
+    let outgoing = spawn_writer(send);
+
+    let challenge = expect::<IdentifyChallenge>(&mut receive, MessageHeader::IdentifyChallenge).await?;
+    let remote = challenge.node_key;
+    // A node that rotated its key is accepted if its previous key signed the new key
+    let rotated = |key: PubKey| {
+        challenge
+            .node_rotation
+            .is_some_and(|r| r.old == key && r.new == remote && r.verify())
+    };
+    if expected.is_some_and(|key| key != remote && !rotated(key)) || remote == state.node_pubkey {
+        return Err(PeerError::UnexpectedKey.into());
+    }
+
+    let mut own_challenge = [0u8; 32];
+    OsRng.fill_bytes(&mut own_challenge);
+
+    let timestamp = Utc::now();
+    let identify = NodeIdentify {
+        key: state.node_pubkey,
+        timestamp,
+        signature: state.sign(&SignedMsg::from_node(&state.node_pubkey, &remote, &challenge.sig_msg, &timestamp)),
+        challenge: own_challenge,
+        transient,
+    };
+    outgoing.unbounded_send(Message::new(MessageHeader::NodeIdentify, &identify)?)?;
+
+    let accepted = expect::<NodeAccepted>(&mut receive, MessageHeader::NodeAccepted).await?;
+    if accepted.key != remote {
+        return Err(PeerError::UnexpectedKey.into());
+    }
+    check_timestamp(state, &accepted.timestamp)?;
+    check_signature(
+        &SignedMsg::from_node(&remote, &state.node_pubkey, &own_challenge, &accepted.timestamp),
+        &remote,
+        &accepted.signature,
+    )?;
+
+    // The node accepts links at the address, so other nodes can learn it
+    let peer = PeerInfo {
+        key: remote,
+        address: address.to_string(),
+        last_seen: Utc::now(),
+    };
+    remember_peer(state, peer).await;
+
+    Ok(Dialed {
+        remote,
+        address: resolved,
+        link: link_id(&challenge.sig_msg, &own_challenge),
+        stream: LinkStream {
+            receive,
+            outgoing,
+            canceller: c_send,
+            dialed: true,
+            transient,
+        },
+    })
+}
+
+/// Links with `node` at `address` in a spawned task, unless a previous call is still dialing it.
+/// Callers wait for the link with [`Peers::wait_linked`]
+pub fn link_once(state: &Arc<NodeState>, address: String, node: PubKey) {
+    if !state.peers.dialing.lock().unwrap().insert(node) {
+        return;
+    }
+    let state = state.clone();
+
+    tokio::spawn(async move {
+        // Once dialed, adding the link ends the attempt
+        let dialed = match dial(&state, &address, Some(node), false).await {
+            Ok(v) => v,
+            Err(e) => {
+                state.peers.dialing.lock().unwrap().remove(&node);
+                tracing::debug!("Cannot link with {:?}: {}", node, e);
+                return;
+            }
+        };
+        if let Err(e) = run_link(state, dialed.remote, dialed.address, dialed.link, dialed.stream).await {
+            tracing::debug!("Link with {:?} closed: {}", node, e);
+        }
+    });
+}
+
+/// Connects to the node at `address`, as `host:port`, and runs the link until either node closes it.
+/// If `expected` is set, the link is only established if the node has that key
+pub async fn connect_peer(state: Arc<NodeState>, address: &str, expected: Option<PubKey>) -> Result<(), Box<dyn Error>> {
+    let dialed = dial(&state, address, expected, false).await?;
+
+    run_link(state, dialed.remote, dialed.address, dialed.link, dialed.stream).await?;
+
+    Ok(())
+}
+
+/// Sends a request to a node and waits for `response` to complete.
+/// The request goes through the link with the node if there is one, or through a transient link otherwise
+pub(super) async fn request_peer<T>(
+    state: &Arc<NodeState>,
+    peer: &PeerInfo,
+    request: Message,
+    response: oneshot::Receiver<T>,
+) -> Result<T, Box<dyn Error>> {
+    if state.peers.send(&peer.key, request.clone()) {
+        return Ok(response.await?);
+    }
+
+    let Dialed {
+        remote, link, stream, ..
+    } = dial(state, &peer.address, Some(peer.key), true).await?;
+    let LinkStream {
+        mut receive, outgoing, ..
+    } = stream;
+    receive.set_max_frame_size(MAX_NODE_FRAME_SIZE);
+
+    let frames = spawn_signer(state.clone(), remote, link, outgoing);
+    frames.unbounded_send(request)?;
+
+    // The link closes once the response is received
+    tokio::select! {
+        v = response => Ok(v?),
+        v = receive_frames(state, &remote, &link, &mut receive, &frames) => Err(v.err().unwrap_or(PeerError::Closed).into()),
+    }
+}
+
+/// Runs an identified link with another node until either node closes it
+async fn run_link(
+    state: Arc<NodeState>,
+    remote: PubKey,
+    address: SocketAddr,
+    link: [u8; 32],
+    stream: LinkStream,
+) -> Result<(), PeerError> {
+    let LinkStream {
+        mut receive,
+        outgoing,
+        canceller,
+        dialed,
+        transient,
+    } = stream;
+    receive.set_max_frame_size(MAX_NODE_FRAME_SIZE);
+
+    let frame_send = spawn_signer(state.clone(), remote, link, outgoing);
+
+    if transient {
+        // The node only sends requests, and is not used to route messages
+        return receive_frames(&state, &remote, &link, &mut receive, &frame_send).await;
+    }
+
+    let id = state.registry.next_id();
+    let peer = PeerLink {
+        id,
+        address,
+        sender: frame_send.clone(),
+        canceller,
+        dialed,
+    };
+    if !state.peers.add(&state.node_pubkey, remote, peer) {
+        tracing::debug!("Kept the previous link with node {:?}", remote);
+        return Ok(());
+    }
+    tracing::info!("Linked with node {:?} at {}", remote, address);
+
+    let records = local_records(&state, &state.registry.online_keys());
+    if !records.is_empty() {
+        let _ = frame_send.unbounded_send(Message::new(MessageHeader::RouteAnnounce, &RouteAnnounce { records })?);
+    }
+    let _ = frame_send.unbounded_send(Message::new(MessageHeader::PeerList, &peer_list(&state))?);
+
+    let result = receive_frames(&state, &remote, &link, &mut receive, &frame_send).await;
+
+    state.peers.remove(&remote, id);
+    tracing::info!("Link with node {:?} closed", remote);
+
+    result
+}
+
+/// Spawns the task signing every message sent on a link, and returns the channel feeding it.
+/// A single task signs the frames, so the sequence follows the order of the frames
+fn spawn_signer(
+    state: Arc<NodeState>,
+    remote: PubKey,
+    link: [u8; 32],
+    outgoing: mpsc::UnboundedSender<Message>,
+) -> mpsc::UnboundedSender<Message> {
+    let (frame_send, mut frame_recv) = mpsc::unbounded::<Message>();
+
+    tokio::spawn(async move {
+        let mut sequence = 0u64;
+
+        while let Some(message) = frame_recv.next().await {
+            sequence += 1;
+
+            let payload = match PeerFrame::signed_payload(&link, sequence, &message) {
+                Ok(v) => v,
+                Err(e) => {
+                    tracing::debug!("Cannot sign frame for node: {}", e);
+                    continue;
+                }
+            };
+            let timestamp = Utc::now();
+            let frame = PeerFrame {
+                sequence,
+                timestamp,
+                message,
+                signature: state.sign(&SignedMsg::from_node(&state.node_pubkey, &remote, &payload, &timestamp)),
+            };
+
+            match Message::new(MessageHeader::PeerFrame, &frame) {
+                Ok(v) => {
+                    if outgoing.unbounded_send(v).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => tracing::debug!("Cannot send frame to node: {}", e),
+            }
+        }
+    });
+
+    frame_send
+}
+
+/// Receives and verifies the frames of a link. Answers to requests are sent to `reply`
+async fn receive_frames(
+    state: &Arc<NodeState>,
+    remote: &PubKey,
+    link: &[u8; 32],
+    receive: &mut ClientReceiver,
+    reply: &mpsc::UnboundedSender<Message>,
+) -> Result<(), PeerError> {
+    let mut last_sequence = 0u64;
+
+    loop {
+        let msg = match receive.receive().await {
+            Ok(Some(v)) => v,
+            // The link was closed by this node
+            Ok(None) | Err(FrameError::Cancelled) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        if msg.header != MessageHeader::PeerFrame {
+            return Err(PeerError::UnexpectedMessage(msg.header));
+        }
+
+        let frame = serde_cbor::value::from_value::<PeerFrame>(msg.object)?;
+        if frame.sequence <= last_sequence {
+            return Err(PeerError::OutOfOrder);
+        }
+
+        let payload = PeerFrame::signed_payload(link, frame.sequence, &frame.message)?;
+        check_signature(
+            &SignedMsg::from_node(remote, &state.node_pubkey, &payload, &frame.timestamp),
+            remote,
+            &frame.signature,
+        )?;
+        last_sequence = frame.sequence;
+
+        handle_peer_message(state, remote, reply, frame.message).await;
+    }
+}
+
+/// Returns the records published by the public keys hosted by this node. Public keys without a record are not announced
+pub(super) fn local_records(state: &NodeState, keys: &[PubKey]) -> Vec<HomeRecord> {
+    keys.iter()
+        .filter_map(|key| state.dht.published_record(key))
+        .filter(|record| record.node == state.node_pubkey)
+        .collect()
+}
+
+/// Adds a node accepting links, and stores it if the information is newer
+async fn remember_peer(state: &NodeState, peer: PeerInfo) {
+    state.dht.insert(peer.clone());
+
+    if !state.peers.learn(peer.clone()) {
+        return;
+    }
+
+    match state.db.add_peer(&peer).await {
+        Ok(()) | Err(DbError::Disabled) => {}
+        Err(e) => tracing::warn!("Cannot store node: {}", e),
+    }
+}
+
+/// Returns the nodes shared with other nodes: every known node, and this node if it has a public address
+fn peer_list(state: &NodeState) -> PeerList {
+    let mut peers = state.peers.known();
+
+    if let Some(address) = &state.config().peers.public_address {
+        peers.push(PeerInfo {
+            key: state.node_pubkey,
+            address: address.clone(),
+            last_seen: Utc::now(),
+        });
+    }
+
+    PeerList { peers }
+}
+
+/// Keeps a link with a node while it is a bootstrap node or a known node. Runs until `shutdown` changes
+async fn keep_linked(state: Arc<NodeState>, key: PubKey, mut shutdown: watch::Receiver<bool>) {
+    let mut delay = state.config().peers.reconnect_min_secs;
+
+    loop {
+        let config = state.config();
+        let bootstrap = config.peers.bootstrap.iter().find(|peer| peer.key == key);
+        let address = match bootstrap {
+            Some(peer) => peer.address.clone(),
+            None => match state.peers.get_known(&key) {
+                Some(peer) => peer.address,
+                // The node was forgotten
+                None => return,
+            },
+        };
+
+        if !state.peers.is_linked(&key) && state.peers.count() < config.peers.max_peers {
+            let started = Instant::now();
+
+            tokio::select! {
+                v = connect_peer(state.clone(), &address, Some(key)) => if let Err(e) = v {
+                    tracing::debug!("Cannot link with node {:?} at {}: {}", key, address, e);
+                },
+                _ = shutdown.changed() => return,
+            }
+
+            // A link that lasted a while was not a failure
+            if started.elapsed().as_secs() >= config.peers.reconnect_max_secs {
+                delay = config.peers.reconnect_min_secs;
+            } else {
+                delay = (delay * 2).min(config.peers.reconnect_max_secs);
+            }
+        } else {
+            delay = config.peers.reconnect_min_secs;
+        }
+
+        // Nodes that restarted together do not all dial at the same time
+        let jitter = rand::thread_rng().gen_range(0..=delay / 2);
+
+        tokio::select! {
+            _ = tokio::time::sleep(std::time::Duration::from_secs(delay + jitter)) => {}
+            _ = shutdown.changed() => return,
+        }
+    }
+}
+
+/// Links with the bootstrap nodes and the known nodes, and shares the known nodes with the linked nodes.
+/// Runs until `shutdown` changes
+pub async fn maintain_peers(state: Arc<NodeState>, mut shutdown: watch::Receiver<bool>) {
+    let mut linking = HashSet::new();
+    let mut tasks = JoinSet::new();
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(
+        state.config().peers.gossip_interval_secs.max(1),
+    ));
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            Some(Ok(key)) = tasks.join_next(), if !tasks.is_empty() => {
+                linking.remove(&key);
+                continue;
+            }
+            _ = shutdown.changed() => break,
+        }
+
+        let config = state.config();
+
+        let before = Utc::now() - Duration::hours(config.peers.forget_after_hours as i64);
+        for key in state.peers.forget(before) {
+            match state.db.remove_peer(&key).await {
+                Ok(()) | Err(DbError::Disabled) | Err(DbError::NotFound) => {}
+                Err(e) => tracing::warn!("Cannot remove forgotten node: {}", e),
+            }
+        }
+
+        let keys = config
+            .peers
+            .bootstrap
+            .iter()
+            .map(|peer| peer.key)
+            .chain(state.peers.known().into_iter().map(|peer| peer.key));
+        for key in keys {
+            if key == state.node_pubkey || !linking.insert(key) {
+                continue;
+            }
+
+            let state = state.clone();
+            let shutdown = shutdown.clone();
+            tasks.spawn(async move {
+                keep_linked(state, key, shutdown).await;
+                key
+            });
+        }
+
+        match Message::new(MessageHeader::PeerList, &peer_list(&state)) {
+            Ok(v) => state.peers.broadcast(&v),
+            Err(e) => tracing::debug!("Cannot share known nodes: {}", e),
+        }
+    }
+
+    while tasks.join_next().await.is_some() {}
+}
+
+/// Decodes the object of a message of a linked node, or logs it and returns [`None`] if it is malformed
+fn decode<T: DeserializeOwned>(msg: Message) -> Option<T> {
+    match serde_cbor::value::from_value(msg.object) {
+        Ok(v) => Some(v),
+        Err(e) => {
+            tracing::debug!("Malformed {:?} message from node: {}", msg.header, e);
+            None
+        }
+    }
+}
+
+/// Handles a verified message of a linked node
+async fn handle_peer_message(
+    state: &Arc<NodeState>,
+    remote: &PubKey,
+    reply: &mpsc::UnboundedSender<Message>,
+    msg: Message,
+) {
+    match msg.header {
+        // 39: ROUTE ANNOUNCE
+        // The node hosts new public keys. Only the public keys that signed a record naming the node are routed to it
+        MessageHeader::RouteAnnounce => {
+            let obj = match decode::<RouteAnnounce>(msg) {
+                Some(v) => v,
+                None => return,
+            };
+            let config = state.config();
+            let announced = obj.records.len();
+
+            let keys: Vec<PubKey> = obj
+                .records
+                .into_iter()
+                .filter(|record| record.node == *remote && valid_until(record, &config).is_some())
+                .map(|record| record.key)
+                .collect();
+            if keys.len() < announced {
+                tracing::debug!("Rejected {} routes without a valid record from node {:?}", announced - keys.len(), remote);
+            }
+
+            state.peers.announce(remote, &keys);
+        }
+        // 40: ROUTE WITHDRAW
+        // The node no longer hosts public keys
+        MessageHeader::RouteWithdraw => {
+            if let Some(obj) = decode::<RouteWithdraw>(msg) {
+                state.peers.withdraw(remote, &obj.keys);
+            }
+        }
+        // 41: NODE FORWARD
+        // The node forwards a message to a public key it routes to this node.
+        // The message is never forwarded again, so a stale route cannot loop between nodes
+        MessageHeader::NodeForward => {
+            let obj = match decode::<NodeForward>(msg) {
+                Some(v) => v,
+                None => return,
+            };
+
+            // Other messages come from the checks of this node, a node cannot make them up
+            let allowed = match obj.message.header {
+                MessageHeader::KeyRotated => serde_cbor::value::from_value::<KeyRotation>(obj.message.object.clone())
+                    .is_ok_and(|rotation| rotation.verify()),
+                header => header.is_forwardable(),
+            };
+            if !allowed {
+                tracing::debug!("Rejected forwarded {:?} message from node {:?}", obj.message.header, remote);
+
+                let error = ErrorMsg::new(
+                    ErrorCode::Unauthorized,
+                    format!("{:?} messages cannot be forwarded", obj.message.header),
+                );
+                if let Ok(v) = Message::new(MessageHeader::Error, &error) {
+                    let _ = reply.unbounded_send(v);
+                }
+                return;
+            }
+
+            if state.registry.send_to(&obj.recipient, &obj.message) {
+                return;
+            }
+            match state.queue(&obj.recipient, &obj.message).await {
+                Ok(_) => {}
+                Err(DbError::Disabled) => tracing::debug!("Dropped forwarded message for a public key that is not connected"),
+                Err(DbError::MailboxFull) => tracing::debug!("Dropped forwarded message for a public key with a full mailbox"),
+                Err(e) => tracing::warn!("Cannot queue forwarded message: {}", e),
+            }
+        }
+        // 42: PEER LIST
+        // The node shares the nodes it knows
+        MessageHeader::PeerList => {
+            let obj = match decode::<PeerList>(msg) {
+                Some(v) => v,
+                None => return,
+            };
+            let now = Utc::now();
+
+            for mut peer in obj.peers {
+                if peer.key == state.node_pubkey {
+                    continue;
+                }
+                // A node cannot keep other nodes known by claiming it saw them in the future
+                peer.last_seen = peer.last_seen.min(now);
+
+                remember_peer(state, peer).await;
+            }
+        }
+        // 43: DHT FIND
+        // The node looks for an entry, or for the nodes closest to a position
+        MessageHeader::DhtFind => {
+            let obj = match decode::<DhtFind>(msg) {
+                Some(v) => v,
+                None => return,
+            };
+            let response = DhtResponse {
+                id: obj.id,
+                entry: state.dht.get(&obj.target),
+                closer: state.dht.closest(&obj.target, K),
+            };
+
+            if let Ok(v) = Message::new(MessageHeader::DhtResponse, &response) {
+                let _ = reply.unbounded_send(v);
+            }
+        }
+        // 44: DHT STORE
+        // The node asks this node to store an entry
+        MessageHeader::DhtStore => {
+            let obj = match decode::<DhtStore>(msg) {
+                Some(v) => v,
+                None => return,
+            };
+            let target = dht_id(&obj.entry.record.key);
+            if !state.dht.store(obj.entry, &state.config()) {
+                tracing::debug!("Rejected DHT entry from node {:?}", remote);
+            }
+
+            let response = DhtResponse {
+                id: obj.id,
+                entry: None,
+                closer: state.dht.closest(&target, K),
+            };
+            if let Ok(v) = Message::new(MessageHeader::DhtResponse, &response) {
+                let _ = reply.unbounded_send(v);
+            }
+        }
+        // 45: DHT RESPONSE
+        // The node answers a request of this node
+        MessageHeader::DhtResponse => {
+            if let Some(obj) = decode::<DhtResponse>(msg) {
+                state.dht.resolve(remote, obj);
+            }
+        }
+        _ => tracing::debug!("Ignored {:?} message from node", msg.header),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::Configuration,
+        data::{crypto::PrivKey, CommunicationRequest, HomeRecord, IdentifyAccepted},
+        db::StorageDb,
+        server::NodeService,
+    };
+
+    use super::*;
+
+    fn node() -> Arc<NodeState> {
+        let db = StorageDb::temporary().unwrap();
+        let node = NodeService::new(Arc::new(Configuration::default()), PrivKey::random(), None, None, db);
+
+        node.state().clone()
+    }
+
+    #[tokio::test]
+    async fn routes_need_a_record_naming_the_node() {
+        let state = node();
+        let (reply, _replies) = mpsc::unbounded();
+        let (remote, other) = (PrivKey::random().public_key(), PrivKey::random().public_key());
+        let (hosted, stolen) = (PrivKey::random(), PrivKey::random());
+
+        let records = vec![
+            HomeRecord::new(&hosted, remote, Utc::now()),
+            // Signed by the public key, but naming another node
+            HomeRecord::new(&stolen, other, Utc::now()),
+        ];
+        let msg = Message::new(MessageHeader::RouteAnnounce, &RouteAnnounce { records }).unwrap();
+        handle_peer_message(&state, &remote, &reply, msg).await;
+
+        assert_eq!(state.peers.route(&hosted.public_key()), Some(remote));
+        assert_eq!(state.peers.route(&stolen.public_key()), None);
+    }
+
+    #[tokio::test]
+    async fn only_forwardable_messages_are_forwarded() {
+        let state = node();
+        let (reply, mut replies) = mpsc::unbounded();
+        let remote = PrivKey::random().public_key();
+        let key = PrivKey::random().public_key();
+
+        let (outgoing, mut received) = mpsc::unbounded();
+        state.registry.register(key, state.registry.next_id(), outgoing);
+
+        let injected = Message::new(MessageHeader::IdentifyAccepted, &IdentifyAccepted { keys: vec![remote] }).unwrap();
+        let forward = NodeForward {
+            recipient: key,
+            message: injected,
+        };
+        let msg = Message::new(MessageHeader::NodeForward, &forward).unwrap();
+        handle_peer_message(&state, &remote, &reply, msg).await;
+
+        assert!(received.try_recv().is_err());
+        let error = replies.try_recv().unwrap();
+        assert_eq!(error.header, MessageHeader::Error);
+
+        let request = CommunicationRequest {
+            from: remote,
+            public_key: key,
+        };
+        let forward = NodeForward {
+            recipient: key,
+            message: Message::new(MessageHeader::CommunicationRequest, &request).unwrap(),
+        };
+        let msg = Message::new(MessageHeader::NodeForward, &forward).unwrap();
+        handle_peer_message(&state, &remote, &reply, msg).await;
+
+        let relayed = received.try_recv().unwrap();
+        assert_eq!(relayed.header, MessageHeader::CommunicationRequest);
+    }
+}
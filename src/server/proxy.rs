@@ -0,0 +1,169 @@
+use std::{collections::HashSet, error::Error, net::SocketAddr, sync::Arc};
+
+use futures::{channel::mpsc, SinkExt, StreamExt};
+use tokio::{net::TcpStream, sync::watch};
+use tokio_tungstenite::tungstenite::{
+    handshake::server::{ErrorResponse, Request, Response},
+    http::StatusCode,
+    protocol::WebSocketConfig,
+    Message as WsMessage,
+};
+
+use crate::{
+    data::{ErrorCode, ErrorMsg, Message, Transport},
+    error::ProxyFormatError,
+};
+
+use super::{client::Client, codec::MAX_FRAME_SIZE, node::NodeState};
+
+/// Maximum size of a WebSocket message. JSON represents bytes as arrays of numbers,
+/// so a message can take up to 4 times the space of the same CBOR frame.
+const MAX_TEXT_SIZE: usize = MAX_FRAME_SIZE as usize * 4;
+
+/// The text format of the messages of a WebSocket connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProxyFormat {
+    /// Selected with the `/json` path. Enabled by the "proxy/json" feature
+    Json,
+    /// Selected with the `/json5` path. Enabled by the "proxy/json5" feature
+    Json5,
+}
+
+impl ProxyFormat {
+    /// Returns the formats enabled by the features of the node
+    pub fn from_features(features: &HashSet<String>) -> HashSet<ProxyFormat> {
+        let mut ret = HashSet::new();
+
+        if features.contains("proxy/json") {
+            ret.insert(ProxyFormat::Json);
+        }
+        if features.contains("proxy/json5") {
+            ret.insert(ProxyFormat::Json5);
+        }
+
+        ret
+    }
+    /// Returns the format selected by the path of the WebSocket request
+    pub fn from_path(path: &str) -> Option<Self> {
+        match path.trim_end_matches('/') {
+            "" | "/json" => Some(ProxyFormat::Json),
+            "/json5" => Some(ProxyFormat::Json5),
+            _ => None,
+        }
+    }
+    /// Parses a message received from a browser
+    pub fn decode(&self, text: &str) -> Result<Message, ProxyFormatError> {
+        match self {
+            ProxyFormat::Json => Ok(serde_json::from_str(text)?),
+            ProxyFormat::Json5 => Ok(json5::from_str(text)?),
+        }
+    }
+    /// Serializes a message sent to a browser. JSON is also valid JSON5, so both formats are sent as JSON
+    pub fn encode(&self, msg: &Message) -> Result<String, ProxyFormatError> {
+        Ok(serde_json::to_string(msg)?)
+    }
+}
+
+/// Handles a browser connecting to the proxy. The connection has the same semantics as a QUIC connection,
+/// with every message sent as a text WebSocket message.
+pub async fn handle_websocket(
+    stream: TcpStream,
+    address: SocketAddr,
+    formats: Arc<HashSet<ProxyFormat>>,
+    state: Arc<NodeState>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<(), Box<dyn Error>> {
+    let mut format = None;
+
+    // Select the format from the path of the request
+    #[allow(clippy::result_large_err)]
+    let callback = |req: &Request, resp: Response| -> Result<Response, ErrorResponse> {
+        match ProxyFormat::from_path(req.uri().path()) {
+            Some(f) if formats.contains(&f) => {
+                format = Some(f);
+                Ok(resp)
+            }
+            _ => {
+                let mut err = ErrorResponse::new(Some("unknown or disabled format".to_string()));
+                *err.status_mut() = StatusCode::NOT_FOUND;
+                Err(err)
+            }
+        }
+    };
+
+    let config = WebSocketConfig {
+        max_message_size: Some(MAX_TEXT_SIZE),
+        max_frame_size: Some(MAX_TEXT_SIZE),
+        ..Default::default()
+    };
+    let ws = tokio_tungstenite::accept_hdr_async_with_config(stream, callback, Some(config)).await?;
+    // Cannot fail, the handshake only succeeds if a format was selected
+    let format = format.unwrap();
+
+    let (mut sink, mut stream) = ws.split();
+
+    // Every message to the client goes through a channel, so other connections can relay messages
+    let (out_send, mut out_recv) = mpsc::unbounded::<Message>();
+
+    let writer = tokio::spawn(async move {
+        while let Some(msg) = out_recv.next().await {
+            let text = match format.encode(&msg) {
+                Ok(v) => v,
+                Err(e) => {
+                    tracing::debug!("Cannot encode message for browser: {}", e);
+                    continue;
+                }
+            };
+
+            if sink.send(WsMessage::Text(text)).await.is_err() {
+                return;
+            }
+        }
+
+        // Every sender was dropped, the connection is closing
+        let _ = sink.close().await;
+    });
+
+    // Closes the connection when the client is kicked
+    let (c_send, mut c_recv) = mpsc::unbounded::<()>();
+
+    let mut client = Client::new(state, out_send, c_send, address, Transport::WebSocket);
+    client.send_challenge()?;
+
+    loop {
+        let msg = tokio::select! {
+            v = stream.next() => match v {
+                Some(v) => v,
+                None => break,
+            },
+            _ = c_recv.next() => break,
+            _ = shutdown.changed() => break,
+        };
+
+        let text = match msg {
+            Ok(WsMessage::Text(v)) => v,
+            Ok(WsMessage::Close(_)) => break,
+            // Pings are answered by tungstenite
+            Ok(WsMessage::Ping(_) | WsMessage::Pong(_)) => continue,
+            Ok(_) => {
+                client.send_error(ErrorMsg::new(ErrorCode::Malformed, "only text messages are accepted"))?;
+                continue;
+            }
+            Err(e) => {
+                tracing::debug!("Cannot receive message from browser: {}", e);
+                break;
+            }
+        };
+
+        match format.decode(&text) {
+            Ok(msg) => client.handle_message(msg).await?,
+            Err(e) => client.send_error(ErrorMsg::new(ErrorCode::Malformed, e.to_string()))?,
+        }
+    }
+
+    // Dropping the client closes the channel, letting the writer close the WebSocket
+    drop(client);
+    let _ = writer.await;
+
+    Ok(())
+}
@@ -0,0 +1,138 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock,
+    },
+};
+
+use futures::channel::mpsc;
+
+use crate::data::{crypto::PubKey, ConnectionInfo, Message, StreamIdentify};
+
+/// Identifies a single connection to the node
+pub type ConnectionId = u64;
+
+/// A connection known to the registry
+struct Connection {
+    info: ConnectionInfo,
+    /// Closes the connection when a value is sent
+    canceller: mpsc::UnboundedSender<()>,
+}
+
+/// Node-wide mapping of identified public keys to the connections identified as them
+#[derive(Default)]
+pub struct Registry {
+    next_id: AtomicU64,
+    connections: RwLock<HashMap<PubKey, HashMap<ConnectionId, mpsc::UnboundedSender<Message>>>>,
+    /// Every open connection, identified or not
+    clients: RwLock<HashMap<ConnectionId, Connection>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Returns a new unique connection ID
+    pub fn next_id(&self) -> ConnectionId {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+    /// Adds an open connection. `canceller` closes the connection when a value is sent to it
+    pub fn connect(&self, info: ConnectionInfo, canceller: mpsc::UnboundedSender<()>) {
+        let mut clients = self.clients.write().unwrap();
+
+        clients.insert(info.id, Connection { info, canceller });
+    }
+    /// Removes a closed connection
+    pub fn disconnect(&self, id: ConnectionId) {
+        self.clients.write().unwrap().remove(&id);
+    }
+    /// Changes the stream type of an open connection
+    pub fn set_stream_type(&self, id: ConnectionId, stream_type: StreamIdentify) {
+        if let Some(conn) = self.clients.write().unwrap().get_mut(&id) {
+            conn.info.stream_type = Some(stream_type);
+        }
+    }
+    /// Adds a connection identified as a public key
+    pub fn register(&self, key: PubKey, id: ConnectionId, sender: mpsc::UnboundedSender<Message>) {
+        let mut connections = self.connections.write().unwrap();
+
+        connections.entry(key).or_default().insert(id, sender);
+
+        if let Some(conn) = self.clients.write().unwrap().get_mut(&id) {
+            conn.info.identities.push(key);
+        }
+    }
+    /// Removes a connection from every public key in `keys`.
+    /// Returns the public keys that are no longer identified by any connection
+    pub fn unregister<'a>(&self, keys: impl IntoIterator<Item = &'a PubKey>, id: ConnectionId) -> Vec<PubKey> {
+        let mut connections = self.connections.write().unwrap();
+        let mut offline = Vec::new();
+
+        for key in keys {
+            if let Some(conns) = connections.get_mut(key) {
+                conns.remove(&id);
+
+                if conns.is_empty() {
+                    connections.remove(key);
+                    offline.push(*key);
+                }
+            }
+        }
+
+        offline
+    }
+    /// Returns true if at least one connection is identified as the public key
+    pub fn is_online(&self, key: &PubKey) -> bool {
+        self.connections.read().unwrap().contains_key(key)
+    }
+    /// Sends a message to every connection identified as the public key.
+    /// Returns true if at least one connection received the message.
+    pub fn send_to(&self, key: &PubKey, msg: &Message) -> bool {
+        let connections = self.connections.read().unwrap();
+
+        let mut sent = false;
+
+        if let Some(conns) = connections.get(key) {
+            for conn in conns.values() {
+                sent |= conn.unbounded_send(msg.clone()).is_ok();
+            }
+        }
+
+        sent
+    }
+    /// Closes every connection identified as the public key, returning the amount of closed connections
+    pub fn kick(&self, key: &PubKey) -> usize {
+        let ids: Vec<ConnectionId> = match self.connections.read().unwrap().get(key) {
+            Some(conns) => conns.keys().copied().collect(),
+            None => return 0,
+        };
+
+        let clients = self.clients.read().unwrap();
+        ids.iter()
+            .filter_map(|id| clients.get(id))
+            .filter(|conn| conn.canceller.unbounded_send(()).is_ok())
+            .count()
+    }
+    /// Returns every open connection
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        self.clients
+            .read()
+            .unwrap()
+            .values()
+            .map(|conn| conn.info.clone())
+            .collect()
+    }
+    /// Returns the amount of open connections
+    pub fn connection_count(&self) -> usize {
+        self.clients.read().unwrap().len()
+    }
+    /// Returns every public key identified by at least one connection
+    pub fn online_keys(&self) -> Vec<PubKey> {
+        self.connections.read().unwrap().keys().copied().collect()
+    }
+    /// Returns the amount of public keys identified by at least one connection
+    pub fn online_count(&self) -> usize {
+        self.connections.read().unwrap().len()
+    }
+}
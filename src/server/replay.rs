@@ -0,0 +1,106 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::Mutex,
+};
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::data::crypto::PubKey;
+
+/// A signed challenge of a public key
+type Entry = (PubKey, [u8; 32]);
+
+/// Bounded node-wide cache of recently accepted `(public key, challenge)` pairs.
+/// A pair is remembered until its timestamp leaves the accepted window, or until the cache is full
+pub struct ReplayCache {
+    capacity: usize,
+    inner: Mutex<ReplayCacheInner>,
+}
+
+#[derive(Default)]
+struct ReplayCacheInner {
+    seen: HashSet<Entry>,
+    /// Entries in insertion order, with the time they stop being accepted anyway
+    order: VecDeque<(DateTime<Utc>, Entry)>,
+}
+
+impl ReplayCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::default(),
+        }
+    }
+    /// Remembers every public key of an identify. Returns false, remembering none of them,
+    /// if one of the public keys already signed the challenge.
+    /// `window` is the clock skew accepted by the node
+    pub fn insert(&self, keys: &[PubKey], sig_msg: &[u8; 32], timestamp: &DateTime<Utc>, window: Duration) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Utc::now();
+
+        // Forget entries that would be rejected as stale
+        while let Some((expiry, entry)) = inner.order.front().copied() {
+            if expiry >= now {
+                break;
+            }
+            inner.order.pop_front();
+            inner.seen.remove(&entry);
+        }
+
+        if keys.iter().any(|key| inner.seen.contains(&(*key, *sig_msg))) {
+            return false;
+        }
+
+        for key in keys {
+            // Forget the oldest entry, the challenge it belongs to was consumed anyway
+            if inner.order.len() >= self.capacity {
+                if let Some((_, entry)) = inner.order.pop_front() {
+                    inner.seen.remove(&entry);
+                }
+            }
+
+            inner.seen.insert((*key, *sig_msg));
+            inner.order.push_back((*timestamp + window, (*key, *sig_msg)));
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::crypto::PrivKey;
+
+    use super::*;
+
+    #[test]
+    fn replayed_challenges_are_rejected() {
+        let cache = ReplayCache::new(10);
+        let (a, b) = (PrivKey::random().public_key(), PrivKey::random().public_key());
+        let window = Duration::minutes(5);
+        let now = Utc::now();
+
+        assert!(cache.insert(&[a], &[1; 32], &now, window));
+        assert!(!cache.insert(&[a], &[1; 32], &now, window));
+        // None of the keys are remembered if one of them is a replay
+        assert!(!cache.insert(&[b, a], &[1; 32], &now, window));
+        assert!(cache.insert(&[b], &[1; 32], &now, window));
+        assert!(cache.insert(&[a], &[2; 32], &now, window));
+    }
+
+    #[test]
+    fn entries_are_forgotten_once_stale_or_evicted() {
+        let cache = ReplayCache::new(2);
+        let key = PrivKey::random().public_key();
+        let window = Duration::minutes(5);
+        let now = Utc::now();
+
+        // Already outside of the window, the timestamp would be rejected anyway
+        assert!(cache.insert(&[key], &[1; 32], &(now - Duration::minutes(10)), window));
+        assert!(cache.insert(&[key], &[1; 32], &now, window));
+
+        assert!(cache.insert(&[key], &[2; 32], &now, window));
+        assert!(cache.insert(&[key], &[3; 32], &now, window));
+        assert!(cache.insert(&[key], &[1; 32], &now, window));
+    }
+}